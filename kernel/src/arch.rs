@@ -4,10 +4,20 @@ use core::time::Duration;
 use riscv::register::*;
 use crate::symbols::PAGE_SIZE;
 
-/// Get current time from MMIO
+/// Get current `mtime`, from MMIO, or from `time` CSR under SBI as CLINT
+/// is only accessible in machine mode
+pub fn ticks() -> u64 {
+    if crate::sbi::enabled() {
+        time::read64()
+    } else {
+        let mtime = (crate::fdt::machine().clint.base + crate::clint::CLINT_MTIME) as *const u64;
+        unsafe { mtime.read_volatile() }
+    }
+}
+
+/// Get current time
 pub fn time() -> Duration {
-    let mtime = crate::clint::CLINT_MTIME_BASE as *const u64;
-    Duration::from_nanos(unsafe { mtime.read_volatile() } * 100)
+    Duration::from_nanos(ticks() * 100)
 }
 
 /// Build satp value from mode, asid and page table base addr
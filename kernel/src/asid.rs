@@ -0,0 +1,158 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Address space identifiers
+//!
+//! Each process is given an ASID, which is programmed in `satp` along with
+//! its page table, so TLB entries of different processes coexist and
+//! switching page tables needs no flush. ASIDs are handed out from a global
+//! generation, and are not reused within a generation even after their
+//! processes exit, so stale TLB entries never match another process. When
+//! a generation runs out, a new one starts, and every hart flushes its
+//! whole TLB before it next switches to a user page table. Processes of
+//! old generations get new ASIDs when they next return to user space.
+//!
+//! ASID 0 is kept for kernel page table. Without ASID support, every
+//! switch to a user page table flushes TLB, as if each one rolls over.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::satp;
+use crate::arch::hart_id;
+use crate::ipi::sfence_vma;
+use crate::spinlock::Mutex;
+use crate::symbols::NCPUS;
+
+/// Offset of ASID field in `satp`
+const SATP_ASID_SHIFT: usize = 44;
+/// Maximum width of ASID field in `satp`
+const SATP_ASID_BITS: usize = 16;
+
+/// Generation is stored above ASID in context id
+const GENERATION_SHIFT: usize = SATP_ASID_BITS;
+
+/// Context id of a process with no ASID yet
+pub const NO_ASID: u64 = 0;
+
+/// Hands out ASIDs by generation
+pub struct AsidAllocator {
+    /// Current generation, starting from 1
+    generation: u64,
+    /// Next free ASID in current generation
+    next: usize,
+    /// Number of ASIDs supported by hardware, including 0
+    count: usize,
+}
+
+impl AsidAllocator {
+    pub const fn new(bits: usize) -> Self {
+        Self { generation: 1, next: 1, count: 1 << bits }
+    }
+
+    /// Validate context id `context` of a process, allocating a new ASID
+    /// if it's from an old generation. Returns new context id, and whether
+    /// a new generation has started, in which case TLB of all harts should
+    /// be flushed before the new ASID is used.
+    pub fn alloc(&mut self, context: u64) -> (u64, bool) {
+        if context >> GENERATION_SHIFT == self.generation {
+            return (context, false);
+        }
+        // ASID 0 belongs to kernel, so nothing to hand out
+        if self.count <= 1 {
+            return (NO_ASID, true);
+        }
+        let mut rollover = false;
+        if self.next == self.count {
+            self.generation += 1;
+            self.next = 1;
+            rollover = true;
+        }
+        let asid = self.next;
+        self.next += 1;
+        (self.generation << GENERATION_SHIFT | asid as u64, rollover)
+    }
+}
+
+/// ASID of context id `context`
+pub const fn asid_of(context: u64) -> usize {
+    (context & ((1 << GENERATION_SHIFT) - 1)) as usize
+}
+
+static ASIDS: Mutex<AsidAllocator> = Mutex::new(AsidAllocator::new(0), "asid");
+
+/// Whether each hart should flush its TLB before next switch to user
+static FLUSH_PENDING: [AtomicBool; NCPUS] = [const { AtomicBool::new(false) }; NCPUS];
+
+/// Probe width of ASID field in `satp`, returning number of bits. Should be
+/// called by booting hart after kernel page table is enabled.
+pub fn init() -> usize {
+    let mask = ((1 << SATP_ASID_BITS) - 1) << SATP_ASID_SHIFT;
+    let old = satp::read().bits();
+    let bits = unsafe {
+        satp::write(old | mask);
+        let bits = ((satp::read().bits() & mask) >> SATP_ASID_SHIFT).count_ones() as usize;
+        satp::write(old);
+        bits
+    };
+    sfence_vma(None, None);
+    *ASIDS.lock() = AsidAllocator::new(bits);
+    bits
+}
+
+/// Validate ASID of a process with context id `context` before switching
+/// to its page table on current hart, flushing TLB if needed. Returns ASID
+/// to program in `satp`. Should be called with interrupt off.
+pub fn activate(context: &mut u64) -> usize {
+    let (new, rollover) = ASIDS.lock().alloc(*context);
+    *context = new;
+    if rollover {
+        for pending in FLUSH_PENDING.iter() {
+            pending.store(true, Ordering::SeqCst);
+        }
+    }
+    if FLUSH_PENDING[hart_id()].swap(false, Ordering::SeqCst) {
+        sfence_vma(None, None);
+    }
+    asid_of(new)
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("generation", test_generation),
+            ("no asid", test_no_asid),
+        ]
+    }
+
+    /// Test that ASIDs are kept within a generation, and reallocated after
+    /// rollover
+    pub fn test_generation() {
+        let mut asids = AsidAllocator::new(2);
+        let (a, rollover) = asids.alloc(NO_ASID);
+        assert!(!rollover);
+        assert_eq!(asid_of(a), 1);
+        assert_eq!(asids.alloc(a), (a, false));
+        let (b, _) = asids.alloc(NO_ASID);
+        let (c, _) = asids.alloc(NO_ASID);
+        assert_eq!((asid_of(b), asid_of(c)), (2, 3));
+        // generation runs out
+        let (d, rollover) = asids.alloc(NO_ASID);
+        assert!(rollover);
+        assert_eq!(asid_of(d), 1);
+        let (a, rollover) = asids.alloc(a);
+        assert!(!rollover);
+        assert_eq!(asid_of(a), 2);
+        assert_eq!(asids.alloc(d), (d, false));
+    }
+
+    /// Test that every switch flushes without ASID support
+    pub fn test_no_asid() {
+        let mut asids = AsidAllocator::new(0);
+        let (a, rollover) = asids.alloc(NO_ASID);
+        assert!(rollover);
+        assert_eq!(asids.alloc(a), (NO_ASID, true));
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Block devices
+//!
+//! Disks and their partitions are registered in `BLOCK_DEVICES` and
+//! identified by their index in it.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::partition::{self, PartitionDevice};
+use crate::spinlock::Mutex;
+use crate::info;
+
+/// Sector size of block devices
+pub const SECTOR_SIZE: usize = 512;
+
+/// Block size used by filesystems
+pub const BSIZE: usize = 1024;
+
+/// Number of sectors in a block
+const SECTORS_PER_BLOCK: usize = BSIZE / SECTOR_SIZE;
+
+/// Error of block I/O
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IoError {
+    /// Sectors beyond end of device
+    OutOfRange,
+}
+
+/// Block device
+///
+/// All block devices should implement their own synchronize mechanisms.
+pub trait BlockDevice: Send + Sync {
+    /// Number of sectors
+    fn sectors(&self) -> usize;
+    /// Read from `sector` into `data`, whose length is a multiple of `SECTOR_SIZE`.
+    fn read_sectors(&self, sector: usize, data: &mut [u8]) -> Result<(), IoError>;
+    /// Write `data` at `sector`, whose length is a multiple of `SECTOR_SIZE`.
+    fn write_sectors(&self, sector: usize, data: &[u8]) -> Result<(), IoError>;
+    /// Read consecutive sectors from `sector` into `bufs` in one batch.
+    fn read_vectored(&self, sector: usize, bufs: &mut [&mut [u8]]) -> Result<(), IoError> {
+        let mut sector = sector;
+        for buf in bufs.iter_mut() {
+            self.read_sectors(sector, buf)?;
+            sector += buf.len() / SECTOR_SIZE;
+        }
+        Ok(())
+    }
+}
+
+/// Block buffer
+#[repr(C)]
+pub struct Buf {
+    /// device ID
+    pub dev: u32,
+    /// block number
+    pub blockno: u32,
+    /// buffer data
+    pub data: [u8; BSIZE],
+}
+
+impl Buf {
+    pub const fn new() -> Self {
+        Self {
+            dev: 0,
+            blockno: 0,
+            data: [0; BSIZE],
+        }
+    }
+}
+
+/// A registered block device
+pub struct BlockDeviceInfo {
+    /// Name for debugging, such as `vda` and `vda1`
+    pub name: String,
+    pub device: Arc<dyn BlockDevice>,
+    /// Whether partitions of this device are registered
+    pub partitioned: bool,
+}
+
+static BLOCK_DEVICES: Mutex<Vec<BlockDeviceInfo>> = Mutex::new(Vec::new(), "block devices");
+
+/// Register a block device and returns its device ID
+pub fn register(name: String, device: Arc<dyn BlockDevice>) -> u32 {
+    let mut devices = BLOCK_DEVICES.lock();
+    devices.push(BlockDeviceInfo { name, device, partitioned: false });
+    (devices.len() - 1) as u32
+}
+
+/// Number of registered block devices
+pub fn count() -> usize {
+    BLOCK_DEVICES.lock().len()
+}
+
+/// Get block device of `dev`
+pub fn get(dev: u32) -> Arc<dyn BlockDevice> {
+    match BLOCK_DEVICES.lock().get(dev as usize) {
+        Some(info) => info.device.clone(),
+        None => panic!("invalid block device {}", dev)
+    }
+}
+
+/// Whether partitions of `dev` are registered as separate devices
+pub fn is_partitioned(dev: u32) -> bool {
+    BLOCK_DEVICES.lock()[dev as usize].partitioned
+}
+
+/// Read block `blockno` from device `dev`
+pub fn read(dev: u32, blockno: u32) -> Result<Box<Buf>, IoError> {
+    let mut buf = Box::new(Buf::new());
+    buf.dev = dev;
+    buf.blockno = blockno;
+    get(dev).read_sectors(blockno as usize * SECTORS_PER_BLOCK, &mut buf.data)?;
+    Ok(buf)
+}
+
+/// Read `count` blocks beginning at `blockno` from device `dev` in one batch
+pub fn read_blocks(dev: u32, blockno: u32, count: usize) -> Result<Vec<Box<Buf>>, IoError> {
+    let mut bufs: Vec<Box<Buf>> = (0..count).map(|i| {
+        let mut buf = Box::new(Buf::new());
+        buf.dev = dev;
+        buf.blockno = blockno + i as u32;
+        buf
+    }).collect();
+    {
+        let mut data: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| &mut buf.data[..]).collect();
+        get(dev).read_vectored(blockno as usize * SECTORS_PER_BLOCK, &mut data)?;
+    }
+    Ok(bufs)
+}
+
+/// Write buffer to its device
+pub fn write(buf: Box<Buf>) -> Result<(), IoError> {
+    get(buf.dev).write_sectors(buf.blockno as usize * SECTORS_PER_BLOCK, &buf.data)
+}
+
+/// Scan partition tables of all disks and register partitions.
+///
+/// Should be called once in first process after disks are registered, as
+/// disk I/O sleeps.
+pub fn init() {
+    let disks = count();
+    for dev in 0..disks {
+        let (name, disk) = {
+            let devices = BLOCK_DEVICES.lock();
+            (devices[dev].name.clone(), devices[dev].device.clone())
+        };
+        let parts = partition::scan(&*disk);
+        for (i, part) in parts.iter().enumerate() {
+            let part_name = format!("{}{}", name, i + 1);
+            info!("    {}: sector {} + {}", part_name, part.start, part.sectors);
+            register(part_name, Arc::new(PartitionDevice::new(disk.clone(), *part)));
+        }
+        if !parts.is_empty() {
+            BLOCK_DEVICES.lock()[dev].partitioned = true;
+        }
+    }
+}
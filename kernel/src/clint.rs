@@ -7,29 +7,35 @@
 
 #![allow(non_snake_case)]
 
-use crate::symbols::{NCPUS, SCHEDULER_INTERVAL, timervec};
+use crate::symbols::{NCPUS, timervec};
 
+/// Default CLINT base address on QEMU RISC-V. Machine-mode timer is set
+/// up before FDT is parsed, so it always uses this address.
 pub const CLINT_BASE: usize = 0x200_0000;
-pub const CLINT_MTIMECMP_BASE: usize = CLINT_BASE + 0x4000;
+/// Offset of `mtime` from CLINT base
+pub const CLINT_MTIME: usize = 0xBFF8;
+/// Offset of `mtimecmp` of hart 0 from CLINT base
+pub const CLINT_MTIMECMP_OFFSET: usize = 0x4000;
+pub const CLINT_MTIMECMP_BASE: usize = CLINT_BASE + CLINT_MTIMECMP_OFFSET;
 pub const fn CLINT_MTIMECMP(hart: usize) -> usize { CLINT_MTIMECMP_BASE + 8 * hart }
-pub const CLINT_MTIME_BASE: usize = CLINT_BASE + 0xBFF8;
+pub const fn CLINT_MSIP(hart: usize) -> usize { CLINT_BASE + 4 * hart }
+pub const CLINT_MTIME_BASE: usize = CLINT_BASE + CLINT_MTIME;
 
 /// space for timer trap to save information.
 static mut MSCRATCH0: [[u64; 8]; NCPUS] = [[0; 8]; NCPUS];
 
-/// Initialize machine-mode timer interrupt
+/// Initialize machine-mode timer interrupt, which forwards CLINT timer and
+/// IPI to supervisor mode. Deadlines are set by supervisor mode, see `timer`.
 pub unsafe fn timer_init() {
     use riscv::register::*;
     let id = mhartid::read();
-    let interval = SCHEDULER_INTERVAL as u64;
     let mtimecmp = CLINT_MTIMECMP(id) as *mut u64;
-    let mtime = CLINT_MTIME_BASE as *const u64;
-    mtimecmp.write_volatile(mtime.read_volatile() + interval);
+    mtimecmp.write_volatile(u64::MAX);
     let scratch = &mut MSCRATCH0[id];
 
     // space for timer trap to save information.
     scratch[3] = mtimecmp as u64;
-    scratch[4] = interval;
+    scratch[5] = CLINT_MSIP(id) as u64;
     mscratch::write(scratch.as_mut_ptr() as usize);
 
     // set machine-mode trap handler as timervec in kernelvec.S
@@ -38,6 +44,7 @@ pub unsafe fn timer_init() {
     // enable machine-mode interrupts.
     mstatus::set_mie();
 
-    // enable machine-mode timer interrupt.
-    // mie::set_mtimer();
+    // enable machine-mode timer interrupt, and software interrupt for IPI.
+    mie::set_mtimer();
+    mie::set_msoft();
 }
@@ -6,12 +6,13 @@
 //! ELF parsing
 
 
+use alloc::sync::Arc;
+use core::mem::size_of;
+use crate::fs::Inode;
 use crate::mem;
-
-use crate::page;
-
+use crate::page::EntryAttributes;
+use crate::process::{Backing, Vma, Vmas};
 use crate::symbols::*;
-use crate::{println};
 
 #[repr(C)]
 pub struct ELFHeader {
@@ -50,44 +51,68 @@ const ELF_PROG_FLAG_WRITE: u32 = 2;
 const ELF_PROG_FLAG_READ: u32 = 4;
 const ELF_MAGIC: u32 = 0x464C457F;
 
-pub fn parse_elf(a: &[u8], pgtable: &mut page::Table) -> u64 {
-    let a = a.as_ptr();
-    /* TODO: Use something safer */
-    // peek head of byte array to get ELF information
-    let elfhdr = unsafe { &*(a as *const ELFHeader) };
+/// Why an ELF file can't be loaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ElfError {
+    /// File ends before a header
+    Truncated,
+    BadMagic,
+    /// Segment with bad size or address
+    BadSegment,
+    /// Segment overlaps another one or stack
+    Overlap,
+}
+
+/// Program loaded by `parse_elf`
+pub struct Image {
+    /// Entry point
+    pub entry: u64,
+    /// End of highest segment, page-aligned, where heap begins
+    pub end: usize,
+}
+
+/// Read `T` at `offset` of ELF file
+fn read_struct<T>(inode: &Arc<dyn Inode>, offset: usize) -> Result<T, ElfError> {
+    let mut buf = [0u8; 64];
+    let buf = &mut buf[..size_of::<T>()];
+    if inode.read_at(offset, buf) != buf.len() as i32 {
+        return Err(ElfError::Truncated);
+    }
+    Ok(unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const T) })
+}
+
+/// Add areas of ELF file `inode` to `vmas`, whose pages are read from the
+/// file on first touch. Segments should be below trapframe, and not
+/// overlap areas in `vmas`.
+pub fn parse_elf(inode: &Arc<dyn Inode>, vmas: &mut Vmas) -> Result<Image, ElfError> {
+    let elfhdr: ELFHeader = read_struct(inode, 0)?;
     if elfhdr.magic != ELF_MAGIC {
-        panic!("wrong magic number");
+        return Err(ElfError::BadMagic);
     }
-    let mut proghdr = unsafe {
-        let offset_u8 = a.offset(elfhdr.phoff as isize);
-        offset_u8 as *const ProgramHeader
-    };
-    for _i in 0..elfhdr.phnum {
-        let hdr: &ProgramHeader = unsafe {
-            let hdr = &*proghdr;
-            proghdr = proghdr.offset(1);
-            hdr
-        };
+    let mut end = 0;
+    for i in 0..elfhdr.phnum as usize {
+        let offset = (elfhdr.phoff as usize).checked_add(i * size_of::<ProgramHeader>()).ok_or(ElfError::Truncated)?;
+        let hdr: ProgramHeader = read_struct(inode, offset)?;
         if hdr.ptype != ELF_PROG_LOAD {
             continue;
         }
-        if hdr.memsz < hdr.filesz {
-            panic!("bad elf: memsz");
+        if hdr.memsz < hdr.filesz || hdr.vaddr as usize % PAGE_SIZE != 0 {
+            return Err(ElfError::BadSegment);
         }
-        if hdr.vaddr + hdr.memsz < hdr.vaddr {
-            panic!("bad elf: vaddr");
+        match hdr.vaddr.checked_add(hdr.memsz) {
+            Some(seg_end) if seg_end as usize <= TRAPFRAME_START => {}
+            _ => { return Err(ElfError::BadSegment); }
         }
-        if hdr.vaddr as usize % PAGE_SIZE != 0 {
-            println!("{:X}", hdr.vaddr);
-            panic!("bad elf: vaddr align")
+        let seg_end = mem::align_val((hdr.vaddr + hdr.memsz) as usize, PAGE_ORDER);
+        let backing = Backing::File {
+            inode: inode.clone(),
+            offset: hdr.off as usize,
+            size: hdr.filesz as usize,
+        };
+        if !vmas.add(Vma::new(hdr.vaddr as usize, seg_end, segment_flags(hdr.flags), backing)) {
+            return Err(ElfError::Overlap);
         }
-        load_segment(
-            pgtable,
-            hdr.vaddr as usize,
-            a,
-            hdr.off as usize,
-            hdr.filesz as usize,
-        );
+        end = end.max(seg_end);
         /* println!(
             "map segment ELF 0x{:X}~0x{:X} -> MEM 0x{:X}",
             hdr.off,
@@ -95,28 +120,78 @@ pub fn parse_elf(a: &[u8], pgtable: &mut page::Table) -> u64 {
             hdr.vaddr
         ); */
     }
-    elfhdr.entry
+    Ok(Image { entry: elfhdr.entry, end })
 }
 
-fn load_segment(
-    pgtable: &mut page::Table,
-    vaddr: usize,
-    elf: *const u8,
-    offset: usize,
-    sz: usize,
-) {
-    let num_pages = mem::align_val(sz, PAGE_ORDER) / PAGE_SIZE;
-    for i in 0..num_pages {
-        let mut seg = page::Page::new();
-        unsafe {
-            let src = elf.add(offset + i * PAGE_SIZE);
-            core::ptr::copy(src, seg.data.as_mut_ptr(), PAGE_SIZE);
-        }
-        use page::EntryAttributes;
-        pgtable.map(
-            vaddr + i * PAGE_SIZE,
-            seg,
-            EntryAttributes::URX as usize
-        );
+/// Page table entry flags of segment with `flags`
+fn segment_flags(flags: u32) -> usize {
+    let mut bits = 0;
+    if flags & ELF_PROG_FLAG_READ != 0 {
+        bits |= EntryAttributes::R as usize;
+    }
+    if flags & ELF_PROG_FLAG_WRITE != 0 {
+        bits |= EntryAttributes::W as usize;
+    }
+    if flags & ELF_PROG_FLAG_EXEC != 0 {
+        bits |= EntryAttributes::X as usize;
+    }
+    bits
+}
+
+pub mod tests {
+    use super::*;
+    use crate::fs;
+    use crate::process::Access;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("bad elf", test_bad_elf),
+        ]
+    }
+
+    /// Bytes of `val`
+    fn bytes_of<T>(val: &T) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) }
+    }
+
+    /// ELF file in `/tmp` with one segment at `vaddr`
+    fn elf_file(magic: u32, vaddr: u64) -> Arc<dyn Inode> {
+        let elfhdr = ELFHeader {
+            magic, elf: [0; 12], etype: 2, machine: 0xf3, version: 1, entry: vaddr,
+            phoff: size_of::<ELFHeader>() as u64, shoff: 0, flags: 0,
+            ehsize: size_of::<ELFHeader>() as u16, phentsize: size_of::<ProgramHeader>() as u16,
+            phnum: 1, shentsize: 0, shnum: 0, shstrndx: 0,
+        };
+        let hdr = ProgramHeader {
+            ptype: ELF_PROG_LOAD, flags: ELF_PROG_FLAG_READ, off: 0, vaddr, paddr: vaddr,
+            filesz: PAGE_SIZE as u64, memsz: 2 * PAGE_SIZE as u64, align: PAGE_SIZE as u64,
+        };
+        fs::unlink("/tmp/elf");
+        let f = fs::create("/tmp/elf").unwrap();
+        f.write_at(0, bytes_of(&elfhdr));
+        f.write_at(size_of::<ELFHeader>(), bytes_of(&hdr));
+        f.truncate(PAGE_SIZE);
+        f
+    }
+
+    /// Test that bad ELF files are rejected, and a good one is loaded
+    pub fn test_bad_elf() {
+        let mut vmas = Vmas::new();
+        let f = elf_file(ELF_MAGIC, 0x10000);
+        f.truncate(size_of::<ELFHeader>());
+        assert_eq!(parse_elf(&f, &mut vmas).err(), Some(ElfError::Truncated));
+        let f = elf_file(0, 0x10000);
+        assert_eq!(parse_elf(&f, &mut vmas).err(), Some(ElfError::BadMagic));
+        let f = elf_file(ELF_MAGIC, 0x10800);
+        assert_eq!(parse_elf(&f, &mut vmas).err(), Some(ElfError::BadSegment));
+        let f = elf_file(ELF_MAGIC, TRAPFRAME_START as u64);
+        assert_eq!(parse_elf(&f, &mut vmas).err(), Some(ElfError::BadSegment));
+        let f = elf_file(ELF_MAGIC, 0x10000);
+        let image = parse_elf(&f, &mut vmas).unwrap();
+        assert_eq!((image.entry, image.end), (0x10000, 0x12000));
+        assert!(vmas.find(0x11000).unwrap().allows(Access::Read));
+        // overlaps segment loaded before
+        assert_eq!(parse_elf(&f, &mut vmas).err(), Some(ElfError::Overlap));
+        fs::unlink("/tmp/elf");
     }
 }
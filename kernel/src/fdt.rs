@@ -0,0 +1,439 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Flattened device tree
+//!
+//! Firmware passes address of FDT in `a1`. It is parsed by booting hart
+//! before allocator is initialized, so results are kept in `MachineInfo`
+//! of fixed size, and memory of FDT may be reused afterwards. Addresses of
+//! QEMU virt machine are kept as defaults, in case FDT is missing or lacks
+//! a device.
+
+use crate::clint::CLINT_BASE;
+use crate::plic::PLIC_BASE;
+use crate::power::FINISHER_BASE;
+use crate::rtc::RTC_BASE;
+use crate::uart::UART_BASE_ADDR;
+use crate::virtio::{VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE, VIRTIO_MMIO_NUM};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Maximum depth of nodes
+const MAX_DEPTH: usize = 16;
+
+/// A memory-mapped device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub base: usize,
+    pub size: usize,
+    /// PLIC interrupt, 0 for none
+    pub irq: u32,
+}
+
+impl DeviceInfo {
+    pub const fn new(base: usize, size: usize, irq: u32) -> Self {
+        Self { base, size, irq }
+    }
+}
+
+/// Hardware found on machine
+pub struct MachineInfo {
+    /// Main memory, in which kernel is loaded
+    pub memory: DeviceInfo,
+    /// Number of harts
+    pub harts: usize,
+    /// Whether all harts have Sstc extension, which provides `stimecmp`
+    pub sstc: bool,
+    pub uart: DeviceInfo,
+    /// Whether UART is found in FDT, instead of being default one
+    pub uart_in_fdt: bool,
+    pub clint: DeviceInfo,
+    pub plic: DeviceInfo,
+    pub rtc: DeviceInfo,
+    pub finisher: DeviceInfo,
+    /// virtio-mmio slots, sorted by address
+    pub virtio: [DeviceInfo; VIRTIO_MMIO_NUM],
+    pub virtio_count: usize,
+}
+
+impl MachineInfo {
+    /// Hardware of QEMU virt machine with 128 MiB memory
+    pub const fn qemu_virt() -> Self {
+        let mut virtio = [DeviceInfo::new(0, 0, 0); VIRTIO_MMIO_NUM];
+        let mut i = 0;
+        while i < VIRTIO_MMIO_NUM {
+            virtio[i] = DeviceInfo::new(VIRTIO_MMIO_BASE + i * VIRTIO_MMIO_SIZE, VIRTIO_MMIO_SIZE, 1 + i as u32);
+            i += 1;
+        }
+        Self {
+            memory: DeviceInfo::new(0x8000_0000, 128 * 1024 * 1024, 0),
+            harts: 1,
+            sstc: false,
+            uart: DeviceInfo::new(UART_BASE_ADDR, 0x100, 10),
+            uart_in_fdt: false,
+            clint: DeviceInfo::new(CLINT_BASE, 0x10000, 0),
+            plic: DeviceInfo::new(PLIC_BASE, 0x400000, 0),
+            rtc: DeviceInfo::new(RTC_BASE, 0x1000, 11),
+            finisher: DeviceInfo::new(FINISHER_BASE, 0x1000, 0),
+            virtio,
+            virtio_count: VIRTIO_MMIO_NUM,
+        }
+    }
+
+    /// virtio-mmio slots found
+    pub fn virtio(&self) -> &[DeviceInfo] {
+        &self.virtio[..self.virtio_count]
+    }
+}
+
+/// Hardware found at boot, only modified by booting hart
+static mut MACHINE: MachineInfo = MachineInfo::qemu_virt();
+
+/// Hardware found on machine
+pub fn machine() -> &'static MachineInfo {
+    unsafe { &MACHINE }
+}
+
+fn be32(data: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]])
+}
+
+/// Read a number of `cells` 32-bit cells
+fn read_cells(data: &[u8], off: usize, cells: usize) -> usize {
+    (0..cells).fold(0, |val, i| val << 32 | be32(data, off + i * 4) as usize)
+}
+
+/// NUL-terminated string at `off`
+fn cstr(data: &[u8], off: usize) -> &[u8] {
+    let len = data[off..].iter().position(|&c| c == 0).unwrap_or(data.len() - off);
+    &data[off..off + len]
+}
+
+/// Properties of a node that matter to us
+#[derive(Clone, Copy)]
+struct Node<'a> {
+    name: &'a [u8],
+    compatible: &'a [u8],
+    device_type: &'a [u8],
+    disabled: bool,
+    reg: Option<(usize, usize)>,
+    irq: u32,
+    /// Whether ISA of cpu node has Sstc extension
+    sstc: bool,
+    /// `#address-cells` and `#size-cells` for children
+    child_cells: (usize, usize),
+}
+
+impl<'a> Node<'a> {
+    const fn new(name: &'a [u8]) -> Self {
+        Self {
+            name,
+            compatible: &[],
+            device_type: &[],
+            disabled: false,
+            reg: None,
+            irq: 0,
+            sstc: false,
+            child_cells: (2, 1),
+        }
+    }
+
+    /// Whether one of strings in `compatible` is `compat`
+    fn is_compatible(&self, compat: &[u8]) -> bool {
+        self.compatible.split(|&c| c == 0).any(|s| s == compat)
+    }
+
+    fn device(&self) -> Option<DeviceInfo> {
+        self.reg.map(|(base, size)| DeviceInfo::new(base, size, self.irq))
+    }
+}
+
+/// Record `node` into `info`
+fn record(info: &mut MachineInfo, node: &Node, found_memory: &mut bool) {
+    if node.disabled {
+        return;
+    }
+    if node.device_type == b"cpu" {
+        info.harts += 1;
+        info.sstc &= node.sstc;
+        return;
+    }
+    let device = match node.device() {
+        Some(device) => device,
+        None => { return; }
+    };
+    if node.device_type == b"memory" || node.name.starts_with(b"memory@") {
+        // kernel is loaded in the first memory node
+        if !*found_memory {
+            info.memory = device;
+            *found_memory = true;
+        }
+    } else if node.is_compatible(b"ns16550a") {
+        info.uart = device;
+        info.uart_in_fdt = true;
+    } else if node.is_compatible(b"riscv,clint0") || node.is_compatible(b"sifive,clint0") {
+        info.clint = device;
+    } else if node.is_compatible(b"riscv,plic0") || node.is_compatible(b"sifive,plic-1.0.0") {
+        info.plic = device;
+    } else if node.is_compatible(b"google,goldfish-rtc") {
+        info.rtc = device;
+    } else if node.is_compatible(b"sifive,test0") || node.is_compatible(b"sifive,test1") {
+        info.finisher = device;
+    } else if node.is_compatible(b"virtio,mmio") && info.virtio_count < VIRTIO_MMIO_NUM {
+        info.virtio[info.virtio_count] = device;
+        info.virtio_count += 1;
+    }
+}
+
+/// Parse FDT in `data` into `info`. Devices not in FDT keep their
+/// values in `info`. Returns `false` if FDT is invalid.
+pub fn parse(data: &[u8], info: &mut MachineInfo) -> bool {
+    if data.len() < 40 || be32(data, 0) != FDT_MAGIC {
+        return false;
+    }
+    let total = (be32(data, 4) as usize).min(data.len());
+    let data = &data[..total];
+    let off_struct = be32(data, 8) as usize;
+    let off_strings = be32(data, 12) as usize;
+    if off_struct >= total || off_strings >= total {
+        return false;
+    }
+
+    let mut stack = [Node::new(&[]); MAX_DEPTH];
+    let mut depth = 0;
+    let mut found_memory = false;
+    let mut virtio_found = false;
+    let old_harts = info.harts;
+    info.harts = 0;
+    info.sstc = true;
+    let mut off = off_struct;
+    while off + 4 <= total {
+        let token = be32(data, off);
+        off += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(data, off);
+                off = (off + name.len() + 1 + 3) & !3;
+                if depth >= MAX_DEPTH {
+                    return false;
+                }
+                stack[depth] = Node::new(name);
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+                let node = stack[depth];
+                if node.is_compatible(b"virtio,mmio") && !virtio_found {
+                    // slots in FDT replace default ones
+                    virtio_found = true;
+                    info.virtio_count = 0;
+                }
+                record(info, &node, &mut found_memory);
+            }
+            FDT_PROP => {
+                if off + 8 > total || depth == 0 {
+                    return false;
+                }
+                let len = be32(data, off) as usize;
+                let name = cstr(data, off_strings + be32(data, off + 4) as usize);
+                let value_off = off + 8;
+                if value_off + len > total {
+                    return false;
+                }
+                let value = &data[value_off..value_off + len];
+                off = (value_off + len + 3) & !3;
+                // cells of `reg` are given by parent
+                let (addr_cells, size_cells) = if depth >= 2 { stack[depth - 2].child_cells } else { (2, 1) };
+                let node = &mut stack[depth - 1];
+                match name {
+                    b"compatible" => { node.compatible = value; }
+                    b"device_type" => { node.device_type = cstr(value, 0); }
+                    b"status" => { node.disabled = cstr(value, 0) != b"okay" && cstr(value, 0) != b"ok"; }
+                    b"#address-cells" if len == 4 => { node.child_cells.0 = be32(value, 0) as usize; }
+                    b"#size-cells" if len == 4 => { node.child_cells.1 = be32(value, 0) as usize; }
+                    b"interrupts" if len >= 4 => { node.irq = be32(value, 0); }
+                    // e.g. "rv64imafdc_zicsr_sstc"
+                    b"riscv,isa" => { node.sstc |= cstr(value, 0).split(|&c| c == b'_').skip(1).any(|ext| ext == b"sstc"); }
+                    b"riscv,isa-extensions" => { node.sstc |= value.split(|&c| c == 0).any(|ext| ext == b"sstc"); }
+                    b"reg" if len >= (addr_cells + size_cells) * 4 => {
+                        node.reg = Some((read_cells(value, 0, addr_cells), read_cells(value, addr_cells * 4, size_cells)));
+                    }
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => { break; }
+            _ => { return false; }
+        }
+    }
+    if info.harts == 0 {
+        info.harts = old_harts;
+        info.sstc = false;
+    }
+    info.virtio[..info.virtio_count].sort_unstable_by_key(|d| d.base);
+    true
+}
+
+/// Parse FDT at `addr`. Should be called by booting hart before
+/// allocator and drivers are initialized. Returns `false` if there's no
+/// valid FDT, in which case QEMU virt defaults are used.
+pub unsafe fn init(addr: usize) -> bool {
+    if addr == 0 || addr % 4 != 0 {
+        return false;
+    }
+    let header = core::slice::from_raw_parts(addr as *const u8, 8);
+    if be32(header, 0) != FDT_MAGIC {
+        return false;
+    }
+    let total = be32(header, 4) as usize;
+    parse(core::slice::from_raw_parts(addr as *const u8, total), &mut MACHINE)
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("parse", test_parse),
+            ("invalid", test_invalid),
+        ]
+    }
+
+    /// Builder of a small FDT
+    struct Builder {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn pad(&mut self) {
+            while self.structs.len() % 4 != 0 {
+                self.structs.push(0);
+            }
+        }
+
+        fn begin(&mut self, name: &str) {
+            self.structs.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+        }
+
+        fn end(&mut self) {
+            self.structs.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) {
+            let nameoff = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.structs.extend_from_slice(&FDT_PROP.to_be_bytes());
+            self.structs.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            self.structs.extend_from_slice(&nameoff.to_be_bytes());
+            self.structs.extend_from_slice(value);
+            self.pad();
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value);
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            self.structs.extend_from_slice(&FDT_END.to_be_bytes());
+            let off_struct = 40;
+            let off_strings = off_struct + self.structs.len();
+            let total = off_strings + self.strings.len();
+            let mut fdt = Vec::new();
+            for val in [FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, 0, 17, 16, 0,
+                        self.strings.len() as u32, self.structs.len() as u32] {
+                fdt.extend_from_slice(&val.to_be_bytes());
+            }
+            fdt.extend_from_slice(&self.structs);
+            fdt.extend_from_slice(&self.strings);
+            fdt
+        }
+    }
+
+    /// Test parsing FDT similar to that of QEMU virt
+    pub fn test_parse() {
+        let mut b = Builder { structs: Vec::new(), strings: Vec::new() };
+        b.begin("");
+        b.cells("#address-cells", &[2]);
+        b.cells("#size-cells", &[2]);
+        b.begin("memory@80000000");
+        b.prop("device_type", b"memory\0");
+        b.cells("reg", &[0, 0x8000_0000, 0, 0x1000_0000]);
+        b.end();
+        b.begin("cpus");
+        b.cells("#address-cells", &[1]);
+        b.cells("#size-cells", &[0]);
+        for i in 0..3 {
+            b.begin("cpu");
+            b.prop("device_type", b"cpu\0");
+            b.prop("riscv,isa", b"rv64imafdch_zicsr_zifencei_sstc\0");
+            b.cells("reg", &[i]);
+            b.end();
+        }
+        b.end();
+        b.begin("soc");
+        b.cells("#address-cells", &[2]);
+        b.cells("#size-cells", &[2]);
+        b.begin("virtio_mmio@10002000");
+        b.prop("compatible", b"virtio,mmio\0");
+        b.cells("reg", &[0, 0x1000_2000, 0, 0x1000]);
+        b.cells("interrupts", &[2]);
+        b.end();
+        b.begin("virtio_mmio@10001000");
+        b.prop("compatible", b"virtio,mmio\0");
+        b.cells("reg", &[0, 0x1000_1000, 0, 0x1000]);
+        b.cells("interrupts", &[1]);
+        b.end();
+        b.begin("serial@10000000");
+        b.prop("compatible", b"ns16550a\0");
+        b.cells("reg", &[0, 0x1000_0000, 0, 0x100]);
+        b.cells("interrupts", &[10]);
+        b.end();
+        b.begin("plic@c000000");
+        b.prop("compatible", b"sifive,plic-1.0.0\0riscv,plic0\0");
+        b.cells("reg", &[0, 0x0c00_0000, 0, 0x60_0000]);
+        b.end();
+        b.end();
+        b.end();
+        let fdt = b.finish();
+
+        let mut info = MachineInfo::qemu_virt();
+        assert!(parse(&fdt, &mut info));
+        assert_eq!(info.memory, DeviceInfo::new(0x8000_0000, 0x1000_0000, 0));
+        assert_eq!(info.harts, 3);
+        assert!(info.sstc);
+        assert_eq!(info.uart, DeviceInfo::new(0x1000_0000, 0x100, 10));
+        assert!(info.uart_in_fdt);
+        assert_eq!(info.plic.size, 0x60_0000);
+        assert_eq!(info.virtio(), &[
+            DeviceInfo::new(0x1000_1000, 0x1000, 1),
+            DeviceInfo::new(0x1000_2000, 0x1000, 2),
+        ]);
+        // not in FDT, default kept
+        assert_eq!(info.rtc.base, RTC_BASE);
+    }
+
+    /// Test rejecting data without FDT magic
+    pub fn test_invalid() {
+        let mut info = MachineInfo::qemu_virt();
+        assert!(!parse(&[0; 64], &mut info));
+        assert_eq!(info.virtio_count, VIRTIO_MMIO_NUM);
+    }
+}
@@ -3,9 +3,10 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! File in core-os including file in filesystem, device, pipe and symbol link
+//! File in core-os including file in filesystem, device, pipe, socket and symbol link
 
 use alloc::boxed::Box;
+use crate::net::{Socket, UnixSocket};
 
 mod device;
 pub use device::*;
@@ -16,5 +17,7 @@ pub use fsfile::*;
 pub enum File {
     Device(Box<dyn Device>),
     FsFile(FsFile),
+    Socket(Socket),
+    UnixSocket(UnixSocket),
     Pipe
 }
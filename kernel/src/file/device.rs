@@ -3,9 +3,17 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! Device trait for devices such as Console
+//! Device trait for devices such as Console and Random
+//!
+//! Devices found by drivers, such as virtio-console ports, are registered
+//! by name and opened as `/dev/<name>`.
 
-use crate::uart::UART;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::{random, tty};
+use crate::spinlock::Mutex;
+use crate::uart::{Uart, UART};
 
 /// Device trait
 ///
@@ -15,30 +23,73 @@ pub trait Device: Send + Sync {
     fn read(&self, content: &mut [u8]) -> i32;
     /// Write content to file and returns number of characters written.
     fn write(&self, content: &[u8]) -> i32;
+    /// Control device by `request` with `arg`. Returns -1 if not supported.
+    fn ioctl(&self, _request: usize, _arg: usize) -> i32 {
+        -1
+    }
+}
+
+impl<T: Device + ?Sized> Device for Arc<T> {
+    fn read(&self, content: &mut [u8]) -> i32 {
+        (**self).read(content)
+    }
+
+    fn write(&self, content: &[u8]) -> i32 {
+        (**self).write(content)
+    }
+
+    fn ioctl(&self, request: usize, arg: usize) -> i32 {
+        (**self).ioctl(request, arg)
+    }
+}
+
+/// Devices registered by drivers
+static DEVICES: Mutex<Vec<(String, Arc<dyn Device>)>> = Mutex::new(Vec::new(), "devices");
+
+/// Register `device` as `/dev/<name>`. A device may have several names.
+pub fn register(name: String, device: Arc<dyn Device>) {
+    DEVICES.lock().push((name, device));
+}
+
+/// Find device registered as `name`
+pub fn lookup(name: &str) -> Option<Arc<dyn Device>> {
+    DEVICES.lock().iter().find(|(n, _)| n == name).map(|(_, device)| device.clone())
 }
 
 /// Console device
 pub struct Console {}
 
 impl Device for Console {
-    /// read from console
+    /// read from console through TTY line discipline
+    fn read(&self, content: &mut [u8]) -> i32 {
+        tty::read(content)
+    }
+
+    /// write to console, returns once content is queued for transmission
+    fn write(&self, content: &[u8]) -> i32 {
+        Uart::write(UART().lock(), content);
+        content.len() as i32
+    }
+
+    /// switch TTY mode or foreground process
+    fn ioctl(&self, request: usize, arg: usize) -> i32 {
+        tty::ioctl(request, arg)
+    }
+}
+
+/// Random device, reading from kernel random number generator
+pub struct Random {}
+
+impl Device for Random {
+    /// read random bytes, never blocks
     fn read(&self, content: &mut [u8]) -> i32 {
-        let mut uart = UART().lock();
-        for i in 0..content.len() {
-            match uart.get() {
-                Some(ch) => { content[i] = ch; }
-                _ => { return i as i32; }
-            }
-        }
+        random::fill(content);
         content.len() as i32
     }
 
-    /// write to console
+    /// mix written bytes into generator
     fn write(&self, content: &[u8]) -> i32 {
-        let mut uart = UART().lock();
-        for i in 0..content.len() {
-            uart.put(content[i]);
-        }
+        random::add_entropy(content);
         content.len() as i32
     }
 }
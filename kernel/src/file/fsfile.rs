@@ -5,85 +5,61 @@
 
 //! File on file system
 
-use crate::virtio::{VIRTIO, VirtIO, BSIZE};
-
+use alloc::sync::Arc;
+use crate::fs::{self, Inode};
 use crate::spinlock::Mutex;
 
 pub struct FsFile {
-    pub offset: usize,
-    pub sz: usize,
+    pub inode: Arc<dyn Inode>,
     rw_offset: Mutex<(usize, usize)>,
     readable: bool,
     writable: bool,
 }
 
-const HEADER_SIZE: usize = 1024;
-const FILE_MAX: usize = 1024;
-
 impl FsFile {
-    fn get_file_info(virtio: &mut VirtIO, path: &str) -> Option<(usize, usize)> {
-        for id in 0..FILE_MAX {
-            let b = virtio.read(1, id as u32);
-            let sz = unsafe { core::ptr::read(b.data.as_ptr() as *const usize) };
-            let offset = unsafe { core::ptr::read((b.data.as_ptr() as *const usize).add(1)) };
-            if sz == 0 {
-                break;
-            }
-            let name_sz = {
-                let mut i = 16;
-                loop {
-                    let d = b.data[i];
-                    if d == 0 {
-                        break;
-                    }
-                    i += 1;
-                    if i == b.data.len() { break; }
-                }
-                i - 16
-            };
-            let u8_slice = unsafe { core::slice::from_raw_parts(b.data.as_ptr().add(16), name_sz) };
-            let name = core::str::from_utf8(u8_slice).unwrap();
-            if name == path {
-                return Some((offset, sz));
-            }
-        }
-        None
-    }
-
-    pub fn open(path: &str, _mode: usize) -> Self {
-        let virtio = VIRTIO();
-        let (offset, sz) = match Self::get_file_info(virtio, path) {
-            Some(x) => x,
-            None => { panic!("{} not found", path); }
+    /// Open file of `path` in mounted filesystems. Returns `None` if not found.
+    ///
+    /// `mode` may contain `O_CREATE` and `O_TRUNC`.
+    pub fn open(path: &str, mode: usize) -> Option<Self> {
+        let inode = match fs::lookup(path) {
+            Some(inode) => inode,
+            None if mode & fs::O_CREATE != 0 => fs::create(path)?,
+            None => { return None; }
         };
-        Self {
-            offset,
-            sz,
+        if mode & fs::O_TRUNC != 0 && inode.truncate(0) != 0 {
+            return None;
+        }
+        Some(Self {
+            inode,
             rw_offset: Mutex::new((0, 0), "file rw offset"),
             readable: true,
             writable: true,
-        }
+        })
+    }
+
+    /// Size of file
+    pub fn size(&self) -> usize {
+        self.inode.size()
     }
 
     pub fn read(&self, content: &mut [u8]) -> i32 {
-        
         if !self.readable { return -1; }
-        let virtio = VIRTIO();
         let read_offset = self.rw_offset.lock().0;
-        let read_sz = (self.sz - read_offset).min(content.len());
-        let result = virtio.read(1, ((self.offset + read_offset) / BSIZE) as u32);
-        // content.copy_from_slice(&result.data[0..content.len()]);
-        for i in 0..read_sz {
-            content[i] = result.data[i];
+        let read_sz = self.inode.read_at(read_offset, content);
+        if read_sz > 0 {
+            self.rw_offset.lock().0 = read_offset + read_sz as usize;
         }
-        self.rw_offset.lock().0 = read_offset + read_sz;
-        // println!("off: {:x}, rsz: {:x}, sz: {:x}", read_offset, read_sz, self.sz);
-        read_sz as i32
+        read_sz
     }
 
-    pub fn write(&self, _content: &[u8]) -> i32 {
+    pub fn write(&self, content: &[u8]) -> i32 {
         if !self.writable { return -1; }
-        unimplemented!()
+        let write_offset = self.rw_offset.lock().1;
+        let write_sz = self.inode.write_at(write_offset, content);
+        if write_sz > 0 {
+            self.rw_offset.lock().1 = write_offset + write_sz as usize;
+        }
+        write_sz
     }
 }
 
@@ -98,16 +74,15 @@ pub mod tests {
         ]
     }
 
-    
-
     /// Test open
     pub fn test_open() {
-        let _f = FsFile::open("/test.txt", 0);
+        let _f = FsFile::open("/test.txt", 0).unwrap();
+        assert!(FsFile::open("/not_exist", 0).is_none());
     }
 
     /// Test read
     pub fn test_read() {
-        let f = FsFile::open("/test.txt", 0);
+        let f = FsFile::open("/test.txt", 0).unwrap();
         let mut content = [0; 10];
         assert_eq!(f.read(&mut content), 10);
         assert_eq!(content, [48, 49, 50, 51, 52, 53, 54, 55, 56, 57]);
@@ -115,9 +90,8 @@ pub mod tests {
 
     /// Test read
     pub fn test_read_elf() {
-        let f = FsFile::open("/init", 0);
+        let f = FsFile::open("/init", 0).unwrap();
         let mut content = [0; 1024];
         while f.read(&mut content) == 1024 {}
     }
 }
-
@@ -0,0 +1,232 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Virtual filesystem layer
+//!
+//! Every filesystem is mounted on a path in `MOUNTS`. Looking up a
+//! path finds the mount with the longest matching prefix and asks
+//! that filesystem for the rest of the path.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::{block, info};
+use crate::spinlock::Mutex;
+use crate::symbols::{INITRAMFS_START, INITRAMFS_END};
+
+mod initramfs;
+pub use initramfs::*;
+mod simplefs;
+pub use simplefs::*;
+pub mod tmpfs;
+pub use tmpfs::*;
+
+/// Open flag: create file if not exist
+pub const O_CREATE: usize = 0x200;
+/// Open flag: truncate file to zero length
+pub const O_TRUNC: usize = 0x400;
+
+/// A file stored in some filesystem
+///
+/// All inodes should implement their own synchronize mechanisms.
+pub trait Inode: Send + Sync {
+    /// Size of file in bytes
+    fn size(&self) -> usize;
+    /// Read from `offset` into `content` and returns number of bytes read.
+    fn read_at(&self, offset: usize, content: &mut [u8]) -> i32;
+    /// Write `content` at `offset` and returns number of bytes written,
+    /// `-ENOMEM` if there's no memory for it.
+    fn write_at(&self, _offset: usize, _content: &[u8]) -> i32 { -1 }
+    /// Change size of file to `size`, filling new space with zero. Returns
+    /// `-ENOMEM` if there's no memory for it.
+    fn truncate(&self, _size: usize) -> i32 { -1 }
+}
+
+/// A mountable filesystem
+///
+/// `path` passed to filesystem is relative to its mount point and
+/// always begins with `/`.
+pub trait FileSystem: Send + Sync {
+    /// Find file of `path`
+    fn lookup(&self, path: &str) -> Option<Arc<dyn Inode>>;
+    /// Create an empty file of `path`. Returns `None` if it exists or
+    /// the filesystem is read-only.
+    fn create(&self, _path: &str) -> Option<Arc<dyn Inode>> { None }
+    /// Create directory of `path`
+    fn mkdir(&self, _path: &str) -> i32 { -1 }
+    /// Remove file or empty directory of `path`
+    fn unlink(&self, _path: &str) -> i32 { -1 }
+}
+
+/// A filesystem mounted at `path`
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new(), "mount table");
+
+/// Mount `fs` at `path`. A filesystem mounted later on the same path
+/// hides the previous one.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) {
+    let mut mounts = MOUNTS.lock();
+    mounts.retain(|m| m.path != path);
+    mounts.push(Mount { path: String::from(path), fs });
+}
+
+/// If `path` is under mount point `mnt`, returns path relative to it.
+fn strip_mount<'a>(mnt: &str, path: &'a str) -> Option<&'a str> {
+    if mnt == "/" {
+        return Some(path);
+    }
+    match path.strip_prefix(mnt) {
+        Some("") => Some("/"),
+        Some(rest) if rest.starts_with('/') => Some(rest),
+        _ => None
+    }
+}
+
+/// Find the filesystem `path` belongs to, and path relative to its mount point
+pub fn resolve(path: &str) -> Option<(Arc<dyn FileSystem>, &str)> {
+    let mounts = MOUNTS.lock();
+    let mut found: Option<(&Mount, &str)> = None;
+    for m in mounts.iter() {
+        if let Some(rest) = strip_mount(&m.path, path) {
+            match found {
+                Some((f, _)) if f.path.len() >= m.path.len() => {}
+                _ => { found = Some((m, rest)); }
+            }
+        }
+    }
+    found.map(|(m, rest)| (m.fs.clone(), rest))
+}
+
+/// Find file of absolute `path` in all mounted filesystems
+pub fn lookup(path: &str) -> Option<Arc<dyn Inode>> {
+    let (fs, rest) = resolve(path)?;
+    fs.lookup(rest)
+}
+
+/// Create file of absolute `path`
+pub fn create(path: &str) -> Option<Arc<dyn Inode>> {
+    let (fs, rest) = resolve(path)?;
+    fs.create(rest)
+}
+
+/// Create directory of absolute `path`
+pub fn mkdir(path: &str) -> i32 {
+    match resolve(path) {
+        Some((fs, rest)) => fs.mkdir(rest),
+        None => -1
+    }
+}
+
+/// Remove file or empty directory of absolute `path`
+pub fn unlink(path: &str) -> i32 {
+    match resolve(path) {
+        Some((fs, rest)) => fs.unlink(rest),
+        None => -1
+    }
+}
+
+/// Mount initramfs as root filesystem and tmpfs at `/tmp`
+pub fn init() {
+    let initramfs = unsafe {
+        core::slice::from_raw_parts(
+            INITRAMFS_START() as *const u8,
+            INITRAMFS_END() - INITRAMFS_START())
+    };
+    mount("/", Arc::new(Initramfs::new(initramfs)));
+    mount("/tmp", Arc::new(Tmpfs::new()));
+}
+
+/// Mount each partition (or disk if not partitioned) holding a simple
+/// filesystem at `/disk`, `/disk1`, `/disk2`...
+///
+/// Should be called once in first process after partitions are
+/// registered, as disk I/O sleeps.
+pub fn mount_disks() {
+    let mut mounted = 0;
+    for dev in 0..block::count() as u32 {
+        if block::is_partitioned(dev) || !SimpleFs::probe(dev) {
+            continue;
+        }
+        let path = match mounted {
+            0 => String::from("/disk"),
+            n => format!("/disk{}", n)
+        };
+        info!("    {} on block device {}", path, dev);
+        mount(&path, Arc::new(SimpleFs::new(dev)));
+        mounted += 1;
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("mount prefix", test_strip_mount),
+            ("initramfs lookup", test_initramfs_lookup),
+            ("initramfs malformed", test_initramfs_malformed),
+            ("tmp", test_tmp),
+        ]
+    }
+
+    /// Test mount point matching
+    pub fn test_strip_mount() {
+        assert_eq!(strip_mount("/", "/init"), Some("/init"));
+        assert_eq!(strip_mount("/disk", "/disk/init"), Some("/init"));
+        assert_eq!(strip_mount("/disk", "/disk"), Some("/"));
+        assert_eq!(strip_mount("/disk", "/disk2/init"), None);
+        assert_eq!(strip_mount("/disk", "/init"), None);
+    }
+
+    /// Test files are found in initramfs root
+    pub fn test_initramfs_lookup() {
+        let f = lookup("/test.txt").unwrap();
+        let mut content = [0; 10];
+        assert_eq!(f.read_at(0, &mut content), 10);
+        assert_eq!(content, [48, 49, 50, 51, 52, 53, 54, 55, 56, 57]);
+        assert!(lookup("/not_exist").is_none());
+    }
+
+    /// cpio newc header with `magic` of a regular file with `filesize` and
+    /// `namesize`
+    macro_rules! header {
+        ($magic:literal, $filesize:literal, $namesize:literal) => {
+            concat!($magic, "00000000", "000081a4", "00000000", "00000000", "00000001", "00000000",
+                $filesize, "00000000", "00000000", "00000000", "00000000", $namesize, "00000000")
+        };
+    }
+
+    /// Test that iteration stops at malformed entries of an archive
+    pub fn test_initramfs_malformed() {
+        let good = concat!(header!("070701", "00000004", "00000002"), "a\0", "abcd");
+        assert_eq!(Initramfs::new(good.as_bytes()).entries().count(), 1);
+        let bad_magic = concat!(header!("070702", "00000004", "00000002"), "a\0", "abcd");
+        assert_eq!(Initramfs::new(bad_magic.as_bytes()).entries().count(), 0);
+        // no name, not even NUL
+        let no_name = header!("070701", "00000000", "00000000");
+        assert_eq!(Initramfs::new(no_name.as_bytes()).entries().count(), 0);
+        // file data runs past end of archive
+        let truncated = concat!(header!("070701", "00001000", "00000002"), "a\0", "abcd");
+        assert_eq!(Initramfs::new(truncated.as_bytes()).entries().count(), 0);
+        // stops after a good entry
+        let trailing = concat!(header!("070701", "00000004", "00000002"), "a\0", "abcd", "0707");
+        assert_eq!(Initramfs::new(trailing.as_bytes()).entries().count(), 1);
+    }
+
+    /// Test files can be created in `/tmp` but not in initramfs
+    pub fn test_tmp() {
+        assert!(create("/test.txt").is_none());
+        let f = create("/tmp/test.txt").unwrap();
+        assert_eq!(f.write_at(0, b"tmp"), 3);
+        assert_eq!(lookup("/tmp/test.txt").unwrap().size(), 3);
+        assert_eq!(unlink("/tmp/test.txt"), 0);
+        assert!(lookup("/tmp/test.txt").is_none());
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Read-only filesystem on a cpio newc archive linked into kernel image
+
+use alloc::sync::Arc;
+use crate::fs::{FileSystem, Inode};
+use crate::warn;
+
+/// Size of cpio newc header
+const HEADER_SIZE: usize = 110;
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_TRAILER: &str = "TRAILER!!!";
+/// File type bits in `mode`
+const S_IFMT: usize = 0o170000;
+/// Regular file
+const S_IFREG: usize = 0o100000;
+
+/// An entry in cpio archive
+pub struct CpioEntry {
+    pub name: &'static str,
+    pub mode: usize,
+    pub data: &'static [u8],
+}
+
+/// Parse a field of 8 hex digits
+fn parse_hex(field: &[u8]) -> Option<usize> {
+    let mut val = 0;
+    for &d in field {
+        let digit = (d as char).to_digit(16)?;
+        val = val << 4 | digit as usize;
+    }
+    Some(val)
+}
+
+/// Iterator over entries of cpio newc archive. Iteration stops at a
+/// malformed entry.
+pub struct CpioIter {
+    archive: &'static [u8],
+    pos: usize,
+}
+
+impl Iterator for CpioIter {
+    type Item = CpioEntry;
+
+    fn next(&mut self) -> Option<CpioEntry> {
+        if self.pos >= self.archive.len() {
+            return None;
+        }
+        let (entry, next) = match self.parse(self.pos) {
+            Some(parsed) => parsed,
+            None => {
+                warn!("initramfs: malformed entry at {}", self.pos);
+                self.pos = self.archive.len();
+                return None;
+            }
+        };
+        if entry.name == CPIO_TRAILER {
+            self.pos = self.archive.len();
+            return None;
+        }
+        self.pos = next;
+        Some(entry)
+    }
+}
+
+impl CpioIter {
+    /// Parse entry at `pos`, returning it and position of next entry, or
+    /// `None` if it's malformed
+    fn parse(&self, pos: usize) -> Option<(CpioEntry, usize)> {
+        let a = self.archive;
+        let hdr = a.get(pos..pos + HEADER_SIZE)?;
+        if &hdr[0..6] != CPIO_MAGIC {
+            return None;
+        }
+        let field = |i: usize| parse_hex(&hdr[6 + i * 8..14 + i * 8]);
+        let mode = field(1)?;
+        let filesize = field(6)?;
+        let namesize = field(11)?;
+        let name_start = pos + HEADER_SIZE;
+        // name includes trailing NUL
+        if namesize == 0 || namesize > a.len() - name_start {
+            return None;
+        }
+        let name = core::str::from_utf8(&a[name_start..name_start + namesize - 1]).ok()?;
+        // header and name are padded to 4 bytes, so is file data
+        let data_start = (name_start + namesize + 3) & !3;
+        if data_start > a.len() || filesize > a.len() - data_start {
+            return None;
+        }
+        let data = &a[data_start..data_start + filesize];
+        Some((CpioEntry { name, mode, data }, (data_start + filesize + 3) & !3))
+    }
+}
+
+/// Initramfs filesystem
+pub struct Initramfs {
+    archive: &'static [u8],
+}
+
+/// File in initramfs
+pub struct InitramfsInode {
+    data: &'static [u8],
+}
+
+impl Initramfs {
+    pub const fn new(archive: &'static [u8]) -> Self {
+        Self { archive }
+    }
+
+    /// Iterate all entries in archive
+    pub fn entries(&self) -> CpioIter {
+        CpioIter { archive: self.archive, pos: 0 }
+    }
+}
+
+/// Normalize name in archive (`./init`, `init`) and path (`/init`) for comparison
+fn trim_name(name: &str) -> &str {
+    name.strip_prefix("./").unwrap_or(name).trim_start_matches('/')
+}
+
+impl FileSystem for Initramfs {
+    fn lookup(&self, path: &str) -> Option<Arc<dyn Inode>> {
+        let path = trim_name(path);
+        for entry in self.entries() {
+            if entry.mode & S_IFMT == S_IFREG && trim_name(entry.name) == path {
+                return Some(Arc::new(InitramfsInode { data: entry.data }));
+            }
+        }
+        None
+    }
+}
+
+impl Inode for InitramfsInode {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read_at(&self, offset: usize, content: &mut [u8]) -> i32 {
+        if offset >= self.data.len() {
+            return 0;
+        }
+        let sz = (self.data.len() - offset).min(content.len());
+        content[..sz].copy_from_slice(&self.data[offset..offset + sz]);
+        sz as i32
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Filesystem on virtio disk made by `fs/fs.cpp`
+//!
+//! The first `FILE_MAX` blocks are headers, each containing size,
+//! offset and name of one file. A header of size 0 ends the list.
+
+use alloc::sync::Arc;
+use crate::fs::{FileSystem, Inode};
+use crate::block::{self, BSIZE};
+
+const FILE_MAX: usize = 1024;
+
+/// Maximum number of blocks read in one batch
+const READ_BATCH: usize = 64;
+
+/// Simple filesystem on block device `dev`
+pub struct SimpleFs {
+    dev: u32,
+}
+
+/// File in simple filesystem
+pub struct SimpleInode {
+    dev: u32,
+    /// offset of file content on disk
+    offset: usize,
+    sz: usize,
+}
+
+impl SimpleFs {
+    pub const fn new(dev: u32) -> Self {
+        Self { dev }
+    }
+
+    /// Check if block device `dev` holds a simple filesystem, whose
+    /// first file name should begin with `/`.
+    pub fn probe(dev: u32) -> bool {
+        let b = match block::read(dev, 0) {
+            Ok(b) => b,
+            Err(_) => { return false; }
+        };
+        let sz = unsafe { core::ptr::read(b.data.as_ptr() as *const usize) };
+        sz != 0 && b.data[16] == b'/'
+    }
+
+    fn get_file_info(&self, path: &str) -> Option<(usize, usize)> {
+        for id in 0..FILE_MAX {
+            let b = block::read(self.dev, id as u32).ok()?;
+            let sz = unsafe { core::ptr::read(b.data.as_ptr() as *const usize) };
+            let offset = unsafe { core::ptr::read((b.data.as_ptr() as *const usize).add(1)) };
+            if sz == 0 {
+                break;
+            }
+            let name_sz = {
+                let mut i = 16;
+                loop {
+                    let d = b.data[i];
+                    if d == 0 {
+                        break;
+                    }
+                    i += 1;
+                    if i == b.data.len() { break; }
+                }
+                i - 16
+            };
+            let u8_slice = unsafe { core::slice::from_raw_parts(b.data.as_ptr().add(16), name_sz) };
+            let name = core::str::from_utf8(u8_slice).unwrap();
+            if name == path {
+                return Some((offset, sz));
+            }
+        }
+        None
+    }
+}
+
+impl FileSystem for SimpleFs {
+    fn lookup(&self, path: &str) -> Option<Arc<dyn Inode>> {
+        let (offset, sz) = self.get_file_info(path)?;
+        Some(Arc::new(SimpleInode { dev: self.dev, offset, sz }))
+    }
+}
+
+impl Inode for SimpleInode {
+    fn size(&self) -> usize {
+        self.sz
+    }
+
+    fn read_at(&self, offset: usize, content: &mut [u8]) -> i32 {
+        if offset >= self.sz {
+            return 0;
+        }
+        let read_sz = (self.sz - offset).min(content.len());
+        let mut done = 0;
+        while done < read_sz {
+            let pos = self.offset + offset + done;
+            let blocks = (pos % BSIZE + read_sz - done + BSIZE - 1) / BSIZE;
+            let bufs = match block::read_blocks(self.dev, (pos / BSIZE) as u32, blocks.min(READ_BATCH)) {
+                Ok(bufs) => bufs,
+                Err(_) => { return -1; }
+            };
+            for b in bufs {
+                let pos = self.offset + offset + done;
+                let blk_offset = pos % BSIZE;
+                let sz = (BSIZE - blk_offset).min(read_sz - done);
+                content[done..done + sz].copy_from_slice(&b.data[blk_offset..blk_offset + sz]);
+                done += sz;
+            }
+        }
+        read_sz as i32
+    }
+}
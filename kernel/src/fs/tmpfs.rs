@@ -0,0 +1,310 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! In-memory filesystem
+//!
+//! File content is stored in pages from `mem::ALLOC`, which are given
+//! back when the file is unlinked and no longer opened. Pages are taken
+//! on behalf of user, so writes fail with `ENOMEM` when memory is low.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::fs::{FileSystem, Inode};
+use crate::mem;
+use crate::spinlock::Mutex;
+use crate::symbols::PAGE_SIZE;
+use crate::syscall::ENOMEM;
+
+/// Content of a file in tmpfs
+struct TmpFileData {
+    /// Address of pages holding file content
+    pages: Vec<usize>,
+    size: usize,
+}
+
+impl TmpFileData {
+    /// Allocate pages until `size` bytes fit. Returns `false` if memory is
+    /// low, with pages allocated so far given back.
+    fn reserve(&mut self, size: usize) -> bool {
+        while self.pages.len() * PAGE_SIZE < size {
+            let page = match mem::ALLOC().lock().allocate_user(PAGE_SIZE) {
+                Some(page) => page,
+                None => {
+                    self.shrink(self.size);
+                    return false;
+                }
+            };
+            unsafe { core::ptr::write_bytes(page, 0, PAGE_SIZE); }
+            self.pages.push(page as usize);
+        }
+        true
+    }
+
+    /// Free pages beyond `size` bytes
+    fn shrink(&mut self, size: usize) {
+        let keep = mem::align_val(size, crate::symbols::PAGE_ORDER) / PAGE_SIZE;
+        while self.pages.len() > keep {
+            let page = self.pages.pop().unwrap();
+            mem::ALLOC().lock().deallocate(page as *mut u8);
+        }
+        // zero the tail of last page, so that growing again reads zero
+        if size % PAGE_SIZE != 0 {
+            if let Some(&page) = self.pages.last() {
+                let off = size % PAGE_SIZE;
+                unsafe { core::ptr::write_bytes((page + off) as *mut u8, 0, PAGE_SIZE - off); }
+            }
+        }
+    }
+}
+
+impl Drop for TmpFileData {
+    fn drop(&mut self) {
+        self.shrink(0);
+    }
+}
+
+/// A file or directory in tmpfs
+pub enum TmpNode {
+    File(Mutex<TmpFileData>),
+    Dir(Mutex<BTreeMap<String, Arc<TmpNode>>>),
+}
+
+impl TmpNode {
+    fn new_file() -> Self {
+        TmpNode::File(Mutex::new(TmpFileData { pages: Vec::new(), size: 0 }, "tmpfs file"))
+    }
+
+    fn new_dir() -> Self {
+        TmpNode::Dir(Mutex::new(BTreeMap::new(), "tmpfs dir"))
+    }
+}
+
+impl Inode for TmpNode {
+    fn size(&self) -> usize {
+        match self {
+            TmpNode::File(f) => f.lock().size,
+            TmpNode::Dir(d) => d.lock().len(),
+        }
+    }
+
+    fn read_at(&self, offset: usize, content: &mut [u8]) -> i32 {
+        let f = match self {
+            TmpNode::File(f) => f.lock(),
+            TmpNode::Dir(_) => { return -1; }
+        };
+        if offset >= f.size {
+            return 0;
+        }
+        let read_sz = (f.size - offset).min(content.len());
+        let mut done = 0;
+        while done < read_sz {
+            let pos = offset + done;
+            let page = f.pages[pos / PAGE_SIZE];
+            let pg_offset = pos % PAGE_SIZE;
+            let sz = (PAGE_SIZE - pg_offset).min(read_sz - done);
+            unsafe {
+                core::ptr::copy((page + pg_offset) as *const u8, content[done..].as_mut_ptr(), sz);
+            }
+            done += sz;
+        }
+        read_sz as i32
+    }
+
+    fn write_at(&self, offset: usize, content: &[u8]) -> i32 {
+        let mut f = match self {
+            TmpNode::File(f) => f.lock(),
+            TmpNode::Dir(_) => { return -1; }
+        };
+        let end = offset + content.len();
+        if !f.reserve(end) {
+            return -ENOMEM;
+        }
+        let mut done = 0;
+        while done < content.len() {
+            let pos = offset + done;
+            let page = f.pages[pos / PAGE_SIZE];
+            let pg_offset = pos % PAGE_SIZE;
+            let sz = (PAGE_SIZE - pg_offset).min(content.len() - done);
+            unsafe {
+                core::ptr::copy(content[done..].as_ptr(), (page + pg_offset) as *mut u8, sz);
+            }
+            done += sz;
+        }
+        f.size = f.size.max(end);
+        content.len() as i32
+    }
+
+    fn truncate(&self, size: usize) -> i32 {
+        let mut f = match self {
+            TmpNode::File(f) => f.lock(),
+            TmpNode::Dir(_) => { return -1; }
+        };
+        if size < f.size {
+            f.shrink(size);
+        } else if !f.reserve(size) {
+            return -ENOMEM;
+        }
+        f.size = size;
+        0
+    }
+}
+
+/// In-memory filesystem
+pub struct Tmpfs {
+    root: Arc<TmpNode>,
+}
+
+/// Split `path` into parent directory components and file name
+fn split_path(path: &str) -> Option<(Vec<&str>, &str)> {
+    let mut parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let name = parts.pop()?;
+    Some((parts, name))
+}
+
+impl Tmpfs {
+    pub fn new() -> Self {
+        Self { root: Arc::new(TmpNode::new_dir()) }
+    }
+
+    /// Find node following `parts` from root
+    fn walk(&self, parts: &[&str]) -> Option<Arc<TmpNode>> {
+        let mut node = self.root.clone();
+        for part in parts {
+            let next = match &*node {
+                TmpNode::Dir(d) => d.lock().get(*part)?.clone(),
+                TmpNode::File(_) => { return None; }
+            };
+            node = next;
+        }
+        Some(node)
+    }
+
+    /// Insert a new node at `path`, whose parent directory must exist
+    fn insert(&self, path: &str, node: TmpNode) -> Option<Arc<TmpNode>> {
+        let (parents, name) = split_path(path)?;
+        let parent = self.walk(&parents)?;
+        let mut dir = match &*parent {
+            TmpNode::Dir(d) => d.lock(),
+            TmpNode::File(_) => { return None; }
+        };
+        if dir.contains_key(name) {
+            return None;
+        }
+        let node = Arc::new(node);
+        dir.insert(String::from(name), node.clone());
+        Some(node)
+    }
+}
+
+impl Default for Tmpfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for Tmpfs {
+    fn lookup(&self, path: &str) -> Option<Arc<dyn Inode>> {
+        let parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let node = self.walk(&parts)?;
+        match &*node {
+            TmpNode::File(_) => Some(node),
+            TmpNode::Dir(_) => None,
+        }
+    }
+
+    fn create(&self, path: &str) -> Option<Arc<dyn Inode>> {
+        let node = self.insert(path, TmpNode::new_file())?;
+        Some(node)
+    }
+
+    fn mkdir(&self, path: &str) -> i32 {
+        match self.insert(path, TmpNode::new_dir()) {
+            Some(_) => 0,
+            None => -1,
+        }
+    }
+
+    fn unlink(&self, path: &str) -> i32 {
+        let (parents, name) = match split_path(path) {
+            Some(x) => x,
+            None => { return -1; }
+        };
+        let parent = match self.walk(&parents) {
+            Some(x) => x,
+            None => { return -1; }
+        };
+        let mut dir = match &*parent {
+            TmpNode::Dir(d) => d.lock(),
+            TmpNode::File(_) => { return -1; }
+        };
+        let is_empty_dir = match dir.get(name).map(|n| &**n) {
+            Some(TmpNode::Dir(d)) => d.lock().is_empty(),
+            Some(TmpNode::File(_)) => true,
+            None => { return -1; }
+        };
+        if !is_empty_dir {
+            return -1;
+        }
+        // pages are freed when the last reference to the node is dropped
+        dir.remove(name);
+        0
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("create and rw", test_rw),
+            ("truncate", test_truncate),
+            ("directory", test_dir),
+        ]
+    }
+
+    /// Test creating, writing across pages and reading back
+    pub fn test_rw() {
+        let fs = Tmpfs::new();
+        let f = fs.create("/a").unwrap();
+        assert!(fs.create("/a").is_none());
+        let data = [0x5a_u8; PAGE_SIZE + 100];
+        assert_eq!(f.write_at(10, &data), data.len() as i32);
+        assert_eq!(f.size(), PAGE_SIZE + 110);
+        let mut content = [0; PAGE_SIZE + 200];
+        assert_eq!(fs.lookup("/a").unwrap().read_at(0, &mut content), (PAGE_SIZE + 110) as i32);
+        assert_eq!(content[9], 0);
+        assert_eq!(content[10], 0x5a);
+        assert_eq!(content[PAGE_SIZE + 109], 0x5a);
+        assert_eq!(fs.unlink("/a"), 0);
+        assert!(fs.lookup("/a").is_none());
+    }
+
+    /// Test truncating clears content
+    pub fn test_truncate() {
+        let fs = Tmpfs::new();
+        let f = fs.create("/a").unwrap();
+        f.write_at(0, b"hello, world");
+        assert_eq!(f.truncate(5), 0);
+        assert_eq!(f.truncate(8), 0);
+        let mut content = [0xff; 8];
+        assert_eq!(f.read_at(0, &mut content), 8);
+        assert_eq!(&content, b"hello\0\0\0");
+    }
+
+    /// Test directories
+    pub fn test_dir() {
+        let fs = Tmpfs::new();
+        assert_eq!(fs.mkdir("/d"), 0);
+        assert_eq!(fs.mkdir("/d"), -1);
+        assert!(fs.create("/x/a").is_none());
+        assert!(fs.create("/d/a").is_some());
+        assert!(fs.lookup("/d/a").is_some());
+        assert_eq!(fs.unlink("/d"), -1);
+        assert_eq!(fs.unlink("/d/a"), 0);
+        assert_eq!(fs.unlink("/d"), 0);
+    }
+}
@@ -6,7 +6,7 @@
 //! Handle interrupts
 
 use riscv::register::*;
-use crate::{arch, plic, println};
+use crate::{arch, fdt, ipi, plic, println, timer, uart};
 use crate::uart::uartintr;
 use crate::virtio::virtiointr;
 // use crate::uart::uartintr;
@@ -27,24 +27,40 @@ pub fn devintr() -> Option<Intr> {
     if cause.is_interrupt() && cause.code() == 9 {
         let plic = plic::PLIC();
         if let Some(interrupt) = plic.next() {
-            match interrupt {
-                plic::UART0_IRQ => {
-                    uartintr();
-                },
-                plic::VIRTIO0_IRQ => {
-                    virtiointr();
-                },
-                _ => {
-                    println!("Unrecognized external interrupt: {}", interrupt);
-                }
+            if interrupt == fdt::machine().uart.irq {
+                uartintr();
+            } else if !virtiointr(interrupt) {
+                println!("Unrecognized external interrupt: {}", interrupt);
             }
             plic.complete(interrupt);
         }
         Some(Intr::Device)
     } else if cause.is_interrupt() && cause.code() == 1 {
         arch::sip_write(sip::read().bits() & !2);
-        Some(Intr::Timer)
+        ipi::handle();
+        if timer::backend() == timer::Backend::Clint {
+            // may also be timer forwarded by `timervec`
+            Some(timer_intr())
+        } else {
+            Some(Intr::Device)
+        }
+    } else if cause.is_interrupt() && cause.code() == 5 {
+        Some(timer_intr())
     } else {
         None
     }
 }
+
+/// Process timer interrupt. It's `Intr::Timer` only if time slice ends.
+fn timer_intr() -> Intr {
+    uart::poll();
+    let expired = timer::interrupt();
+    if expired.event {
+        timer::run_timers();
+    }
+    if expired.slice {
+        Intr::Timer
+    } else {
+        Intr::Device
+    }
+}
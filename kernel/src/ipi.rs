@@ -0,0 +1,131 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Inter-processor interrupts and cross-calls
+//!
+//! IPIs are supervisor software interrupts, sent through SBI IPI extension
+//! under SBI, or by writing CLINT `msip` otherwise, which `timervec`
+//! forwards to supervisor mode. A cross-call queues a function for other
+//! harts and interrupts them, then waits until all of them have run it.
+//! While waiting, the calling hart runs calls queued for itself, so that
+//! two harts cross-calling each other don't deadlock.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::arch::hart_id;
+use crate::fdt::machine;
+use crate::process::my_cpu;
+use crate::sbi;
+use crate::spinlock::Mutex;
+use crate::symbols::NCPUS;
+
+/// A function called on other harts
+struct Call {
+    func: Box<dyn Fn() + Send + Sync>,
+    /// Number of harts yet to run `func`
+    pending: AtomicUsize,
+}
+
+/// Calls queued for each hart
+static QUEUES: [Mutex<VecDeque<Arc<Call>>>; NCPUS] = [const { Mutex::new(VecDeque::new(), "ipi queue") }; NCPUS];
+
+/// Whether each hart takes IPIs
+static ONLINE: [AtomicBool; NCPUS] = [const { AtomicBool::new(false) }; NCPUS];
+
+/// Mark current hart as taking IPIs. Should be called after trap vector
+/// is set.
+pub fn hartinit() {
+    ONLINE[hart_id()].store(true, Ordering::SeqCst);
+}
+
+/// Send IPI to `hart`
+pub fn send(hart: usize) {
+    if sbi::enabled() {
+        sbi::send_ipi(1, hart);
+    } else {
+        let msip = machine().clint.base + 4 * hart;
+        unsafe { (msip as *mut u32).write_volatile(1); }
+    }
+}
+
+/// Run calls queued for current hart. Should be called in IPI, or with
+/// interrupt off.
+pub fn handle() {
+    loop {
+        let call = match QUEUES[hart_id()].lock().pop_front() {
+            Some(call) => call,
+            None => { return; }
+        };
+        (call.func)();
+        call.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Run `func` on all online harts other than current one, and wait until
+/// all of them have finished. Returns number of harts called.
+///
+/// Other harts run `func` in interrupt, so caller should not hold a lock
+/// that they may be spinning on with interrupt off.
+pub fn cross_call<F: Fn() + Send + Sync + 'static>(func: F) -> usize {
+    // stay on this hart while waiting
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let me = hart_id();
+    let targets = (0..NCPUS).filter(|&hart| hart != me && ONLINE[hart].load(Ordering::SeqCst));
+    let call = Arc::new(Call { func: Box::new(func), pending: AtomicUsize::new(0) });
+    let mut count = 0;
+    for hart in targets {
+        call.pending.fetch_add(1, Ordering::SeqCst);
+        QUEUES[hart].lock().push_back(call.clone());
+        send(hart);
+        count += 1;
+    }
+    while call.pending.load(Ordering::SeqCst) != 0 {
+        handle();
+        core::hint::spin_loop();
+    }
+    count
+}
+
+/// Flush TLB of current hart, for page of `vaddr`, or for all pages, in
+/// address space `asid`, or in all address spaces
+pub fn sfence_vma(vaddr: Option<usize>, asid: Option<usize>) {
+    unsafe {
+        match (vaddr, asid) {
+            (Some(vaddr), Some(asid)) => asm!("sfence.vma {0}, {1}", in(reg) vaddr, in(reg) asid),
+            (Some(vaddr), None) => asm!("sfence.vma {0}, zero", in(reg) vaddr),
+            (None, Some(asid)) => asm!("sfence.vma zero, {0}", in(reg) asid),
+            (None, None) => asm!("sfence.vma zero, zero"),
+        }
+    }
+}
+
+/// Flush TLB of all harts after a page table is modified, for page of
+/// `vaddr`, or for all pages, in address space `asid`, or in all of them
+pub fn flush_tlb(vaddr: Option<usize>, asid: Option<usize>) {
+    sfence_vma(vaddr, asid);
+    cross_call(move || sfence_vma(vaddr, asid));
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("cross call", test_cross_call),
+        ]
+    }
+
+    /// Test that cross-call runs on all other online harts
+    pub fn test_cross_call() {
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        CALLED.store(0, Ordering::SeqCst);
+        let count = cross_call(|| { CALLED.fetch_add(1, Ordering::SeqCst); });
+        assert_eq!(CALLED.load(Ordering::SeqCst), count);
+        flush_tlb(None, None);
+    }
+}
@@ -7,12 +7,20 @@ extern crate alloc;
 
 use core::arch::asm;
 
+pub mod fdt;
 pub mod uart;
+pub mod tty;
 pub mod page;
 pub mod trap;
 pub mod plic;
 pub mod syscall;
 pub mod clint;
+pub mod sbi;
+pub mod timer;
+pub mod ipi;
+pub mod asid;
+pub mod rtc;
+pub mod power;
 pub mod intr;
 pub mod start;
 pub mod spinlock;
@@ -23,7 +31,12 @@ pub mod symbols;
 pub mod print;
 pub mod mem;
 pub mod virtio;
+pub mod block;
+pub mod net;
+pub mod random;
 pub mod file;
+pub mod fs;
+pub mod partition;
 pub mod elf;
 pub mod test;
 
@@ -46,7 +59,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         println!("no information available.");
     }
-    abort();
+    power::exit(power::PANIC_EXIT_CODE);
 }
 
 #[no_mangle]
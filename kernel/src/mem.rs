@@ -6,27 +6,73 @@
 //! Allocator implementation
 
 use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::info;
 use crate::println;
 use crate::symbols::*;
 use crate::spinlock::Mutex;
 use crate::page::EntryAttributes;
 use crate::page::{Table, KERNEL_PGTABLE};
-use crate::uart::UART_BASE_ADDR;
+use crate::fdt::machine;
 use riscv::{register::*, asm};
 use crate::arch;
 
-/// Maximum number of pages. As QEMU and linker script `kernel.ld`
-/// are set to have 128MB of RAM, maximum number of pages can be calculated.
-pub const MAX_PAGE: usize = 128 * 1024 * 1024 / (1 << 12);
+pub mod slab;
+use slab::class_of;
 
-/// Frame allocator gives out one or more pages.
+/// Number of block orders, largest block being `1 << (ORDERS - 1)` pages
+pub const ORDERS: usize = 16;
+
+/// Page record of a free block, or'ed with its order
+const FREE: u8 = 0x80;
+/// Page record of a page not at start of a block
+const TAIL: u8 = 0xff;
+/// End of free list
+const NIL: usize = usize::MAX;
+
+/// Free list links, stored in first page of a free block
+struct Link {
+    prev: usize,
+    next: usize,
+}
+
+/// Buddy frame allocator gives out blocks of `1 << order` pages.
+///
+/// Free blocks of each order are kept in a doubly-linked list threaded
+/// through the blocks themselves. Allocation splits the smallest free block
+/// large enough, and freeing merges a block with its buddy as long as the
+/// buddy is free, so both take O(log n).
 pub struct Allocator {
-    /// A bool array records whether a page is handed out
-    pub page_allocated: [usize; MAX_PAGE],
+    /// Record of each page, placed at start of heap and sized by memory
+    /// found in FDT. The first page of a block records its order, or'ed
+    /// with `FREE` if it's free. Other pages record `TAIL`.
+    pub page_order: &'static mut [u8],
     /// Pages are handed out from `base_addr`, which is the start address
     /// of HEAP.
     pub base_addr: usize,
+    /// First free block of each order
+    free: [usize; ORDERS],
+    /// Number of free blocks of each order
+    free_blocks: [usize; ORDERS],
+    /// Number of live allocations
+    allocations: usize,
+    /// Free pages kept for kernel, which `allocate_user` doesn't take
+    pub reserved: usize,
+}
+
+/// Page usage of allocator
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Pages managed by allocator
+    pub total: usize,
+    /// Pages in free blocks
+    pub free: usize,
+    /// Pages in allocated blocks, including those rounded up
+    pub used: usize,
+    /// Number of live allocations
+    pub allocations: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; ORDERS],
 }
 
 /// Align an address to upper bound according to specified order.
@@ -45,14 +91,43 @@ pub const fn page_down(val: usize) -> usize {
     align_val_down(val, PAGE_ORDER)
 }
 
+/// Smallest order of a block holding `pages` pages
+pub const fn order_of(pages: usize) -> usize {
+    if pages <= 1 { 0 } else { (usize::BITS - (pages - 1).leading_zeros()) as usize }
+}
+
 impl Allocator {
     /// Returns a new allocator instance
     ///
-    /// `base_addr` should be intialized later.
+    /// Pages should be given later with `init`.
     pub const fn new() -> Self {
         Allocator {
             base_addr: 0,
-            page_allocated: [0; MAX_PAGE],
+            page_order: &mut [],
+            free: [NIL; ORDERS],
+            free_blocks: [0; ORDERS],
+            allocations: 0,
+            reserved: 0,
+        }
+    }
+
+    /// Manage pages from `base_addr`, one for each record in `page_order`,
+    /// all of them free
+    pub unsafe fn init(&mut self, page_order: &'static mut [u8], base_addr: usize) {
+        *self = Self::new();
+        self.base_addr = base_addr;
+        self.page_order = page_order;
+        self.page_order.fill(TAIL);
+        // largest aligned blocks that fit
+        let pages = self.page_order.len();
+        let mut id = 0;
+        while id < pages {
+            let mut order = (id.trailing_zeros() as usize).min(ORDERS - 1);
+            while id + (1 << order) > pages {
+                order -= 1;
+            }
+            self.push(id, order);
+            id += 1 << order;
         }
     }
 
@@ -70,72 +145,169 @@ impl Allocator {
         (page as usize - self.base_addr) / PAGE_SIZE
     }
 
-    pub fn allocate(&mut self, size: usize) -> *mut u8 {
+    fn link(&mut self, id: usize) -> &mut Link {
+        unsafe { &mut *(self.offset_addr_of(id) as *mut Link) }
+    }
+
+    /// Put block `id` of `order` into free list
+    fn push(&mut self, id: usize, order: usize) {
+        let next = self.free[order];
+        *self.link(id) = Link { prev: NIL, next };
+        if next != NIL {
+            self.link(next).prev = id;
+        }
+        self.free[order] = id;
+        self.free_blocks[order] += 1;
+        self.page_order[id] = FREE | order as u8;
+    }
+
+    /// Take free block `id` of `order` out of free list
+    fn remove(&mut self, id: usize, order: usize) {
+        let Link { prev, next } = *self.link(id);
+        if prev == NIL {
+            self.free[order] = next;
+        } else {
+            self.link(prev).next = next;
+        }
+        if next != NIL {
+            self.link(next).prev = prev;
+        }
+        self.free_blocks[order] -= 1;
+        self.page_order[id] = TAIL;
+    }
+
+    /// Allocate a block of at least `size` bytes, rounded up to a power of
+    /// two pages. Returns `None` if there's no free block large enough.
+    pub fn try_allocate(&mut self, size: usize) -> Option<*mut u8> {
         let page_required = align_val(size, PAGE_ORDER) / PAGE_SIZE;
-        for i in 0..MAX_PAGE {
-            if self.page_allocated[i] == 0 {
-                let mut found = true;
-                for j in 0..page_required {
-                    if self.page_allocated[i + j] != 0 {
-                        found = false;
-                        break;
-                    }
-                }
-                if found {
-                    for j in 0..page_required {
-                        self.page_allocated[i + j] = page_required;
-                    }
-                    unsafe { return self.offset_id_of(i); }
-                }
-            }
+        let order = order_of(page_required);
+        let mut from = order;
+        while from < ORDERS && self.free[from] == NIL {
+            from += 1;
+        }
+        if from >= ORDERS {
+            return None;
+        }
+        let id = self.free[from];
+        self.remove(id, from);
+        // give back the upper halves
+        while from > order {
+            from -= 1;
+            self.push(id + (1 << from), from);
+        }
+        self.page_order[id] = order as u8;
+        self.allocations += 1;
+        unsafe { Some(self.offset_id_of(id)) }
+    }
+
+    /// Allocate for kernel, which may take reserved pages. Running into them
+    /// raises `LOW_MEMORY`, so that a process is killed to free some.
+    pub fn allocate(&mut self, size: usize) -> *mut u8 {
+        let addr = match self.try_allocate(size) {
+            Some(addr) => addr,
+            None => panic!("no available page")
+        };
+        if self.free_pages() < self.reserved {
+            LOW_MEMORY.store(true, Ordering::SeqCst);
+        }
+        addr
+    }
+
+    /// Allocate on behalf of a user process, leaving reserved pages to
+    /// kernel. Returns `None` if memory is low.
+    pub fn allocate_user(&mut self, size: usize) -> Option<*mut u8> {
+        let pages = 1 << order_of(align_val(size, PAGE_ORDER) / PAGE_SIZE);
+        if self.free_pages() < self.reserved + pages {
+            return None;
         }
-        panic!("no available page")
+        self.try_allocate(size)
     }
 
     pub fn deallocate(&mut self, addr: *mut u8) {
-        let id = self.offset_page_of(addr);
-        let page_stride = self.page_allocated[id];
-        for j in 0..page_stride {
-            self.page_allocated[j + id] = 0;
+        let mut id = self.offset_page_of(addr);
+        let mut order = self.page_order[id] as usize;
+        if order >= ORDERS {
+            panic!("freeing {:p}, which is not allocated", addr);
+        }
+        self.page_order[id] = TAIL;
+        self.allocations -= 1;
+        while order + 1 < ORDERS {
+            let buddy = id ^ (1 << order);
+            if buddy >= self.page_order.len() || self.page_order[buddy] != FREE | order as u8 {
+                break;
+            }
+            self.remove(buddy, order);
+            id = id.min(buddy);
+            order += 1;
+        }
+        self.push(id, order);
+    }
+
+    fn free_pages(&self) -> usize {
+        self.free_blocks.iter().enumerate().map(|(order, count)| count << order).sum()
+    }
+
+    /// Free pages that `allocate_user` may take
+    pub fn user_available(&self) -> usize {
+        self.free_pages().saturating_sub(self.reserved)
+    }
+
+    /// Current page usage
+    pub fn stats(&self) -> Stats {
+        let free = self.free_pages();
+        Stats {
+            total: self.page_order.len(),
+            free,
+            used: self.page_order.len() - free,
+            allocations: self.allocations,
+            free_blocks: self.free_blocks,
         }
     }
 
     /// Print page allocation status
     pub fn debug(&self) {
-        let mut j = 0;
-        loop {
-            let size = self.page_allocated[j];
-            let addr = &self.page_allocated as *const usize;
-            let addr = unsafe { addr.add(j) };
-            if size != 0 {
-                let from = self.offset_addr_of(j);
-                let to = self.offset_addr_of(j + size);
-                println!("{} {:X} {:X}-{:X} (pages: {:X})", j, addr as usize, from, to, size);
-                j += size;
-            } else {
-                j += 1;
-            }
-            if j == MAX_PAGE {
-                break;
+        let stats = self.stats();
+        println!("pages: {} total, {} free, {} used by {} allocations",
+            stats.total, stats.free, stats.used, stats.allocations);
+        for (order, count) in stats.free_blocks.iter().enumerate() {
+            if *count != 0 {
+                println!("  order {:2} ({:6} pages): {} free", order, 1 << order, count);
             }
         }
     }
 }
 
+/// Pages kept for kernel when user allocations run out of memory, 1 MiB
+pub const RESERVED_PAGES: usize = 256;
+
+/// Set when kernel allocations take reserved pages
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// Whether kernel has run into reserved pages since last call
+pub fn take_low_memory() -> bool {
+    LOW_MEMORY.swap(false, Ordering::SeqCst)
+}
+
 static __ALLOC: Mutex<Allocator> = Mutex::new(Allocator::new(), "global allocator");
 
 
+/// End of heap, which is end of memory found in FDT
+pub fn heap_end() -> usize {
+    let memory = machine().memory;
+    page_down(memory.base + memory.size)
+}
+
 /// Initialize allocator and kernel page table
-/// This function should only be called in boot hart
+/// This function should only be called in boot hart, after FDT is parsed
 pub unsafe fn init() {
-    // Initialize allocator
-    ALLOC().get().base_addr = align_val(HEAP_START(), PAGE_ORDER);
-
-    // workaround for non-zero data region
-    let alloc = ALLOC().get();
-    for i in 0..MAX_PAGE {
-        alloc.page_allocated[i] = 0;
-    }
+    // Initialize allocator, with page records at start of heap
+    let heap_start = align_val(HEAP_START(), PAGE_ORDER);
+    let heap_end = heap_end();
+    let max_page = (heap_end - heap_start) / PAGE_SIZE;
+    let base_addr = align_val(heap_start + max_page, PAGE_ORDER);
+    let page_order = core::slice::from_raw_parts_mut(heap_start as *mut u8, (heap_end - base_addr) / PAGE_SIZE);
+    ALLOC().get().init(page_order, base_addr);
+    ALLOC().get().reserved = RESERVED_PAGES;
 
     #[allow(invalid_reference_casting)]
     let pgtable: &mut Table = &mut *(&KERNEL_PGTABLE as *const _ as *mut _); // to bypass mut ref
@@ -164,16 +336,15 @@ pub unsafe fn init() {
         KERNEL_STACK_END(),
         EntryAttributes::RW as usize,
     );
-    pgtable.kernel_map(
-        UART_BASE_ADDR,
-        UART_BASE_ADDR,
-        EntryAttributes::RW as usize,
-    );
-    pgtable.kernel_map(
-        VIRTIO_MMIO_BASE,
-        VIRTIO_MMIO_BASE,
+    let machine = machine();
+    pgtable.id_map_range(
+        machine.uart.base,
+        machine.uart.base + machine.uart.size,
         EntryAttributes::RW as usize,
     );
+    for virtio in machine.virtio() {
+        pgtable.id_map_range(virtio.base, virtio.base + virtio.size, EntryAttributes::RW as usize);
+    }
     pgtable.kernel_map(
         TRAMPOLINE_START,
         TRAMPOLINE_TEXT_START(),
@@ -181,13 +352,17 @@ pub unsafe fn init() {
     );
     pgtable.id_map_range(
         HEAP_START(),
-        HEAP_START() + HEAP_SIZE(),
+        heap_end,
         EntryAttributes::RW as usize,
     );
+    // test finisher
+    pgtable.kernel_map(page_down(machine.finisher.base), page_down(machine.finisher.base), EntryAttributes::RW as usize);
+    // RTC
+    pgtable.kernel_map(page_down(machine.rtc.base), page_down(machine.rtc.base), EntryAttributes::RW as usize);
     // CLINT
-    pgtable.id_map_range(CLINT_BASE, CLINT_BASE + 0x10000, EntryAttributes::RW as usize);
+    pgtable.id_map_range(machine.clint.base, machine.clint.base + machine.clint.size, EntryAttributes::RW as usize);
     // PLIC
-    pgtable.id_map_range(PLIC_BASE, PLIC_BASE + 0x400000, EntryAttributes::RW as usize);
+    pgtable.id_map_range(machine.plic.base, machine.plic.base + machine.plic.size, EntryAttributes::RW as usize);
 }
 
 pub fn hartinit() {
@@ -203,19 +378,25 @@ pub fn hartinit() {
 pub fn ALLOC() -> &'static Mutex<Allocator> { &__ALLOC }
 
 use core::alloc::{GlobalAlloc, Layout};
-use crate::plic::PLIC_BASE;
-use crate::clint::CLINT_BASE;
-use crate::virtio::VIRTIO_MMIO_BASE;
 
 struct OsAllocator {}
 
+/// Small objects come from slabs, others take whole pages. Pages are only
+/// aligned to `PAGE_SIZE`, so larger alignment can't be satisfied.
 unsafe impl GlobalAlloc for OsAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        ALLOC().lock().allocate(layout.size())
+        match class_of(layout) {
+            Some(class) => slab::alloc(class),
+            None if layout.align() <= PAGE_SIZE => ALLOC().lock().allocate(layout.size()),
+            None => core::ptr::null_mut(),
+        }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        ALLOC().lock().deallocate(ptr);
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match class_of(layout) {
+            Some(class) => slab::dealloc(ptr, class),
+            None => ALLOC().lock().deallocate(ptr),
+        }
     }
 }
 
@@ -258,3 +439,123 @@ pub fn debug() {
 pub fn alloc_stack() -> *mut u8 {
     ALLOC().lock().allocate(PAGE_SIZE * 1024)
 }
+
+pub mod tests {
+    use super::*;
+    use super::slab::{class_size, SizeClass};
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("buddy", test_buddy),
+            ("uneven", test_uneven),
+            ("reserve", test_reserve),
+            ("size class", test_size_class),
+            ("slab", test_slab),
+            ("slab alignment", test_slab_alignment),
+        ]
+    }
+
+    /// Allocator over `pages` pages taken from global allocator
+    fn local_allocator(pages: usize) -> (Allocator, *mut u8) {
+        let region = ALLOC().lock().allocate(pages * PAGE_SIZE);
+        let page_order = Box::leak(vec![0u8; pages].into_boxed_slice());
+        let mut alloc = Allocator::new();
+        unsafe { alloc.init(page_order, region as usize); }
+        (alloc, region)
+    }
+
+    /// Test splitting blocks and merging buddies
+    pub fn test_buddy() {
+        let (mut alloc, region) = local_allocator(16);
+        assert_eq!(alloc.stats().free_blocks[4], 1);
+        let a = alloc.allocate(PAGE_SIZE);
+        // rounded up to 4 pages
+        let b = alloc.allocate(3 * PAGE_SIZE);
+        let c = alloc.allocate(PAGE_SIZE);
+        assert_eq!(c as usize, a as usize + PAGE_SIZE);
+        let stats = alloc.stats();
+        assert_eq!((stats.free, stats.used, stats.allocations), (10, 6, 3));
+        assert_eq!((stats.free_blocks[1], stats.free_blocks[3]), (1, 1));
+        alloc.deallocate(a);
+        alloc.deallocate(b);
+        assert_eq!(alloc.stats().free_blocks[4], 0);
+        alloc.deallocate(c);
+        let stats = alloc.stats();
+        assert_eq!((stats.free, stats.allocations, stats.free_blocks[4]), (16, 0, 1));
+        ALLOC().lock().deallocate(region);
+    }
+
+    /// Test memory not a power of two pages
+    pub fn test_uneven() {
+        let (mut alloc, region) = local_allocator(13);
+        let stats = alloc.stats();
+        assert_eq!(stats.free, 13);
+        assert_eq!((stats.free_blocks[0], stats.free_blocks[2], stats.free_blocks[3]), (1, 1, 1));
+        let a = alloc.allocate(8 * PAGE_SIZE);
+        assert_eq!(a, region);
+        alloc.deallocate(a);
+        assert_eq!(alloc.stats().free_blocks[3], 1);
+        ALLOC().lock().deallocate(region);
+    }
+
+    /// Test that user allocations leave reserved pages
+    pub fn test_reserve() {
+        let (mut alloc, region) = local_allocator(16);
+        alloc.reserved = 4;
+        let a = alloc.allocate_user(8 * PAGE_SIZE).unwrap();
+        assert!(alloc.allocate_user(8 * PAGE_SIZE).is_none());
+        let b = alloc.allocate_user(4 * PAGE_SIZE).unwrap();
+        assert!(alloc.allocate_user(PAGE_SIZE).is_none());
+        // kernel may take them
+        let c = alloc.try_allocate(4 * PAGE_SIZE).unwrap();
+        assert!(alloc.try_allocate(PAGE_SIZE).is_none());
+        for addr in [a, b, c] {
+            alloc.deallocate(addr);
+        }
+        assert_eq!(alloc.stats().free, 16);
+        ALLOC().lock().deallocate(region);
+    }
+
+    /// Test rounding layouts to size classes
+    pub fn test_size_class() {
+        let class = |size, align| class_of(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(17, 8), Some(1));
+        assert_eq!(class(24, 64), Some(2));
+        assert_eq!(class(1024, 8), Some(6));
+        assert_eq!(class(1025, 8), None);
+        assert_eq!(class(8, PAGE_SIZE), None);
+    }
+
+    /// Test carving and giving back slabs
+    pub fn test_slab() {
+        let mut class = SizeClass::new(class_size(6));
+        let capacity = class.capacity();
+        assert_eq!(capacity, 3);
+        let objects: alloc::vec::Vec<_> = (0..capacity + 1).map(|_| class.alloc()).collect();
+        assert_eq!(class.usage(), (capacity + 1, 2));
+        for object in objects.iter() {
+            assert_eq!(*object as usize % 1024, 0);
+        }
+        for object in objects {
+            class.dealloc(object);
+        }
+        assert_eq!(class.usage(), (0, 0));
+    }
+
+    /// Test that heap objects honor alignment and share pages
+    pub fn test_slab_alignment() {
+        #[repr(align(256))]
+        struct Aligned(u8);
+        let a = Box::new(Aligned(1));
+        let b = Box::new(Aligned(2));
+        assert_eq!(&*a as *const _ as usize % 256, 0);
+        assert_eq!(&*b as *const _ as usize % 256, 0);
+        assert_eq!(page_down(&*a as *const _ as usize), page_down(&*b as *const _ as usize));
+        let small = Box::new(0u64);
+        assert_eq!(&*small as *const _ as usize % 16, 0);
+        assert_eq!(a.0 + b.0, 3);
+    }
+}
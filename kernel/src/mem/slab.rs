@@ -0,0 +1,239 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Slab allocator for small kernel objects
+//!
+//! Objects up to `MAX_SLAB_SIZE` bytes are rounded up to a power-of-two
+//! size class, at least their alignment, and carved out of one-page slabs
+//! of that class, so they are naturally aligned to their size. Each slab
+//! begins with a header, and keeps its free objects in a list threaded
+//! through them. Slabs with free objects are linked in their class, and an
+//! empty slab is given back to page allocator.
+//!
+//! Each hart caches up to `CACHE_SIZE` free objects of every class, so
+//! most allocations don't take the class lock, let alone `ALLOC`. A cache
+//! is refilled from, and drained to, slabs half of it at a time.
+
+use core::alloc::Layout;
+use core::ptr::null_mut;
+use crate::arch::hart_id;
+use crate::println;
+use crate::process::my_cpu;
+use crate::spinlock::Mutex;
+use crate::symbols::{NCPUS, PAGE_SIZE};
+use super::{align_val, ALLOC};
+
+/// Smallest size class
+pub const MIN_SLAB_SIZE: usize = 16;
+/// Largest size class, larger objects take whole pages
+pub const MAX_SLAB_SIZE: usize = 1024;
+/// Number of size classes
+const CLASSES: usize = 7;
+
+/// Free objects cached by each hart for each class
+const CACHE_SIZE: usize = 32;
+
+/// Size class of `layout`, `None` if it should take whole pages
+pub fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_SLAB_SIZE).next_power_of_two();
+    if size > MAX_SLAB_SIZE {
+        None
+    } else {
+        Some((size / MIN_SLAB_SIZE).trailing_zeros() as usize)
+    }
+}
+
+/// Object size of `class`
+pub const fn class_size(class: usize) -> usize {
+    MIN_SLAB_SIZE << class
+}
+
+/// A free object, linking to next one
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at start of a slab page
+struct Slab {
+    /// Objects handed out, including those in hart caches
+    in_use: usize,
+    free: *mut FreeObject,
+    prev: *mut Slab,
+    next: *mut Slab,
+}
+
+/// Offset of first object in a slab of object `size`
+const fn first_object(size: usize) -> usize {
+    align_val(core::mem::size_of::<Slab>(), size.trailing_zeros() as usize)
+}
+
+/// Slab of object at `addr`
+fn slab_of(addr: usize) -> *mut Slab {
+    (addr & !(PAGE_SIZE - 1)) as *mut Slab
+}
+
+/// Slabs of a size class
+pub struct SizeClass {
+    size: usize,
+    /// Slabs with free objects
+    partial: *mut Slab,
+    /// Number of slabs
+    slabs: usize,
+    /// Objects handed out
+    in_use: usize,
+}
+
+unsafe impl Send for SizeClass {}
+
+impl SizeClass {
+    pub const fn new(size: usize) -> Self {
+        Self { size, partial: null_mut(), slabs: 0, in_use: 0 }
+    }
+
+    /// Number of objects in a slab
+    pub const fn capacity(&self) -> usize {
+        (PAGE_SIZE - first_object(self.size)) / self.size
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let Slab { prev, next, .. } = *slab;
+        if prev.is_null() {
+            self.partial = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+    }
+
+    /// Carve a new page into free objects
+    unsafe fn grow(&mut self) {
+        let page = ALLOC().lock().allocate(PAGE_SIZE) as usize;
+        let slab = page as *mut Slab;
+        *slab = Slab { in_use: 0, free: null_mut(), prev: null_mut(), next: null_mut() };
+        for i in (0..self.capacity()).rev() {
+            let object = (page + first_object(self.size) + i * self.size) as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+        }
+        self.link(slab);
+        self.slabs += 1;
+    }
+
+    /// Take one free object
+    pub fn alloc(&mut self) -> *mut u8 {
+        unsafe {
+            if self.partial.is_null() {
+                self.grow();
+            }
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+            self.in_use += 1;
+            object as *mut u8
+        }
+    }
+
+    /// Give back `object` allocated from this class
+    pub fn dealloc(&mut self, object: *mut u8) {
+        unsafe {
+            let slab = slab_of(object as usize);
+            let object = object as *mut FreeObject;
+            if (*slab).free.is_null() {
+                self.link(slab);
+            }
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            self.in_use -= 1;
+            if (*slab).in_use == 0 {
+                self.unlink(slab);
+                self.slabs -= 1;
+                ALLOC().lock().deallocate(slab as *mut u8);
+            }
+        }
+    }
+
+    /// Objects handed out, and number of slabs
+    pub fn usage(&self) -> (usize, usize) {
+        (self.in_use, self.slabs)
+    }
+
+    /// Print usage of this class
+    pub fn debug(&self) {
+        println!("  {:4} bytes: {} objects in {} slabs", self.size, self.in_use, self.slabs);
+    }
+}
+
+static CLASSES_POOL: [Mutex<SizeClass>; CLASSES] = [
+    Mutex::new(SizeClass::new(class_size(0)), "slab 16"),
+    Mutex::new(SizeClass::new(class_size(1)), "slab 32"),
+    Mutex::new(SizeClass::new(class_size(2)), "slab 64"),
+    Mutex::new(SizeClass::new(class_size(3)), "slab 128"),
+    Mutex::new(SizeClass::new(class_size(4)), "slab 256"),
+    Mutex::new(SizeClass::new(class_size(5)), "slab 512"),
+    Mutex::new(SizeClass::new(class_size(6)), "slab 1024"),
+];
+
+/// Free objects cached by a hart for a class
+#[derive(Clone, Copy)]
+struct Cache {
+    count: usize,
+    objects: [usize; CACHE_SIZE],
+}
+
+/// Caches of each hart, only accessed by its own hart with interrupt off
+static mut CACHES: [[Cache; CLASSES]; NCPUS] = [[Cache { count: 0, objects: [0; CACHE_SIZE] }; CLASSES]; NCPUS];
+
+/// Allocate an object of `class`
+pub fn alloc(class: usize) -> *mut u8 {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let cache = unsafe { &mut CACHES[hart_id()][class] };
+    if cache.count == 0 {
+        let mut pool = CLASSES_POOL[class].lock();
+        for object in cache.objects[..CACHE_SIZE / 2].iter_mut() {
+            *object = pool.alloc() as usize;
+        }
+        cache.count = CACHE_SIZE / 2;
+    }
+    cache.count -= 1;
+    cache.objects[cache.count] as *mut u8
+}
+
+/// Free `object` of `class`
+pub fn dealloc(object: *mut u8, class: usize) {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let cache = unsafe { &mut CACHES[hart_id()][class] };
+    if cache.count == CACHE_SIZE {
+        let mut pool = CLASSES_POOL[class].lock();
+        for object in cache.objects[CACHE_SIZE / 2..].iter() {
+            pool.dealloc(*object as *mut u8);
+        }
+        cache.count = CACHE_SIZE / 2;
+    }
+    cache.objects[cache.count] = object as usize;
+    cache.count += 1;
+}
+
+/// Print usage of all classes, counting objects in hart caches as used
+pub fn debug() {
+    for class in CLASSES_POOL.iter() {
+        class.lock().debug();
+    }
+}
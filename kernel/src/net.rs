@@ -0,0 +1,278 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Minimal TCP/IP stack
+//!
+//! One network interface is supported, configured with the defaults of
+//! QEMU user-mode networking. Packets to `127.0.0.0/8` or to the interface
+//! itself go through loopback, which works without network device. All
+//! protocol state is kept in `NET`, and sockets refer to their state by ID.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::spinlock::Mutex;
+
+mod ether;
+mod arp;
+mod ipv4;
+mod icmp;
+pub mod udp;
+pub mod tcp;
+mod socket;
+pub use socket::*;
+pub mod unix;
+pub use unix::{UnixSocket, AF_UNIX};
+
+/// IPv4 address
+pub type Ipv4Addr = [u8; 4];
+
+/// MAC address
+pub type MacAddr = [u8; 6];
+
+/// Address of QEMU user-mode network guest
+pub const DEFAULT_IP: Ipv4Addr = [10, 0, 2, 15];
+/// Netmask of QEMU user-mode network
+pub const DEFAULT_NETMASK: Ipv4Addr = [255, 255, 255, 0];
+/// Gateway of QEMU user-mode network, which is also the host
+pub const DEFAULT_GATEWAY: Ipv4Addr = [10, 0, 2, 2];
+
+/// Address of loopback interface
+pub const LOOPBACK_IP: Ipv4Addr = [127, 0, 0, 1];
+
+/// Whether `ip` is in `127.0.0.0/8`
+pub fn is_loopback(ip: Ipv4Addr) -> bool {
+    ip[0] == 127
+}
+
+/// Network device sending and receiving ethernet frames
+///
+/// Received frames are passed to `receive`.
+pub trait NetDevice: Send + Sync {
+    /// Hardware address
+    fn mac(&self) -> MacAddr;
+    /// Send a frame. Frame may be dropped if device is busy.
+    fn send(&self, frame: &[u8]);
+}
+
+/// Network interface
+pub struct Interface {
+    pub dev: Arc<dyn NetDevice>,
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+impl Interface {
+    /// Next hop of packets to `dst`
+    pub fn next_hop(&self, dst: Ipv4Addr) -> Ipv4Addr {
+        let same_subnet = (0..4).all(|i| dst[i] & self.netmask[i] == self.ip[i] & self.netmask[i]);
+        if same_subnet { dst } else { self.gateway }
+    }
+}
+
+/// State of network stack
+pub struct NetStack {
+    pub iface: Option<Interface>,
+    /// Packets sent through loopback, not yet received
+    loopback: VecDeque<Vec<u8>>,
+    /// Whether loopback packets are being received
+    loopback_busy: bool,
+    arp: arp::ArpTable,
+    ip_id: u16,
+    pub udp: udp::UdpTable,
+    pub tcp: tcp::TcpTable,
+    /// ID of next new socket
+    next_id: usize,
+    /// Next ephemeral port
+    next_port: u16,
+}
+
+impl NetStack {
+    pub const fn new() -> Self {
+        Self {
+            iface: None,
+            loopback: VecDeque::new(),
+            loopback_busy: false,
+            arp: arp::ArpTable::new(),
+            ip_id: 0,
+            udp: udp::UdpTable::new(),
+            tcp: tcp::TcpTable::new(),
+            next_id: 0,
+            next_port: EPHEMERAL_PORT_BEGIN,
+        }
+    }
+
+    /// Whether `ip` is an address of this host
+    pub fn is_local(&self, ip: Ipv4Addr) -> bool {
+        is_loopback(ip) || self.iface.as_ref().map_or(false, |iface| iface.ip == ip)
+    }
+
+    /// Source address of packets to `dst`, `None` if unreachable
+    pub fn source_for(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        if is_loopback(dst) {
+            Some(LOOPBACK_IP)
+        } else {
+            self.iface.as_ref().map(|iface| iface.ip)
+        }
+    }
+
+    /// Allocate ID for a new socket
+    pub fn alloc_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Allocate an ephemeral port not used by `in_use`
+    pub fn alloc_port<F: Fn(&Self, u16) -> bool>(&mut self, in_use: F) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX { EPHEMERAL_PORT_BEGIN } else { port + 1 };
+            if !in_use(self, port) {
+                return port;
+            }
+        }
+    }
+}
+
+/// First ephemeral port
+const EPHEMERAL_PORT_BEGIN: u16 = 49152;
+
+pub static NET: Mutex<NetStack> = Mutex::new(NetStack::new(), "net");
+
+/// Register network device as the interface
+pub fn register(dev: Arc<dyn NetDevice>) {
+    let mut net = NET.lock();
+    if net.iface.is_some() {
+        return;
+    }
+    net.iface = Some(Interface {
+        mac: dev.mac(),
+        dev,
+        ip: DEFAULT_IP,
+        netmask: DEFAULT_NETMASK,
+        gateway: DEFAULT_GATEWAY,
+    });
+}
+
+/// Whether a network interface is registered
+pub fn is_up() -> bool {
+    NET.lock().iface.is_some()
+}
+
+/// Process a frame received by network device
+pub fn receive(frame: &[u8]) {
+    let mut net = NET.lock();
+    ether::receive(&mut net, frame);
+}
+
+pub fn be16(data: &[u8], off: usize) -> u16 {
+    u16::from_be_bytes([data[off], data[off + 1]])
+}
+
+pub fn be32(data: &[u8], off: usize) -> u32 {
+    u32::from_be_bytes([data[off], data[off + 1], data[off + 2], data[off + 3]])
+}
+
+/// Add 16-bit big-endian words of `data` to `sum`
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// Fold `sum` into internet checksum
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of `data`
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("checksum", test_checksum),
+            ("sockaddr", test_sockaddr),
+            ("next hop", test_next_hop),
+            ("loopback", test_loopback),
+        ]
+    }
+
+    /// Test internet checksum with an IPv4 header
+    pub fn test_checksum() {
+        let mut hdr = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11,
+            0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&hdr), 0xb861);
+        hdr[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&hdr), 0);
+        assert_eq!(checksum(&[0x01]), !0x0100);
+    }
+
+    /// Test parsing socket address
+    pub fn test_sockaddr() {
+        let mut addr = [0; 16];
+        addr[0..2].copy_from_slice(&(AF_INET as u16).to_le_bytes());
+        addr[2..4].copy_from_slice(&7u16.to_be_bytes());
+        addr[4..8].copy_from_slice(&[10, 0, 2, 2]);
+        assert_eq!(parse_sockaddr_in(&addr), Some(([10, 0, 2, 2], 7)));
+        assert_eq!(parse_sockaddr_in(&addr[..4]), None);
+        addr[0] = 0;
+        assert_eq!(parse_sockaddr_in(&addr), None);
+    }
+
+    /// Test routing through gateway
+    pub fn test_next_hop() {
+        struct Dummy;
+        impl NetDevice for Dummy {
+            fn mac(&self) -> MacAddr { [0; 6] }
+            fn send(&self, _frame: &[u8]) {}
+        }
+        let iface = Interface {
+            dev: Arc::new(Dummy),
+            mac: [0; 6],
+            ip: DEFAULT_IP,
+            netmask: DEFAULT_NETMASK,
+            gateway: DEFAULT_GATEWAY,
+        };
+        assert_eq!(iface.next_hop([10, 0, 2, 3]), [10, 0, 2, 3]);
+        assert_eq!(iface.next_hop([1, 1, 1, 1]), DEFAULT_GATEWAY);
+    }
+
+    /// Test UDP through loopback
+    pub fn test_loopback() {
+        let a = Socket::new(AF_INET, SOCK_DGRAM).unwrap();
+        let b = Socket::new(AF_INET, SOCK_DGRAM).unwrap();
+        let addr = |port: u16| {
+            let mut addr = [0; 16];
+            addr[0..2].copy_from_slice(&(AF_INET as u16).to_le_bytes());
+            addr[2..4].copy_from_slice(&port.to_be_bytes());
+            addr[4..8].copy_from_slice(&LOOPBACK_IP);
+            addr
+        };
+        assert_eq!(a.bind(&addr(40000)), 0);
+        assert_eq!(b.bind(&addr(40001)), 0);
+        assert_eq!(a.connect(&addr(40001)), 0);
+        assert_eq!(a.send(b"ping"), 4);
+        let mut buf = [0; 8];
+        assert_eq!(b.recv(&mut buf), 4);
+        assert_eq!(&buf[..4], b"ping");
+    }
+}
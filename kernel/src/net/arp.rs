@@ -0,0 +1,98 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Address resolution protocol
+//!
+//! IP packets to an unresolved address wait in the table until a reply
+//! arrives.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use crate::net::{NetStack, Ipv4Addr, MacAddr, be16, ether};
+
+const ARP_HTYPE_ETHERNET: u16 = 1;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+const ARP_LEN: usize = 28;
+
+/// Maximum number of packets waiting for resolution
+const PENDING_MAX: usize = 16;
+
+pub struct ArpTable {
+    entries: BTreeMap<Ipv4Addr, MacAddr>,
+    /// IP packets waiting for address resolution of next hop
+    pending: Vec<(Ipv4Addr, Vec<u8>)>,
+}
+
+impl ArpTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Build an ARP packet
+fn build(op: u16, sha: MacAddr, spa: Ipv4Addr, tha: MacAddr, tpa: Ipv4Addr) -> [u8; ARP_LEN] {
+    let mut p = [0; ARP_LEN];
+    p[0..2].copy_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+    p[2..4].copy_from_slice(&ether::ETH_TYPE_IPV4.to_be_bytes());
+    p[4] = 6;
+    p[5] = 4;
+    p[6..8].copy_from_slice(&op.to_be_bytes());
+    p[8..14].copy_from_slice(&sha);
+    p[14..18].copy_from_slice(&spa);
+    p[18..24].copy_from_slice(&tha);
+    p[24..28].copy_from_slice(&tpa);
+    p
+}
+
+/// Process a received ARP packet
+pub fn receive(net: &mut NetStack, p: &[u8]) {
+    if p.len() < ARP_LEN || be16(p, 0) != ARP_HTYPE_ETHERNET || be16(p, 2) != ether::ETH_TYPE_IPV4 {
+        return;
+    }
+    let (mac, ip) = match net.iface.as_ref() {
+        Some(iface) => (iface.mac, iface.ip),
+        None => { return; }
+    };
+    let mut sha = [0; 6];
+    sha.copy_from_slice(&p[8..14]);
+    let mut spa = [0; 4];
+    spa.copy_from_slice(&p[14..18]);
+    let tpa = &p[24..28];
+
+    net.arp.entries.insert(spa, sha);
+    let (ready, waiting): (Vec<_>, Vec<_>) = core::mem::take(&mut net.arp.pending)
+        .into_iter()
+        .partition(|(hop, _)| *hop == spa);
+    net.arp.pending = waiting;
+    for (_, packet) in ready {
+        ether::send(net, sha, ether::ETH_TYPE_IPV4, &packet);
+    }
+
+    if be16(p, 6) == ARP_OP_REQUEST && tpa == ip {
+        let reply = build(ARP_OP_REPLY, mac, ip, sha, spa);
+        ether::send(net, sha, ether::ETH_TYPE_ARP, &reply);
+    }
+}
+
+/// Send IP `packet` to `next_hop`, resolving its address if needed
+pub fn send(net: &mut NetStack, next_hop: Ipv4Addr, packet: Vec<u8>) {
+    if let Some(&mac) = net.arp.entries.get(&next_hop) {
+        ether::send(net, mac, ether::ETH_TYPE_IPV4, &packet);
+        return;
+    }
+    let (mac, ip) = match net.iface.as_ref() {
+        Some(iface) => (iface.mac, iface.ip),
+        None => { return; }
+    };
+    if net.arp.pending.len() < PENDING_MAX {
+        net.arp.pending.push((next_hop, packet));
+    }
+    let request = build(ARP_OP_REQUEST, mac, ip, [0; 6], next_hop);
+    ether::send(net, ether::ETH_BROADCAST, ether::ETH_TYPE_ARP, &request);
+}
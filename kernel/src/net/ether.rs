@@ -0,0 +1,49 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Ethernet II frames
+
+use alloc::vec::Vec;
+use crate::net::{NetStack, MacAddr, be16, arp, ipv4};
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const ETH_TYPE_IPV4: u16 = 0x0800;
+pub const ETH_TYPE_ARP: u16 = 0x0806;
+pub const ETH_BROADCAST: MacAddr = [0xff; 6];
+
+/// Process a received frame
+pub fn receive(net: &mut NetStack, frame: &[u8]) {
+    let mac = match net.iface.as_ref() {
+        Some(iface) => iface.mac,
+        None => { return; }
+    };
+    if frame.len() < ETH_HDR_LEN {
+        return;
+    }
+    let dst = &frame[0..6];
+    if dst != mac && dst != ETH_BROADCAST {
+        return;
+    }
+    let payload = &frame[ETH_HDR_LEN..];
+    match be16(frame, 12) {
+        ETH_TYPE_ARP => arp::receive(net, payload),
+        ETH_TYPE_IPV4 => ipv4::receive(net, payload),
+        _ => {}
+    }
+}
+
+/// Send `payload` of `ethertype` to `dst`
+pub fn send(net: &NetStack, dst: MacAddr, ethertype: u16, payload: &[u8]) {
+    let iface = match net.iface.as_ref() {
+        Some(iface) => iface,
+        None => { return; }
+    };
+    let mut frame = Vec::with_capacity(ETH_HDR_LEN + payload.len());
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&iface.mac);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    iface.dev.send(&frame);
+}
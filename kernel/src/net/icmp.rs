@@ -0,0 +1,26 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Internet control message protocol, only echo is supported
+
+use alloc::vec::Vec;
+use crate::net::{NetStack, Ipv4Addr, checksum, ipv4};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_HDR_LEN: usize = 8;
+
+/// Process a received ICMP message, replying echo requests
+pub fn receive(net: &mut NetStack, src: Ipv4Addr, dst: Ipv4Addr, p: &[u8]) {
+    if p.len() < ICMP_HDR_LEN || checksum(p) != 0 || p[0] != ICMP_ECHO_REQUEST {
+        return;
+    }
+    let mut reply = Vec::from(p);
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let sum = checksum(&reply);
+    reply[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(net, dst, src, ipv4::IP_PROTO_ICMP, &reply);
+}
@@ -0,0 +1,102 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Internet protocol version 4
+//!
+//! Fragmented packets and options are not supported.
+
+use alloc::vec::Vec;
+use crate::net::{NetStack, Ipv4Addr, be16, checksum, arp, icmp, udp, tcp};
+
+pub const IP_HDR_LEN: usize = 20;
+pub const IP_PROTO_ICMP: u8 = 1;
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+
+const IP_TTL: u8 = 64;
+/// Flag of don't fragment
+const IP_FLAG_DF: u16 = 0x4000;
+/// Mask of more fragments flag and fragment offset
+const IP_FRAGMENT_MASK: u16 = 0x3fff;
+
+/// Process a received IP packet
+pub fn receive(net: &mut NetStack, p: &[u8]) {
+    if p.len() < IP_HDR_LEN || p[0] >> 4 != 4 {
+        return;
+    }
+    let hdr_len = (p[0] & 0xf) as usize * 4;
+    let total_len = be16(p, 2) as usize;
+    if hdr_len < IP_HDR_LEN || total_len < hdr_len || total_len > p.len() {
+        return;
+    }
+    if checksum(&p[..hdr_len]) != 0 || be16(p, 6) & IP_FRAGMENT_MASK != 0 {
+        return;
+    }
+    let mut src = [0; 4];
+    src.copy_from_slice(&p[12..16]);
+    let mut dst = [0; 4];
+    dst.copy_from_slice(&p[16..20]);
+    if !net.is_local(dst) {
+        return;
+    }
+    let payload = &p[hdr_len..total_len];
+    match p[9] {
+        IP_PROTO_ICMP => icmp::receive(net, src, dst, payload),
+        IP_PROTO_UDP => udp::receive(net, src, dst, payload),
+        IP_PROTO_TCP => tcp::receive(net, src, dst, payload),
+        _ => {}
+    }
+}
+
+/// Send `payload` of `proto` from `src` to `dst`
+pub fn send(net: &mut NetStack, src: Ipv4Addr, dst: Ipv4Addr, proto: u8, payload: &[u8]) {
+    net.ip_id = net.ip_id.wrapping_add(1);
+    let mut p = Vec::with_capacity(IP_HDR_LEN + payload.len());
+    p.push(0x45);
+    p.push(0);
+    p.extend_from_slice(&((IP_HDR_LEN + payload.len()) as u16).to_be_bytes());
+    p.extend_from_slice(&net.ip_id.to_be_bytes());
+    p.extend_from_slice(&IP_FLAG_DF.to_be_bytes());
+    p.push(IP_TTL);
+    p.push(proto);
+    p.extend_from_slice(&[0, 0]);
+    p.extend_from_slice(&src);
+    p.extend_from_slice(&dst);
+    let sum = checksum(&p);
+    p[10..12].copy_from_slice(&sum.to_be_bytes());
+    p.extend_from_slice(payload);
+    if net.is_local(dst) {
+        loopback(net, p);
+        return;
+    }
+    let next_hop = match net.iface.as_ref() {
+        Some(iface) => iface.next_hop(dst),
+        None => { return; }
+    };
+    arp::send(net, next_hop, p);
+}
+
+/// Receive packet `p` sent to this host.
+///
+/// Packets sent while receiving are queued, so that they are received
+/// in order without recursion.
+fn loopback(net: &mut NetStack, p: Vec<u8>) {
+    net.loopback.push_back(p);
+    if net.loopback_busy {
+        return;
+    }
+    net.loopback_busy = true;
+    while let Some(p) = net.loopback.pop_front() {
+        receive(net, &p);
+    }
+    net.loopback_busy = false;
+}
+
+/// Checksum of TCP and UDP pseudo header
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: usize) -> u32 {
+    let mut sum = crate::net::checksum_add(0, &src);
+    sum = crate::net::checksum_add(sum, &dst);
+    sum + proto as u32 + len as u32
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Sockets exposed to user as files
+//!
+//! Blocking operations sleep on socket state in `NET`, and are woken up
+//! when segments arrive, or when TCP gives up retransmitting.
+
+use alloc::boxed::Box;
+use crate::net::{NET, NetStack, Ipv4Addr, udp, tcp};
+use crate::process::sleep;
+use crate::spinlock::MutexGuard;
+
+/// Address family of IPv4
+pub const AF_INET: usize = 2;
+/// Socket type of reliable byte stream
+pub const SOCK_STREAM: usize = 1;
+/// Socket type of datagram
+pub const SOCK_DGRAM: usize = 2;
+
+/// Parse `sockaddr_in` of family (little-endian), port and address (big-endian)
+pub fn parse_sockaddr_in(addr: &[u8]) -> Option<(Ipv4Addr, u16)> {
+    if addr.len() < 8 || u16::from_le_bytes([addr[0], addr[1]]) as usize != AF_INET {
+        return None;
+    }
+    let mut ip = [0; 4];
+    ip.copy_from_slice(&addr[4..8]);
+    Some((ip, u16::from_be_bytes([addr[2], addr[3]])))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SocketKind {
+    Tcp,
+    Udp,
+}
+
+/// A socket, whose state is in `NET`
+pub struct Socket {
+    kind: SocketKind,
+    id: usize,
+}
+
+/// Sleep on TCP socket `id` until woken up
+fn sleep_tcp<'a>(net: MutexGuard<'a, NetStack>, id: usize) -> MutexGuard<'a, NetStack> {
+    let chan = &*net.tcp.sockets[&id] as *const tcp::TcpSocket;
+    sleep(chan, net)
+}
+
+impl Socket {
+    /// Create a socket of `domain` and `ty`. Returns `None` if not supported.
+    pub fn new(domain: usize, ty: usize) -> Option<Self> {
+        if domain != AF_INET {
+            return None;
+        }
+        let mut net = NET.lock();
+        match ty {
+            SOCK_STREAM => Some(Self { kind: SocketKind::Tcp, id: tcp::open(&mut net) }),
+            SOCK_DGRAM => {
+                let id = net.alloc_id();
+                net.udp.sockets.insert(id, Box::new(udp::UdpSocket {
+                    port: None,
+                    remote: None,
+                    rx: Default::default(),
+                }));
+                Some(Self { kind: SocketKind::Udp, id })
+            }
+            _ => None
+        }
+    }
+
+    /// Bind socket to local address `addr`
+    pub fn bind(&self, addr: &[u8]) -> i32 {
+        let (ip, port) = match parse_sockaddr_in(addr) {
+            Some(addr) => addr,
+            None => { return -1; }
+        };
+        let mut net = NET.lock();
+        match self.kind {
+            SocketKind::Tcp => tcp::bind(&mut net, self.id, ip, port),
+            SocketKind::Udp => {
+                if net.udp.sockets[&self.id].port.is_some() {
+                    return -1;
+                }
+                let port = match port {
+                    0 => net.alloc_port(|net, port| net.udp.port_in_use(port)),
+                    port if net.udp.port_in_use(port) => { return -1; }
+                    port => port
+                };
+                net.udp.sockets.get_mut(&self.id).unwrap().port = Some(port);
+                0
+            }
+        }
+    }
+
+    /// Listen for connections, at most `backlog` of them waiting for accept
+    pub fn listen(&self, backlog: usize) -> i32 {
+        if self.kind != SocketKind::Tcp {
+            return -1;
+        }
+        tcp::listen(&mut NET.lock(), self.id, backlog)
+    }
+
+    /// Wait for a connection on listening socket
+    pub fn accept(&self) -> Option<Socket> {
+        if self.kind != SocketKind::Tcp {
+            return None;
+        }
+        let mut net = NET.lock();
+        loop {
+            let listener = net.tcp.sockets.get_mut(&self.id).unwrap();
+            if listener.state != tcp::TcpState::Listen {
+                return None;
+            }
+            if let Some(id) = listener.backlog.pop_front() {
+                if let Some(sock) = net.tcp.sockets.get_mut(&id) {
+                    sock.parent = None;
+                    return Some(Socket { kind: SocketKind::Tcp, id });
+                }
+                continue;
+            }
+            net = sleep_tcp(net, self.id);
+        }
+    }
+
+    /// Connect to remote address `addr`. TCP sockets wait until connection
+    /// is established, and fail if it's refused or SYN is never answered,
+    /// while UDP sockets only filter incoming datagrams.
+    pub fn connect(&self, addr: &[u8]) -> i32 {
+        let remote = match parse_sockaddr_in(addr) {
+            Some(addr) => addr,
+            None => { return -1; }
+        };
+        let mut net = NET.lock();
+        match self.kind {
+            SocketKind::Tcp => {
+                if tcp::connect(&mut net, self.id, remote) != 0 {
+                    return -1;
+                }
+                loop {
+                    match net.tcp.sockets[&self.id].state {
+                        tcp::TcpState::SynSent => { net = sleep_tcp(net, self.id); }
+                        tcp::TcpState::Established => { return 0; }
+                        _ => { return -1; }
+                    }
+                }
+            }
+            SocketKind::Udp => {
+                if net.udp.sockets[&self.id].port.is_none() {
+                    let port = net.alloc_port(|net, port| net.udp.port_in_use(port));
+                    net.udp.sockets.get_mut(&self.id).unwrap().port = Some(port);
+                }
+                net.udp.sockets.get_mut(&self.id).unwrap().remote = Some(remote);
+                0
+            }
+        }
+    }
+
+    /// Send `data` to peer, returns number of bytes sent
+    pub fn send(&self, data: &[u8]) -> i32 {
+        let mut net = NET.lock();
+        match self.kind {
+            SocketKind::Tcp => {
+                let mut sent = 0;
+                while sent < data.len() {
+                    if !net.tcp.sockets[&self.id].can_send() {
+                        return if sent == 0 { -1 } else { sent as i32 };
+                    }
+                    match tcp::send(&mut net, self.id, &data[sent..]) {
+                        0 => { net = sleep_tcp(net, self.id); }
+                        n => { sent += n; }
+                    }
+                }
+                sent as i32
+            }
+            SocketKind::Udp => {
+                let sock = &net.udp.sockets[&self.id];
+                let (port, remote) = match (sock.port, sock.remote) {
+                    (Some(port), Some(remote)) => (port, remote),
+                    _ => { return -1; }
+                };
+                udp::send(&mut net, port, remote, data);
+                data.len() as i32
+            }
+        }
+    }
+
+    /// Receive data into `buf`, returns number of bytes received.
+    /// 0 means peer has closed the connection.
+    pub fn recv(&self, buf: &mut [u8]) -> i32 {
+        let mut net = NET.lock();
+        match self.kind {
+            SocketKind::Tcp => loop {
+                let sock = &net.tcp.sockets[&self.id];
+                if !sock.rx.is_empty() {
+                    return tcp::recv(&mut net, self.id, buf) as i32;
+                }
+                if sock.reset {
+                    return -1;
+                }
+                if !sock.can_recv() {
+                    return 0;
+                }
+                net = sleep_tcp(net, self.id);
+            }
+            SocketKind::Udp => loop {
+                let sock = net.udp.sockets.get_mut(&self.id).unwrap();
+                if let Some((_, _, data)) = sock.rx.pop_front() {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    return len as i32;
+                }
+                if sock.port.is_none() {
+                    return -1;
+                }
+                let chan = &**sock as *const udp::UdpSocket;
+                net = sleep(chan, net);
+            }
+        }
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let mut net = NET.lock();
+        match self.kind {
+            SocketKind::Tcp => tcp::close(&mut net, self.id),
+            SocketKind::Udp => { net.udp.sockets.remove(&self.id); }
+        }
+    }
+}
@@ -0,0 +1,814 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Transmission control protocol
+//!
+//! Implements connection setup and teardown, in-order receiving and sending
+//! within peer window. Out-of-order segments are dropped. Connections in
+//! `TimeWait` are closed immediately.
+//!
+//! While sent SYN, FIN or data in `unacked` is not acknowledged, a
+//! retransmission timer runs on the timer wheel. When it fires, the earliest
+//! unacknowledged segment is sent again, and the timeout doubles up to
+//! `RTO_MAX`. Connecting fails after `SYN_RETRIES` retransmissions, and
+//! other connections are aborted after `DATA_RETRIES` of them. Any new
+//! acknowledgement resets the timeout.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::net::{NET, NetStack, Ipv4Addr, be16, be32, checksum_add, checksum_finish, ipv4};
+use crate::process::wakeup;
+use crate::timer::{TimerId, add_timer, cancel_timer, deadline_after};
+
+const TCP_HDR_LEN: usize = 20;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+/// Option kind of maximum segment size
+const TCP_OPT_MSS: u8 = 2;
+
+/// Maximum segment size advertised to peer
+const MSS: usize = 1460;
+
+/// Maximum segment size if peer does not tell
+const DEFAULT_MSS: usize = 536;
+
+/// Size of receive buffer
+pub const RX_CAPACITY: usize = 16384;
+
+/// Retransmission timeout before backoff
+const RTO_INITIAL: Duration = Duration::from_secs(1);
+
+/// Maximum retransmission timeout
+const RTO_MAX: Duration = Duration::from_secs(60);
+
+/// Retransmissions of SYN before connecting fails
+const SYN_RETRIES: usize = 5;
+
+/// Retransmissions of a segment before connection is aborted
+const DATA_RETRIES: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// TCP socket
+pub struct TcpSocket {
+    pub state: TcpState,
+    /// Local address and port, port 0 if not bound
+    pub local: (Ipv4Addr, u16),
+    pub remote: (Ipv4Addr, u16),
+    /// Oldest unacknowledged sequence number
+    pub snd_una: u32,
+    /// Next sequence number to send
+    pub snd_nxt: u32,
+    /// Window of peer
+    pub snd_wnd: u32,
+    /// Next sequence number expected from peer
+    pub rcv_nxt: u32,
+    /// Maximum segment size to peer
+    pub mss: usize,
+    /// Sent data not acknowledged by peer
+    pub unacked: VecDeque<u8>,
+    /// Received data not read by user
+    pub rx: VecDeque<u8>,
+    /// Listening socket this connection comes from, until accepted
+    pub parent: Option<usize>,
+    /// Established connections not yet accepted, for listening socket
+    pub backlog: VecDeque<usize>,
+    /// Maximum number of connections not yet accepted
+    pub backlog_max: usize,
+    /// Whether user has closed this socket, it is freed when connection ends
+    pub orphan: bool,
+    /// Whether connection is reset by peer, or aborted as peer doesn't
+    /// acknowledge retransmissions
+    pub reset: bool,
+    /// Retransmission timeout, doubled on each retransmission
+    pub rto: Duration,
+    /// Retransmissions since peer acknowledged anything
+    pub retries: usize,
+    /// Retransmission timer, running while anything sent is unacknowledged
+    timer: Option<TimerId>,
+    /// Generation of retransmission timer, so that a stale timer which
+    /// has fired does nothing
+    timer_gen: u64,
+}
+
+impl TcpSocket {
+    pub const fn new() -> Self {
+        Self {
+            state: TcpState::Closed,
+            local: ([0; 4], 0),
+            remote: ([0; 4], 0),
+            snd_una: 0,
+            snd_nxt: 0,
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            mss: DEFAULT_MSS,
+            unacked: VecDeque::new(),
+            rx: VecDeque::new(),
+            parent: None,
+            backlog: VecDeque::new(),
+            backlog_max: 0,
+            orphan: false,
+            reset: false,
+            rto: RTO_INITIAL,
+            retries: 0,
+            timer: None,
+            timer_gen: 0,
+        }
+    }
+
+    /// Whether peer may still send data
+    pub fn can_recv(&self) -> bool {
+        use TcpState::*;
+        matches!(self.state, SynSent | SynReceived | Established | FinWait1 | FinWait2)
+    }
+
+    /// Whether user may still send data
+    pub fn can_send(&self) -> bool {
+        matches!(self.state, TcpState::Established | TcpState::CloseWait)
+    }
+
+    /// Window advertised to peer
+    fn rcv_wnd(&self) -> u16 {
+        (RX_CAPACITY - self.rx.len()).min(u16::MAX as usize) as u16
+    }
+
+    /// (Re)start retransmission timer of this socket `id` after `rto`
+    fn start_timer(&mut self, id: usize) {
+        self.stop_timer();
+        let gen = self.timer_gen;
+        self.timer = Some(add_timer(deadline_after(self.rto), Box::new(move || timeout(id, gen))));
+    }
+
+    /// Stop retransmission timer
+    fn stop_timer(&mut self) {
+        self.timer_gen += 1;
+        if let Some(timer) = self.timer.take() {
+            cancel_timer(timer);
+        }
+    }
+
+    /// Take acknowledgement of sequence numbers before `ack`, and restart
+    /// retransmission timer of this socket `id` if anything is still
+    /// unacknowledged
+    fn acknowledge(&mut self, id: usize, ack: u32) {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        let data = acked.min(self.unacked.len());
+        self.unacked.drain(..data);
+        self.snd_una = ack;
+        self.rto = RTO_INITIAL;
+        self.retries = 0;
+        if self.snd_una == self.snd_nxt {
+            self.stop_timer();
+        } else {
+            self.start_timer(id);
+        }
+    }
+
+    /// Connection ends without closing handshake
+    fn abort(&mut self) {
+        self.state = TcpState::Closed;
+        self.reset = true;
+        self.stop_timer();
+    }
+}
+
+impl Drop for TcpSocket {
+    fn drop(&mut self) {
+        self.stop_timer();
+    }
+}
+
+pub struct TcpTable {
+    pub sockets: BTreeMap<usize, Box<TcpSocket>>,
+    /// Initial sequence number of next connection
+    isn: u32,
+}
+
+impl TcpTable {
+    pub const fn new() -> Self {
+        Self { sockets: BTreeMap::new(), isn: 0x1000 }
+    }
+
+    /// Whether local `port` is used by any socket
+    pub fn port_in_use(&self, port: u16) -> bool {
+        self.sockets.values().any(|s| s.local.1 == port)
+    }
+
+    fn next_isn(&mut self) -> u32 {
+        self.isn = self.isn.wrapping_add(64000);
+        self.isn
+    }
+}
+
+/// `a < b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// `a <= b` in sequence space
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+/// A received segment
+struct Segment<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<usize>,
+    payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Length in sequence space
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 + self.has(TCP_SYN) as u32 + self.has(TCP_FIN) as u32
+    }
+}
+
+/// Parse segment `p`, returns `None` if it is malformed
+fn parse(p: &[u8]) -> Option<Segment> {
+    if p.len() < TCP_HDR_LEN {
+        return None;
+    }
+    let hdr_len = (p[12] >> 4) as usize * 4;
+    if hdr_len < TCP_HDR_LEN || hdr_len > p.len() {
+        return None;
+    }
+    let mut mss = None;
+    let mut opts = &p[TCP_HDR_LEN..hdr_len];
+    while let Some(&kind) = opts.first() {
+        match kind {
+            0 => { break; }
+            1 => { opts = &opts[1..]; }
+            _ => {
+                if opts.len() < 2 || (opts[1] as usize) < 2 || opts.len() < opts[1] as usize {
+                    break;
+                }
+                if kind == TCP_OPT_MSS && opts[1] == 4 {
+                    mss = Some(be16(opts, 2) as usize);
+                }
+                opts = &opts[opts[1] as usize..];
+            }
+        }
+    }
+    Some(Segment {
+        src_port: be16(p, 0),
+        dst_port: be16(p, 2),
+        seq: be32(p, 4),
+        ack: be32(p, 8),
+        flags: p[13],
+        window: be16(p, 14),
+        mss,
+        payload: &p[hdr_len..],
+    })
+}
+
+/// Checksum of TCP segment `p`
+fn tcp_checksum(src: Ipv4Addr, dst: Ipv4Addr, p: &[u8]) -> u16 {
+    let sum = ipv4::pseudo_header_sum(src, dst, ipv4::IP_PROTO_TCP, p.len());
+    checksum_finish(checksum_add(sum, p))
+}
+
+/// Build and send a segment
+fn send_raw(net: &mut NetStack, local: (Ipv4Addr, u16), remote: (Ipv4Addr, u16),
+            seq: u32, ack: u32, flags: u8, window: u16, data: &[u8]) {
+    let opt_len = if flags & TCP_SYN != 0 { 4 } else { 0 };
+    let mut p = Vec::with_capacity(TCP_HDR_LEN + opt_len + data.len());
+    p.extend_from_slice(&local.1.to_be_bytes());
+    p.extend_from_slice(&remote.1.to_be_bytes());
+    p.extend_from_slice(&seq.to_be_bytes());
+    p.extend_from_slice(&ack.to_be_bytes());
+    p.push((((TCP_HDR_LEN + opt_len) / 4) << 4) as u8);
+    p.push(flags);
+    p.extend_from_slice(&window.to_be_bytes());
+    p.extend_from_slice(&[0, 0, 0, 0]);
+    if opt_len != 0 {
+        p.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        p.extend_from_slice(&(MSS as u16).to_be_bytes());
+    }
+    p.extend_from_slice(data);
+    let sum = tcp_checksum(local.0, remote.0, &p);
+    p[16..18].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(net, local.0, remote.0, ipv4::IP_PROTO_TCP, &p);
+}
+
+/// Send a segment of socket `id` with `flags` and `data` at `snd_nxt`,
+/// and advance `snd_nxt`
+fn output(net: &mut NetStack, id: usize, flags: u8, data: &[u8]) {
+    let sock = match net.tcp.sockets.get_mut(&id) {
+        Some(sock) => sock,
+        None => { return; }
+    };
+    let seq = sock.snd_nxt;
+    let ack = if flags & TCP_ACK != 0 { sock.rcv_nxt } else { 0 };
+    let (local, remote, window) = (sock.local, sock.remote, sock.rcv_wnd());
+    let len = data.len() as u32 + (flags & TCP_SYN != 0) as u32 + (flags & TCP_FIN != 0) as u32;
+    sock.snd_nxt = sock.snd_nxt.wrapping_add(len);
+    if len != 0 && sock.timer.is_none() {
+        sock.start_timer(id);
+    }
+    send_raw(net, local, remote, seq, ack, flags, window, data);
+}
+
+/// Retransmission timer of socket `id` fires, in timer interrupt
+fn timeout(id: usize, gen: u64) {
+    let mut net = NET.lock();
+    retransmit(&mut net, id, gen);
+    release_if_done(&mut net, id);
+}
+
+/// Send earliest unacknowledged segment of socket `id` again with timer of
+/// generation `gen` fired, or end the connection after too many retries
+fn retransmit(net: &mut NetStack, id: usize, gen: u64) {
+    let sock = match net.tcp.sockets.get_mut(&id) {
+        Some(sock) if sock.timer_gen == gen && sock.timer.is_some() => sock,
+        _ => { return; }
+    };
+    sock.timer = None;
+    if sock.snd_una == sock.snd_nxt || sock.state == TcpState::Closed {
+        return;
+    }
+    let max_retries = match sock.state {
+        TcpState::SynSent | TcpState::SynReceived => SYN_RETRIES,
+        _ => DATA_RETRIES
+    };
+    if sock.retries >= max_retries {
+        if sock.state == TcpState::SynReceived {
+            // not yet accepted, nobody is waiting for it
+            net.tcp.sockets.remove(&id);
+            return;
+        }
+        sock.abort();
+        wake(net, id);
+        return;
+    }
+    sock.retries += 1;
+    sock.rto = (sock.rto * 2).min(RTO_MAX);
+    let (flags, len) = match sock.state {
+        TcpState::SynSent => (TCP_SYN, 0),
+        TcpState::SynReceived => (TCP_SYN | TCP_ACK, 0),
+        // only FIN is left
+        _ if sock.unacked.is_empty() => (TCP_FIN | TCP_ACK, 0),
+        _ => (TCP_ACK | TCP_PSH, sock.unacked.len().min(sock.mss)),
+    };
+    let data: Vec<u8> = sock.unacked.iter().take(len).copied().collect();
+    let ack = if flags & TCP_ACK != 0 { sock.rcv_nxt } else { 0 };
+    let (seq, local, remote, window) = (sock.snd_una, sock.local, sock.remote, sock.rcv_wnd());
+    sock.start_timer(id);
+    send_raw(net, local, remote, seq, ack, flags, window, &data);
+}
+
+/// Reply reset to `seg` from `src` to `dst` which belongs to no connection
+fn reset(net: &mut NetStack, src: Ipv4Addr, dst: Ipv4Addr, seg: &Segment) {
+    let (local, remote) = ((dst, seg.dst_port), (src, seg.src_port));
+    if seg.has(TCP_ACK) {
+        send_raw(net, local, remote, seg.ack, 0, TCP_RST, 0, &[]);
+    } else {
+        send_raw(net, local, remote, 0, seg.seq.wrapping_add(seg.len()), TCP_RST | TCP_ACK, 0, &[]);
+    }
+}
+
+/// Wake up processes waiting on socket `id`
+fn wake(net: &NetStack, id: usize) {
+    if let Some(sock) = net.tcp.sockets.get(&id) {
+        wakeup(&**sock as *const TcpSocket);
+    }
+}
+
+/// Free socket `id` if user has closed it and connection is over
+fn release_if_done(net: &mut NetStack, id: usize) {
+    let done = match net.tcp.sockets.get_mut(&id) {
+        Some(sock) => {
+            if sock.state == TcpState::TimeWait {
+                sock.state = TcpState::Closed;
+            }
+            sock.orphan && sock.state == TcpState::Closed
+        }
+        None => false
+    };
+    if done {
+        net.tcp.sockets.remove(&id);
+    }
+}
+
+/// Process a received TCP segment
+pub fn receive(net: &mut NetStack, src: Ipv4Addr, dst: Ipv4Addr, p: &[u8]) {
+    if tcp_checksum(src, dst, p) != 0 {
+        return;
+    }
+    let seg = match parse(p) {
+        Some(seg) => seg,
+        None => { return; }
+    };
+    let remote = (src, seg.src_port);
+    let id = net.tcp.sockets.iter()
+        .find(|(_, s)| s.state != TcpState::Listen && s.state != TcpState::Closed
+            && s.local.1 == seg.dst_port && s.remote == remote)
+        .or_else(|| net.tcp.sockets.iter()
+            .find(|(_, s)| s.state == TcpState::Listen && s.local.1 == seg.dst_port))
+        .map(|(&id, _)| id);
+    let id = match id {
+        Some(id) => id,
+        None => {
+            if !seg.has(TCP_RST) {
+                reset(net, src, dst, &seg);
+            }
+            return;
+        }
+    };
+    match net.tcp.sockets[&id].state {
+        TcpState::Listen => receive_listen(net, id, src, dst, &seg),
+        TcpState::SynSent => receive_syn_sent(net, id, src, dst, &seg),
+        _ => receive_synchronized(net, id, src, dst, &seg),
+    }
+    release_if_done(net, id);
+}
+
+/// Process segment to listening socket `id`
+fn receive_listen(net: &mut NetStack, id: usize, src: Ipv4Addr, dst: Ipv4Addr, seg: &Segment) {
+    if seg.has(TCP_RST) {
+        return;
+    }
+    if seg.has(TCP_ACK) {
+        reset(net, src, dst, seg);
+        return;
+    }
+    if !seg.has(TCP_SYN) {
+        return;
+    }
+    let pending = net.tcp.sockets.values().filter(|s| s.parent == Some(id)).count();
+    if pending >= net.tcp.sockets[&id].backlog_max {
+        return;
+    }
+    let child_id = net.alloc_id();
+    let iss = net.tcp.next_isn();
+    let mut child = TcpSocket::new();
+    child.state = TcpState::SynReceived;
+    child.local = (dst, seg.dst_port);
+    child.remote = (src, seg.src_port);
+    child.snd_una = iss;
+    child.snd_nxt = iss;
+    child.snd_wnd = seg.window as u32;
+    child.rcv_nxt = seg.seq.wrapping_add(1);
+    child.mss = seg.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+    child.parent = Some(id);
+    net.tcp.sockets.insert(child_id, Box::new(child));
+    output(net, child_id, TCP_SYN | TCP_ACK, &[]);
+}
+
+/// Process segment to socket `id` connecting to peer
+fn receive_syn_sent(net: &mut NetStack, id: usize, src: Ipv4Addr, dst: Ipv4Addr, seg: &Segment) {
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    if seg.has(TCP_ACK) && seg.ack != sock.snd_nxt {
+        if !seg.has(TCP_RST) {
+            reset(net, src, dst, seg);
+        }
+        return;
+    }
+    if seg.has(TCP_RST) {
+        if seg.has(TCP_ACK) {
+            sock.abort();
+            wake(net, id);
+        }
+        return;
+    }
+    if !seg.has(TCP_SYN) || !seg.has(TCP_ACK) {
+        return;
+    }
+    sock.rcv_nxt = seg.seq.wrapping_add(1);
+    sock.acknowledge(id, seg.ack);
+    sock.snd_wnd = seg.window as u32;
+    sock.mss = seg.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+    sock.state = TcpState::Established;
+    output(net, id, TCP_ACK, &[]);
+    wake(net, id);
+}
+
+/// Process segment to socket `id` in synchronized states
+fn receive_synchronized(net: &mut NetStack, id: usize, src: Ipv4Addr, dst: Ipv4Addr, seg: &Segment) {
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+
+    if seg.has(TCP_RST) {
+        if seg.seq == sock.rcv_nxt {
+            if sock.state == TcpState::SynReceived {
+                net.tcp.sockets.remove(&id);
+                return;
+            }
+            sock.abort();
+            wake(net, id);
+        }
+        return;
+    }
+    if seg.seq != sock.rcv_nxt {
+        // out of order or duplicate, tell peer what we expect
+        if seg.len() != 0 {
+            output(net, id, TCP_ACK, &[]);
+        }
+        return;
+    }
+    if seg.has(TCP_SYN) || !seg.has(TCP_ACK) {
+        return;
+    }
+
+    if sock.state == TcpState::SynReceived {
+        if seg.ack != sock.snd_nxt {
+            reset(net, src, dst, seg);
+            return;
+        }
+        sock.state = TcpState::Established;
+        if let Some(parent) = sock.parent {
+            if let Some(listener) = net.tcp.sockets.get_mut(&parent) {
+                listener.backlog.push_back(id);
+            }
+            wake(net, parent);
+        }
+    }
+
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    if seq_lt(sock.snd_una, seg.ack) && seq_le(seg.ack, sock.snd_nxt) {
+        sock.acknowledge(id, seg.ack);
+    }
+    sock.snd_wnd = seg.window as u32;
+    let all_acked = sock.snd_una == sock.snd_nxt;
+    match sock.state {
+        TcpState::FinWait1 if all_acked => { sock.state = TcpState::FinWait2; }
+        TcpState::Closing if all_acked => { sock.state = TcpState::TimeWait; }
+        TcpState::LastAck if all_acked => { sock.state = TcpState::Closed; }
+        _ => {}
+    }
+
+    let mut need_ack = false;
+    let mut accepted = 0;
+    if !seg.payload.is_empty() && matches!(sock.state, TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2) {
+        accepted = seg.payload.len().min(RX_CAPACITY - sock.rx.len());
+        sock.rx.extend(&seg.payload[..accepted]);
+        sock.rcv_nxt = sock.rcv_nxt.wrapping_add(accepted as u32);
+        need_ack = true;
+    }
+    if seg.has(TCP_FIN) && accepted == seg.payload.len() {
+        sock.rcv_nxt = sock.rcv_nxt.wrapping_add(1);
+        need_ack = true;
+        let all_acked = sock.snd_una == sock.snd_nxt;
+        sock.state = match sock.state {
+            TcpState::Established => TcpState::CloseWait,
+            TcpState::FinWait1 if all_acked => TcpState::TimeWait,
+            TcpState::FinWait1 => TcpState::Closing,
+            TcpState::FinWait2 => TcpState::TimeWait,
+            state => state
+        };
+    }
+    if need_ack {
+        output(net, id, TCP_ACK, &[]);
+    }
+    wake(net, id);
+}
+
+/// Create a closed socket, returns its ID
+pub fn open(net: &mut NetStack) -> usize {
+    let id = net.alloc_id();
+    net.tcp.sockets.insert(id, Box::new(TcpSocket::new()));
+    id
+}
+
+/// Bind socket `id` to `port`, or an ephemeral port if `port` is 0
+pub fn bind(net: &mut NetStack, id: usize, addr: Ipv4Addr, port: u16) -> i32 {
+    if net.tcp.sockets[&id].local.1 != 0 {
+        return -1;
+    }
+    let port = match port {
+        0 => net.alloc_port(|net, port| net.tcp.port_in_use(port)),
+        port if net.tcp.port_in_use(port) => { return -1; }
+        port => port
+    };
+    net.tcp.sockets.get_mut(&id).unwrap().local = (addr, port);
+    0
+}
+
+/// Make socket `id` listen for connections
+pub fn listen(net: &mut NetStack, id: usize, backlog: usize) -> i32 {
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    if sock.local.1 == 0 || !matches!(sock.state, TcpState::Closed | TcpState::Listen) || sock.reset {
+        return -1;
+    }
+    sock.state = TcpState::Listen;
+    sock.backlog_max = backlog.max(1);
+    0
+}
+
+/// Begin connecting socket `id` to `remote`
+pub fn connect(net: &mut NetStack, id: usize, remote: (Ipv4Addr, u16)) -> i32 {
+    let ip = match net.source_for(remote.0) {
+        Some(ip) => ip,
+        None => { return -1; }
+    };
+    if net.tcp.sockets[&id].state != TcpState::Closed {
+        return -1;
+    }
+    if net.tcp.sockets[&id].local.1 == 0 && bind(net, id, ip, 0) != 0 {
+        return -1;
+    }
+    let iss = net.tcp.next_isn();
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    sock.local.0 = ip;
+    sock.remote = remote;
+    sock.snd_una = iss;
+    sock.snd_nxt = iss;
+    sock.state = TcpState::SynSent;
+    output(net, id, TCP_SYN, &[]);
+    0
+}
+
+/// Send as much of `data` as peer window allows, returns number of
+/// bytes sent
+pub fn send(net: &mut NetStack, id: usize, data: &[u8]) -> usize {
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    let inflight = sock.snd_nxt.wrapping_sub(sock.snd_una);
+    let window = sock.snd_wnd.saturating_sub(inflight) as usize;
+    let len = window.min(data.len());
+    let mss = sock.mss;
+    sock.unacked.extend(&data[..len]);
+    for chunk in data[..len].chunks(mss) {
+        output(net, id, TCP_ACK | TCP_PSH, chunk);
+    }
+    len
+}
+
+/// Read received data into `buf`, returns number of bytes read
+pub fn recv(net: &mut NetStack, id: usize, buf: &mut [u8]) -> usize {
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    let old_wnd = sock.rcv_wnd() as usize;
+    let len = buf.len().min(sock.rx.len());
+    for (dst, src) in buf.iter_mut().zip(sock.rx.drain(..len)) {
+        *dst = src;
+    }
+    // tell peer window is open again
+    if old_wnd < sock.mss && sock.rcv_wnd() as usize >= sock.mss && sock.can_recv() {
+        output(net, id, TCP_ACK, &[]);
+    }
+    len
+}
+
+/// Close socket `id` by user, it is freed when connection ends
+pub fn close(net: &mut NetStack, id: usize) {
+    let sock = net.tcp.sockets.get_mut(&id).unwrap();
+    sock.orphan = true;
+    match sock.state {
+        TcpState::Listen => {
+            let children: Vec<usize> = net.tcp.sockets.iter()
+                .filter(|(_, s)| s.parent == Some(id))
+                .map(|(&child, _)| child)
+                .collect();
+            for child in children {
+                output(net, child, TCP_RST | TCP_ACK, &[]);
+                net.tcp.sockets.remove(&child);
+            }
+            net.tcp.sockets.remove(&id);
+        }
+        TcpState::Closed | TcpState::SynSent => {
+            net.tcp.sockets.remove(&id);
+        }
+        TcpState::SynReceived | TcpState::Established => {
+            sock.state = TcpState::FinWait1;
+            output(net, id, TCP_FIN | TCP_ACK, &[]);
+        }
+        TcpState::CloseWait => {
+            sock.state = TcpState::LastAck;
+            output(net, id, TCP_FIN | TCP_ACK, &[]);
+        }
+        _ => {}
+    }
+    release_if_done(net, id);
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("sequence", test_seq),
+            ("parse", test_parse),
+            ("retransmit syn", test_retransmit_syn),
+            ("retransmit data", test_retransmit_data),
+        ]
+    }
+
+    /// Socket `id` in `state` on a stack without interface, where
+    /// segments are dropped
+    fn unreachable_socket(net: &mut NetStack, state: TcpState) -> usize {
+        let id = open(net);
+        let sock = net.tcp.sockets.get_mut(&id).unwrap();
+        sock.local = ([10, 0, 2, 15], 40000);
+        sock.remote = ([10, 0, 2, 2], 7);
+        sock.state = state;
+        id
+    }
+
+    /// Test retransmitting SYN with backoff until connecting fails
+    pub fn test_retransmit_syn() {
+        let mut net = NetStack::new();
+        let id = unreachable_socket(&mut net, TcpState::SynSent);
+        output(&mut net, id, TCP_SYN, &[]);
+        let mut rto = RTO_INITIAL;
+        for retry in 1..=SYN_RETRIES {
+            let gen = net.tcp.sockets[&id].timer_gen;
+            // stale timer does nothing
+            retransmit(&mut net, id, gen.wrapping_sub(1));
+            assert_eq!(net.tcp.sockets[&id].retries, retry - 1);
+            retransmit(&mut net, id, gen);
+            rto = (rto * 2).min(RTO_MAX);
+            let sock = &net.tcp.sockets[&id];
+            assert_eq!(sock.retries, retry);
+            assert_eq!(sock.rto, rto);
+            assert_eq!(sock.state, TcpState::SynSent);
+        }
+        let gen = net.tcp.sockets[&id].timer_gen;
+        retransmit(&mut net, id, gen);
+        let sock = &net.tcp.sockets[&id];
+        assert_eq!(sock.state, TcpState::Closed);
+        assert!(sock.reset && sock.timer.is_none());
+    }
+
+    /// Test retransmitting data, and acknowledgement resetting timeout
+    pub fn test_retransmit_data() {
+        let mut net = NetStack::new();
+        let id = unreachable_socket(&mut net, TcpState::Established);
+        let sock = net.tcp.sockets.get_mut(&id).unwrap();
+        sock.snd_wnd = 65535;
+        sock.mss = 4;
+        assert_eq!(send(&mut net, id, b"hello"), 5);
+        let gen = net.tcp.sockets[&id].timer_gen;
+        retransmit(&mut net, id, gen);
+        let sock = net.tcp.sockets.get_mut(&id).unwrap();
+        assert_eq!(sock.retries, 1);
+        assert!(sock.timer.is_some());
+        let ack = sock.snd_una.wrapping_add(4);
+        sock.acknowledge(id, ack);
+        assert_eq!(sock.unacked.len(), 1);
+        assert_eq!(sock.retries, 0);
+        assert_eq!(sock.rto, RTO_INITIAL);
+        assert!(sock.timer.is_some());
+        let ack = sock.snd_nxt;
+        sock.acknowledge(id, ack);
+        assert!(sock.unacked.is_empty() && sock.timer.is_none());
+    }
+
+    /// Test comparing sequence numbers across wrap-around
+    pub fn test_seq() {
+        assert!(seq_lt(1, 2));
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(!seq_lt(0, u32::MAX));
+        assert!(seq_le(5, 5));
+    }
+
+    /// Test parsing segment with MSS option
+    pub fn test_parse() {
+        let mut p = [0; 24];
+        p[0..2].copy_from_slice(&1234u16.to_be_bytes());
+        p[2..4].copy_from_slice(&7u16.to_be_bytes());
+        p[4..8].copy_from_slice(&100u32.to_be_bytes());
+        p[12] = 6 << 4;
+        p[13] = TCP_SYN;
+        p[14..16].copy_from_slice(&8192u16.to_be_bytes());
+        p[20..24].copy_from_slice(&[TCP_OPT_MSS, 4, 0x05, 0xb4]);
+        let seg = parse(&p).unwrap();
+        assert_eq!(seg.src_port, 1234);
+        assert_eq!(seg.dst_port, 7);
+        assert_eq!(seg.seq, 100);
+        assert_eq!(seg.window, 8192);
+        assert_eq!(seg.mss, Some(1460));
+        assert_eq!(seg.len(), 1);
+        assert!(seg.payload.is_empty());
+        p[12] = 7 << 4;
+        assert!(parse(&p).is_none());
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! User datagram protocol
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use crate::net::{NetStack, Ipv4Addr, be16, checksum_add, checksum_finish, ipv4};
+use crate::process::wakeup;
+
+const UDP_HDR_LEN: usize = 8;
+
+/// Maximum number of datagrams queued in a socket
+const RX_MAX: usize = 64;
+
+/// UDP socket
+pub struct UdpSocket {
+    /// Bound local port
+    pub port: Option<u16>,
+    /// Peer set by `connect`, datagrams from others are dropped
+    pub remote: Option<(Ipv4Addr, u16)>,
+    /// Received datagrams of (source address, source port, payload)
+    pub rx: VecDeque<(Ipv4Addr, u16, Vec<u8>)>,
+}
+
+pub struct UdpTable {
+    pub sockets: BTreeMap<usize, Box<UdpSocket>>,
+}
+
+impl UdpTable {
+    pub const fn new() -> Self {
+        Self { sockets: BTreeMap::new() }
+    }
+
+    /// Whether `port` is bound by any socket
+    pub fn port_in_use(&self, port: u16) -> bool {
+        self.sockets.values().any(|s| s.port == Some(port))
+    }
+}
+
+/// Checksum of UDP datagram `p`
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, p: &[u8]) -> u16 {
+    let sum = ipv4::pseudo_header_sum(src, dst, ipv4::IP_PROTO_UDP, p.len());
+    checksum_finish(checksum_add(sum, p))
+}
+
+/// Process a received UDP datagram
+pub fn receive(net: &mut NetStack, src: Ipv4Addr, dst: Ipv4Addr, p: &[u8]) {
+    if p.len() < UDP_HDR_LEN {
+        return;
+    }
+    let len = be16(p, 4) as usize;
+    if len < UDP_HDR_LEN || len > p.len() {
+        return;
+    }
+    let p = &p[..len];
+    // zero checksum means checksum is not computed by sender
+    if be16(p, 6) != 0 && udp_checksum(src, dst, p) != 0 {
+        return;
+    }
+    let src_port = be16(p, 0);
+    let dst_port = be16(p, 2);
+    let sock = net.udp.sockets.values_mut().find(|s| {
+        s.port == Some(dst_port) && s.remote.map_or(true, |r| r == (src, src_port))
+    });
+    if let Some(sock) = sock {
+        if sock.rx.len() < RX_MAX {
+            sock.rx.push_back((src, src_port, Vec::from(&p[UDP_HDR_LEN..])));
+            wakeup(&**sock as *const UdpSocket);
+        }
+    }
+}
+
+/// Send `data` from `port` to `dst`
+pub fn send(net: &mut NetStack, port: u16, dst: (Ipv4Addr, u16), data: &[u8]) {
+    let ip = match net.source_for(dst.0) {
+        Some(ip) => ip,
+        None => { return; }
+    };
+    let len = UDP_HDR_LEN + data.len();
+    let mut p = Vec::with_capacity(len);
+    p.extend_from_slice(&port.to_be_bytes());
+    p.extend_from_slice(&dst.1.to_be_bytes());
+    p.extend_from_slice(&(len as u16).to_be_bytes());
+    p.extend_from_slice(&[0, 0]);
+    p.extend_from_slice(data);
+    let sum = match udp_checksum(ip, dst.0, &p) {
+        0 => 0xffff,
+        sum => sum
+    };
+    p[6..8].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(net, ip, dst.0, ipv4::IP_PROTO_UDP, &p);
+}
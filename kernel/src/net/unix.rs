@@ -0,0 +1,376 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Unix-domain sockets
+//!
+//! Binding a socket creates an empty file at its path, and the socket is
+//! found by that file afterwards, so it can't be reached once the file is
+//! removed. Stream connections are pairs of sockets sending into each
+//! other's receive buffer.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::fs::{self, Inode};
+use crate::net::{SOCK_STREAM, SOCK_DGRAM};
+use crate::process::{sleep, wakeup};
+use crate::spinlock::{Mutex, MutexGuard};
+
+/// Address family of Unix-domain sockets
+pub const AF_UNIX: usize = 1;
+
+/// Maximum length of path in `sockaddr_un`
+const UNIX_PATH_MAX: usize = 108;
+
+/// Size of receive buffer of stream sockets
+const STREAM_CAPACITY: usize = 16384;
+
+/// Maximum number of datagrams queued in a socket
+const DGRAM_MAX: usize = 64;
+
+/// Parse `sockaddr_un` of family (little-endian) and NUL-terminated path
+pub fn parse_sockaddr_un(addr: &[u8]) -> Option<&str> {
+    if addr.len() < 3 || u16::from_le_bytes([addr[0], addr[1]]) as usize != AF_UNIX {
+        return None;
+    }
+    let path = &addr[2..addr.len().min(2 + UNIX_PATH_MAX)];
+    let len = path.iter().position(|&c| c == 0).unwrap_or(path.len());
+    core::str::from_utf8(&path[..len]).ok().filter(|path| !path.is_empty())
+}
+
+/// State of a Unix-domain socket
+struct UnixSocketState {
+    ty: usize,
+    /// File created by `bind`
+    inode: Option<Arc<dyn Inode>>,
+    listening: bool,
+    /// Connections not yet accepted, for listening socket
+    backlog: VecDeque<usize>,
+    backlog_max: usize,
+    /// Connected peer. For datagram sockets, the file peer is bound to.
+    peer: Option<usize>,
+    peer_inode: Option<Arc<dyn Inode>>,
+    /// Whether stream peer has closed
+    peer_closed: bool,
+    /// Received bytes of stream socket
+    rx: VecDeque<u8>,
+    /// Received datagrams
+    dgrams: VecDeque<Vec<u8>>,
+}
+
+impl UnixSocketState {
+    fn new(ty: usize) -> Self {
+        Self {
+            ty,
+            inode: None,
+            listening: false,
+            backlog: VecDeque::new(),
+            backlog_max: 0,
+            peer: None,
+            peer_inode: None,
+            peer_closed: false,
+            rx: VecDeque::new(),
+            dgrams: VecDeque::new(),
+        }
+    }
+}
+
+struct UnixTable {
+    sockets: BTreeMap<usize, Box<UnixSocketState>>,
+    /// Bound sockets by address of their file
+    names: BTreeMap<usize, usize>,
+    next_id: usize,
+}
+
+impl UnixTable {
+    const fn new() -> Self {
+        Self {
+            sockets: BTreeMap::new(),
+            names: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn insert(&mut self, state: UnixSocketState) -> usize {
+        self.next_id += 1;
+        self.sockets.insert(self.next_id, Box::new(state));
+        self.next_id
+    }
+
+    /// Socket bound to file of `path`
+    fn find(&self, path: &str) -> Option<(usize, Arc<dyn Inode>)> {
+        let inode = fs::lookup(path)?;
+        let id = *self.names.get(&inode_key(&inode))?;
+        Some((id, inode))
+    }
+
+    fn wake(&self, id: usize) {
+        if let Some(sock) = self.sockets.get(&id) {
+            wakeup(&**sock as *const UnixSocketState);
+        }
+    }
+}
+
+/// Key of file in `names`
+fn inode_key(inode: &Arc<dyn Inode>) -> usize {
+    Arc::as_ptr(inode) as *const u8 as usize
+}
+
+static UNIX: Mutex<UnixTable> = Mutex::new(UnixTable::new(), "unix socket");
+
+/// Sleep on socket `id` until woken up
+fn sleep_on<'a>(table: MutexGuard<'a, UnixTable>, id: usize) -> MutexGuard<'a, UnixTable> {
+    let chan = &*table.sockets[&id] as *const UnixSocketState;
+    sleep(chan, table)
+}
+
+/// A Unix-domain socket, whose state is in `UNIX`
+pub struct UnixSocket {
+    id: usize,
+}
+
+impl UnixSocket {
+    /// Create a socket of `ty`. Returns `None` if not supported.
+    pub fn new(ty: usize) -> Option<Self> {
+        if ty != SOCK_STREAM && ty != SOCK_DGRAM {
+            return None;
+        }
+        Some(Self { id: UNIX.lock().insert(UnixSocketState::new(ty)) })
+    }
+
+    /// Bind socket to path in `addr`, which must not exist
+    pub fn bind(&self, addr: &[u8]) -> i32 {
+        let path = match parse_sockaddr_un(addr) {
+            Some(path) => path,
+            None => { return -1; }
+        };
+        let mut table = UNIX.lock();
+        if table.sockets[&self.id].inode.is_some() {
+            return -1;
+        }
+        let inode = match fs::create(path) {
+            Some(inode) => inode,
+            None => { return -1; }
+        };
+        table.names.insert(inode_key(&inode), self.id);
+        table.sockets.get_mut(&self.id).unwrap().inode = Some(inode);
+        0
+    }
+
+    /// Listen for connections, at most `backlog` of them waiting for accept
+    pub fn listen(&self, backlog: usize) -> i32 {
+        let mut table = UNIX.lock();
+        let sock = table.sockets.get_mut(&self.id).unwrap();
+        if sock.ty != SOCK_STREAM || sock.inode.is_none() || sock.peer.is_some() {
+            return -1;
+        }
+        sock.listening = true;
+        sock.backlog_max = backlog.max(1);
+        0
+    }
+
+    /// Wait for a connection on listening socket
+    pub fn accept(&self) -> Option<UnixSocket> {
+        let mut table = UNIX.lock();
+        loop {
+            let sock = table.sockets.get_mut(&self.id).unwrap();
+            if !sock.listening {
+                return None;
+            }
+            if let Some(id) = sock.backlog.pop_front() {
+                return Some(UnixSocket { id });
+            }
+            table = sleep_on(table, self.id);
+        }
+    }
+
+    /// Connect to socket bound to path in `addr`. Stream connection is
+    /// queued for accept, while datagram socket only sets default peer.
+    pub fn connect(&self, addr: &[u8]) -> i32 {
+        let path = match parse_sockaddr_un(addr) {
+            Some(path) => path,
+            None => { return -1; }
+        };
+        let mut table = UNIX.lock();
+        let (listener, inode) = match table.find(path) {
+            Some(found) => found,
+            None => { return -1; }
+        };
+        let ty = table.sockets[&self.id].ty;
+        if table.sockets[&listener].ty != ty || table.sockets[&self.id].peer.is_some() {
+            return -1;
+        }
+        if ty == SOCK_DGRAM {
+            table.sockets.get_mut(&self.id).unwrap().peer_inode = Some(inode);
+            return 0;
+        }
+        let l = &table.sockets[&listener];
+        if !l.listening || l.backlog.len() >= l.backlog_max || table.sockets[&self.id].listening {
+            return -1;
+        }
+        let mut server = UnixSocketState::new(SOCK_STREAM);
+        server.peer = Some(self.id);
+        let server = table.insert(server);
+        table.sockets.get_mut(&self.id).unwrap().peer = Some(server);
+        table.sockets.get_mut(&listener).unwrap().backlog.push_back(server);
+        table.wake(listener);
+        0
+    }
+
+    /// Send `data` to peer, returns number of bytes sent
+    pub fn send(&self, data: &[u8]) -> i32 {
+        let mut table = UNIX.lock();
+        if table.sockets[&self.id].ty == SOCK_DGRAM {
+            let peer = table.sockets[&self.id].peer_inode.as_ref()
+                .and_then(|inode| table.names.get(&inode_key(inode)).copied());
+            let peer = match peer.and_then(|id| table.sockets.get_mut(&id)) {
+                Some(peer) => peer,
+                None => { return -1; }
+            };
+            if peer.dgrams.len() < DGRAM_MAX {
+                peer.dgrams.push_back(Vec::from(data));
+                wakeup(&**peer as *const UnixSocketState);
+            }
+            return data.len() as i32;
+        }
+        let mut sent = 0;
+        while sent < data.len() {
+            let sock = &table.sockets[&self.id];
+            let peer = match sock.peer {
+                Some(peer) if !sock.peer_closed => peer,
+                _ => { return if sent == 0 { -1 } else { sent as i32 }; }
+            };
+            let rx = &mut table.sockets.get_mut(&peer).unwrap().rx;
+            let len = (STREAM_CAPACITY - rx.len()).min(data.len() - sent);
+            if len == 0 {
+                table = sleep_on(table, self.id);
+                continue;
+            }
+            rx.extend(&data[sent..sent + len]);
+            sent += len;
+            table.wake(peer);
+        }
+        sent as i32
+    }
+
+    /// Receive data into `buf`, returns number of bytes received.
+    /// 0 means peer of stream socket has closed.
+    pub fn recv(&self, buf: &mut [u8]) -> i32 {
+        let mut table = UNIX.lock();
+        loop {
+            let sock = table.sockets.get_mut(&self.id).unwrap();
+            if sock.ty == SOCK_DGRAM {
+                if let Some(data) = sock.dgrams.pop_front() {
+                    let len = data.len().min(buf.len());
+                    buf[..len].copy_from_slice(&data[..len]);
+                    return len as i32;
+                }
+            } else {
+                if !sock.rx.is_empty() {
+                    let len = buf.len().min(sock.rx.len());
+                    for (dst, src) in buf.iter_mut().zip(sock.rx.drain(..len)) {
+                        *dst = src;
+                    }
+                    // peer may be waiting for space in buffer
+                    if let Some(peer) = sock.peer {
+                        table.wake(peer);
+                    }
+                    return len as i32;
+                }
+                if sock.peer_closed || (sock.peer.is_none() && !sock.listening) {
+                    return 0;
+                }
+            }
+            table = sleep_on(table, self.id);
+        }
+    }
+}
+
+/// Close socket `id`, telling its peer
+fn close(table: &mut UnixTable, id: usize) {
+    let sock = match table.sockets.remove(&id) {
+        Some(sock) => sock,
+        None => { return; }
+    };
+    if let Some(inode) = sock.inode.as_ref() {
+        table.names.remove(&inode_key(inode));
+    }
+    if sock.ty == SOCK_STREAM {
+        if let Some(peer) = sock.peer.and_then(|peer| table.sockets.get_mut(&peer)) {
+            peer.peer_closed = true;
+            wakeup(&**peer as *const UnixSocketState);
+        }
+    }
+    for conn in sock.backlog {
+        close(table, conn);
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        close(&mut UNIX.lock(), self.id);
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("sockaddr", test_sockaddr),
+            ("stream", test_stream),
+            ("datagram", test_dgram),
+        ]
+    }
+
+    fn sockaddr(path: &str) -> Vec<u8> {
+        let mut addr = Vec::from((AF_UNIX as u16).to_le_bytes());
+        addr.extend_from_slice(path.as_bytes());
+        addr.push(0);
+        addr
+    }
+
+    /// Test parsing socket address
+    pub fn test_sockaddr() {
+        assert_eq!(parse_sockaddr_un(&sockaddr("/tmp/sock")), Some("/tmp/sock"));
+        assert_eq!(parse_sockaddr_un(&sockaddr("")), None);
+        assert_eq!(parse_sockaddr_un(&[0, 0, b'/']), None);
+    }
+
+    /// Test stream connection in one process
+    pub fn test_stream() {
+        let addr = sockaddr("/tmp/test_stream.sock");
+        let server = UnixSocket::new(SOCK_STREAM).unwrap();
+        assert_eq!(server.bind(&addr), 0);
+        assert_eq!(server.listen(1), 0);
+        let client = UnixSocket::new(SOCK_STREAM).unwrap();
+        assert_eq!(client.connect(&addr), 0);
+        let conn = server.accept().unwrap();
+        assert_eq!(client.send(b"ping"), 4);
+        let mut buf = [0; 8];
+        assert_eq!(conn.recv(&mut buf), 4);
+        assert_eq!(&buf[..4], b"ping");
+        drop(client);
+        assert_eq!(conn.recv(&mut buf), 0);
+        assert_eq!(fs::unlink("/tmp/test_stream.sock"), 0);
+    }
+
+    /// Test datagrams, and unreachable socket after its file is removed
+    pub fn test_dgram() {
+        let addr = sockaddr("/tmp/test_dgram.sock");
+        let a = UnixSocket::new(SOCK_DGRAM).unwrap();
+        assert_eq!(a.bind(&addr), 0);
+        let b = UnixSocket::new(SOCK_DGRAM).unwrap();
+        assert_eq!(b.connect(&addr), 0);
+        assert_eq!(b.send(b"hello"), 5);
+        let mut buf = [0; 8];
+        assert_eq!(a.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(fs::unlink("/tmp/test_dgram.sock"), 0);
+        let c = UnixSocket::new(SOCK_DGRAM).unwrap();
+        assert_eq!(c.connect(&addr), -1);
+    }
+}
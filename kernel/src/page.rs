@@ -30,6 +30,15 @@ impl Page {
             data: [0; PAGE_SIZE]
         })
     }
+
+    /// Allocate a zeroed page for user space, `None` if memory is low
+    pub fn try_new() -> Option<Box<Self>> {
+        let page = mem::ALLOC().lock().allocate_user(PAGE_SIZE)? as *mut Self;
+        unsafe {
+            (*page).data.fill(0);
+            Some(Box::from_raw(page))
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -117,10 +126,11 @@ impl PPN {
             _ => unreachable!(),
         }
     }
-    pub fn clone_page(&self) -> Box<Page> {
-        let mut pg = Page::new();
+    /// Copy page at this address for user space, `None` if memory is low
+    pub fn try_clone_page(&self) -> Option<Box<Page>> {
+        let mut pg = Page::try_new()?;
         unsafe { core::ptr::copy(self.0 as *const u8, pg.data.as_mut_ptr(), PAGE_SIZE); }
-        pg
+        Some(pg)
     }
 }
 
@@ -151,6 +161,15 @@ impl Table {
         }
     }
 
+    /// Allocate an empty page table for user space, `None` if memory is low
+    pub fn try_new() -> Option<Box<Self>> {
+        let table = mem::ALLOC().lock().allocate_user(PAGE_SIZE)? as *mut Self;
+        unsafe {
+            table.write(Self::new());
+            Some(Box::from_raw(table))
+        }
+    }
+
     pub const fn len(&self) -> usize {
         TABLE_ENTRY_CNT
     }
@@ -202,9 +221,31 @@ impl Table {
             let entry = v.paddr().0 as *mut Entry;
             v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
         }
+        if !v.is_v() {
+            return None;
+        }
         Some(v.paddr().0)
     }
 
+    /// Unmap user page at `vaddr`, returning it
+    pub fn unmap(&mut self, vaddr: usize) -> Option<Box<Page>> {
+        let vpn = VPN(vaddr);
+        let mut v = &mut self.entries[vpn.vpn2()];
+        for lvl in (0..2).rev() {
+            if !v.is_v() {
+                return None;
+            }
+            let entry = v.paddr().0 as *mut Entry;
+            v = unsafe { entry.add(vpn.idx(lvl)).as_mut().unwrap() };
+        }
+        if !v.is_v() || !v.is_u() {
+            return None;
+        }
+        let pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
+        *v = Entry(0);
+        Some(pg)
+    }
+
     fn _walk(&self, level: usize, vpn: usize) {
         for i in 0..self.len() {
             let v = &self.entries[i];
@@ -266,7 +307,7 @@ impl Table {
 
     /* TODO: use same function for drop_walk, unmap_user and walk */
 
-    fn drop_walk(&mut self, _level: usize) {
+    fn drop_walk(&mut self) {
         for i in 0..self.len() {
             let v = &mut self.entries[i];
             if v.is_v() {
@@ -276,32 +317,52 @@ impl Table {
                         let _pg = unsafe { Box::from_raw(v.paddr().0 as *mut Page) };
                     }
                 } else {
-                    // drop page table
-                    let mut table = unsafe { Box::from_raw(v.paddr().0 as *mut Table) };
-                    table.drop_walk(_level - 1);
+                    // drop page table, which drops pages below it
+                    let _table = unsafe { Box::from_raw(v.paddr().0 as *mut Table) };
                 }
+                *v = Entry(0);
             }
         }
     }
 
-    fn clone_walk(&self, _level: usize) -> Box<Self> {
-        let mut pgtable = Table::new();
+    /// Copy user pages into a new page table, `None` if memory is low. Kernel
+    /// pages are not copied. What's copied is dropped on failure.
+    pub fn try_clone(&self) -> Option<Box<Self>> {
+        let mut pgtable = Table::try_new()?;
         for i in 0..self.len() {
             let v = &self.entries[i];
             if v.is_v() {
                 if v.is_leaf() {
                     if v.is_u() {
-                        let pg = v.paddr().clone_page();
+                        let pg = v.paddr().try_clone_page()?;
                         pgtable.entries[i] = Entry::new(Box::into_raw(pg) as usize, v.flags());
                     }
                 } else {
                     let table = unsafe { (v.paddr().0 as *mut Table).as_mut().unwrap() };
-                    let pg = table.clone_walk(_level - 1);
+                    let pg = table.try_clone()?;
                     pgtable.entries[i] = Entry::new(Box::into_raw(pg) as usize, v.flags());
                 }
             }
         }
-        Box::new(pgtable)
+        Some(pgtable)
+    }
+
+    /// Number of user pages mapped
+    pub fn user_pages(&self) -> usize {
+        let mut count = 0;
+        for v in self.entries.iter() {
+            if v.is_v() {
+                if v.is_leaf() {
+                    if v.is_u() {
+                        count += 1;
+                    }
+                } else {
+                    let table = unsafe { &*(v.paddr().0 as *const Table) };
+                    count += table.user_pages();
+                }
+            }
+        }
+        count
     }
 
     pub fn unmap_user(&mut self) {
@@ -326,13 +387,7 @@ impl Table {
 
 impl Drop for Table {
     fn drop(&mut self) {
-        self.drop_walk(2);
-    }
-}
-
-impl Clone for Box<Table> {
-    fn clone(&self) -> Self {
-        self.clone_walk(2)
+        self.drop_walk();
     }
 }
 
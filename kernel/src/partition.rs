@@ -0,0 +1,296 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! MBR and GPT partition table
+//!
+//! Partitions found at boot are registered as block devices, whose
+//! sectors are relative to the beginning of partition.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::block::{BlockDevice, IoError, SECTOR_SIZE};
+
+/// A window of disk sectors
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Partition {
+    /// First sector
+    pub start: usize,
+    /// Number of sectors
+    pub sectors: usize,
+}
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_ENTRY_OFFSET: usize = 446;
+const MBR_TYPE_EMPTY: u8 = 0;
+const MBR_TYPE_EXTENDED: u8 = 0x05;
+const MBR_TYPE_EXTENDED_LBA: u8 = 0x0f;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Entries read from GPT at most, as many as its minimum array size
+/// holds in 128-byte entries
+const GPT_MAX_ENTRIES: usize = 128;
+/// Bytes of GPT entries read at most
+const GPT_MAX_ENTRIES_SIZE: usize = 128 * 128;
+
+fn read_u16(data: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([data[off], data[off + 1]])
+}
+
+fn read_u32(data: &[u8], off: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&data[off..off + 4]);
+    u32::from_le_bytes(b)
+}
+
+fn read_u64(data: &[u8], off: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&data[off..off + 8]);
+    u64::from_le_bytes(b)
+}
+
+/// Result of parsing MBR in sector 0
+#[derive(PartialEq, Debug)]
+pub enum Mbr {
+    /// No valid MBR signature
+    None,
+    /// Protective MBR, partitions are in GPT
+    Gpt,
+    /// Primary partitions
+    Partitions(Vec<Partition>),
+}
+
+/// Parse MBR in `sector0`
+pub fn parse_mbr(sector0: &[u8]) -> Mbr {
+    if read_u16(sector0, 510) != MBR_SIGNATURE {
+        return Mbr::None;
+    }
+    let mut parts = Vec::new();
+    for i in 0..4 {
+        let entry = &sector0[MBR_ENTRY_OFFSET + i * 16..MBR_ENTRY_OFFSET + (i + 1) * 16];
+        match entry[4] {
+            MBR_TYPE_GPT_PROTECTIVE => { return Mbr::Gpt; }
+            MBR_TYPE_EMPTY | MBR_TYPE_EXTENDED | MBR_TYPE_EXTENDED_LBA => {}
+            _ => {
+                parts.push(Partition {
+                    start: read_u32(entry, 8) as usize,
+                    sectors: read_u32(entry, 12) as usize,
+                });
+            }
+        }
+    }
+    Mbr::Partitions(parts)
+}
+
+/// Parse GPT with header in `sector1`. `read_sector` reads one sector
+/// from the whole disk. Entries beyond `GPT_MAX_ENTRIES` or
+/// `GPT_MAX_ENTRIES_SIZE` are ignored.
+pub fn parse_gpt<F>(sector1: &[u8], mut read_sector: F) -> Option<Vec<Partition>>
+    where F: FnMut(usize, &mut [u8; SECTOR_SIZE]) -> Result<(), IoError>
+{
+    if &sector1[0..8] != GPT_SIGNATURE {
+        return None;
+    }
+    let entries_lba = read_u64(sector1, 72) as usize;
+    let entries_num = read_u32(sector1, 80) as usize;
+    let entry_size = read_u32(sector1, 84) as usize;
+    if entry_size < 128 || entry_size > SECTOR_SIZE || SECTOR_SIZE % entry_size != 0 {
+        return None;
+    }
+    let entries_num = entries_num.min(GPT_MAX_ENTRIES).min(GPT_MAX_ENTRIES_SIZE / entry_size);
+    let per_sector = SECTOR_SIZE / entry_size;
+    let mut parts = Vec::new();
+    let mut sector = [0; SECTOR_SIZE];
+    for i in 0..entries_num {
+        if i % per_sector == 0 {
+            read_sector(entries_lba.checked_add(i / per_sector)?, &mut sector).ok()?;
+        }
+        let entry = &sector[(i % per_sector) * entry_size..];
+        // unused entry has type GUID of zero
+        if entry[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = read_u64(entry, 32) as usize;
+        let last = read_u64(entry, 40) as usize;
+        if last < first {
+            return None;
+        }
+        parts.push(Partition { start: first, sectors: last - first + 1 });
+    }
+    Some(parts)
+}
+
+/// Scan partition table on `disk`. Partitions beyond end of disk are
+/// dropped.
+pub fn scan(disk: &dyn BlockDevice) -> Vec<Partition> {
+    let read_sector = |lba: usize, sector: &mut [u8; SECTOR_SIZE]| disk.read_sectors(lba, sector);
+    let mut sector0 = [0; SECTOR_SIZE];
+    if disk.read_sectors(0, &mut sector0).is_err() {
+        return Vec::new();
+    }
+    let mut parts = match parse_mbr(&sector0) {
+        Mbr::None => Vec::new(),
+        Mbr::Partitions(parts) => parts,
+        Mbr::Gpt => {
+            let mut sector1 = [0; SECTOR_SIZE];
+            match disk.read_sectors(1, &mut sector1) {
+                Ok(()) => parse_gpt(&sector1, read_sector).unwrap_or_default(),
+                Err(_) => Vec::new(),
+            }
+        }
+    };
+    let sectors = disk.sectors();
+    parts.retain(|part| part.start <= sectors && part.sectors <= sectors - part.start);
+    parts
+}
+
+/// A partition as block device
+pub struct PartitionDevice {
+    disk: Arc<dyn BlockDevice>,
+    part: Partition,
+}
+
+impl PartitionDevice {
+    pub fn new(disk: Arc<dyn BlockDevice>, part: Partition) -> Self {
+        Self { disk, part }
+    }
+
+    /// Translate sector in partition into disk sector, for I/O of `len`
+    /// bytes
+    fn sector_of(&self, sector: usize, len: usize) -> Result<usize, IoError> {
+        if sector > self.part.sectors || len / SECTOR_SIZE > self.part.sectors - sector {
+            return Err(IoError::OutOfRange);
+        }
+        Ok(self.part.start + sector)
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn sectors(&self) -> usize {
+        self.part.sectors
+    }
+
+    fn read_sectors(&self, sector: usize, data: &mut [u8]) -> Result<(), IoError> {
+        self.disk.read_sectors(self.sector_of(sector, data.len())?, data)
+    }
+
+    fn write_sectors(&self, sector: usize, data: &[u8]) -> Result<(), IoError> {
+        self.disk.write_sectors(self.sector_of(sector, data.len())?, data)
+    }
+
+    fn read_vectored(&self, sector: usize, bufs: &mut [&mut [u8]]) -> Result<(), IoError> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.disk.read_vectored(self.sector_of(sector, len)?, bufs)
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("mbr", test_mbr),
+            ("gpt", test_gpt),
+            ("gpt entries", test_gpt_entries),
+            ("out of range", test_out_of_range),
+        ]
+    }
+
+    /// Test parsing MBR primary partitions
+    pub fn test_mbr() {
+        let mut sector = [0; SECTOR_SIZE];
+        assert_eq!(parse_mbr(&sector), Mbr::None);
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        let entry = MBR_ENTRY_OFFSET;
+        sector[entry + 4] = 0x83;
+        sector[entry + 8..entry + 12].copy_from_slice(&2048u32.to_le_bytes());
+        sector[entry + 12..entry + 16].copy_from_slice(&4096u32.to_le_bytes());
+        let entry = MBR_ENTRY_OFFSET + 16;
+        sector[entry + 4] = MBR_TYPE_EXTENDED;
+        assert_eq!(parse_mbr(&sector), Mbr::Partitions(alloc::vec![
+            Partition { start: 2048, sectors: 4096 }
+        ]));
+        sector[entry + 4] = MBR_TYPE_GPT_PROTECTIVE;
+        assert_eq!(parse_mbr(&sector), Mbr::Gpt);
+    }
+
+    /// Test parsing GPT entries
+    pub fn test_gpt() {
+        let mut header = [0; SECTOR_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&6u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let parts = parse_gpt(&header, |lba, sector| {
+            *sector = [0; SECTOR_SIZE];
+            // first entry of LBA 2 and second entry of LBA 3 are used
+            let idx = if lba == 2 { 0 } else { 1 };
+            let entry = &mut sector[idx * 128..(idx + 1) * 128];
+            entry[0] = 1;
+            entry[32..40].copy_from_slice(&(lba as u64 * 100).to_le_bytes());
+            entry[40..48].copy_from_slice(&(lba as u64 * 100 + 9).to_le_bytes());
+            Ok(())
+        }).unwrap();
+        assert_eq!(parts, alloc::vec![
+            Partition { start: 200, sectors: 10 },
+            Partition { start: 300, sectors: 10 },
+        ]);
+    }
+
+    /// Test that number of GPT entries is bounded, and read errors fail
+    /// parsing
+    pub fn test_gpt_entries() {
+        let mut header = [0; SECTOR_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+        header[84..88].copy_from_slice(&512u32.to_le_bytes());
+        let mut reads = 0;
+        let parts = parse_gpt(&header, |_, sector| {
+            *sector = [0; SECTOR_SIZE];
+            reads += 1;
+            Ok(())
+        }).unwrap();
+        assert!(parts.is_empty());
+        assert_eq!(reads, GPT_MAX_ENTRIES_SIZE / SECTOR_SIZE);
+        assert!(parse_gpt(&header, |_, _| Err(IoError::OutOfRange)).is_none());
+    }
+
+    /// Disk of zeroes
+    struct ZeroDisk(usize);
+
+    impl BlockDevice for ZeroDisk {
+        fn sectors(&self) -> usize {
+            self.0
+        }
+
+        fn read_sectors(&self, sector: usize, data: &mut [u8]) -> Result<(), IoError> {
+            if sector + data.len() / SECTOR_SIZE > self.0 {
+                return Err(IoError::OutOfRange);
+            }
+            data.fill(0);
+            Ok(())
+        }
+
+        fn write_sectors(&self, sector: usize, data: &[u8]) -> Result<(), IoError> {
+            if sector + data.len() / SECTOR_SIZE > self.0 {
+                return Err(IoError::OutOfRange);
+            }
+            Ok(())
+        }
+    }
+
+    /// Test that I/O beyond a partition fails
+    pub fn test_out_of_range() {
+        let part = PartitionDevice::new(Arc::new(ZeroDisk(100)), Partition { start: 10, sectors: 20 });
+        let mut data = [0; SECTOR_SIZE * 2];
+        assert_eq!(part.read_sectors(18, &mut data), Ok(()));
+        assert_eq!(part.read_sectors(19, &mut data), Err(IoError::OutOfRange));
+        assert_eq!(part.write_sectors(usize::MAX, &data), Err(IoError::OutOfRange));
+        let mut bufs = [&mut data[..]];
+        assert_eq!(part.read_vectored(20, &mut bufs), Err(IoError::OutOfRange));
+        assert!(scan(&ZeroDisk(100)).is_empty());
+    }
+}
@@ -4,20 +4,23 @@
 // https://opensource.org/licenses/MIT
 
 //! RISC-V Platform-Level Interrupt Controller
-
-
+//!
+//! Base address and IRQs of devices are taken from FDT. Register
+//! constants below are offsets from base address.
 
 use crate::arch::hart_id;
+use crate::fdt::machine;
 
+/// Default PLIC base address on QEMU RISC-V, used if not in FDT
 pub const PLIC_BASE: usize = 0x0c00_0000;
-pub const PLIC_PRIORITY: usize = PLIC_BASE;
-pub const PLIC_PENDING: usize = PLIC_BASE + 0x1000;
-pub const PLIC_MENABLE_BASE: usize = PLIC_BASE + 0x2000;
-pub const PLIC_SENABLE_BASE: usize = PLIC_BASE + 0x2080;
-pub const PLIC_MPRIORITY_BASE: usize = PLIC_BASE + 0x200000;
-pub const PLIC_SPRIORITY_BASE: usize = PLIC_BASE + 0x201000;
-pub const PLIC_MCLAIM_BASE: usize = PLIC_BASE + 0x200004;
-pub const PLIC_SCLAIM_BASE: usize = PLIC_BASE + 0x201004;
+pub const PLIC_PRIORITY: usize = 0;
+pub const PLIC_PENDING: usize = 0x1000;
+pub const PLIC_MENABLE_BASE: usize = 0x2000;
+pub const PLIC_SENABLE_BASE: usize = 0x2080;
+pub const PLIC_MPRIORITY_BASE: usize = 0x200000;
+pub const PLIC_SPRIORITY_BASE: usize = 0x201000;
+pub const PLIC_MCLAIM_BASE: usize = 0x200004;
+pub const PLIC_SCLAIM_BASE: usize = 0x201004;
 
 #[allow(non_snake_case)]
 pub const fn PLIC_MENABLE(hart: usize) -> usize { PLIC_MENABLE_BASE + hart * 0x100 }
@@ -37,22 +40,27 @@ pub const fn PLIC_MCLAIM(hart: usize) -> usize { PLIC_MCLAIM_BASE + hart * 0x200
 #[allow(non_snake_case)]
 pub const fn PLIC_SCLAIM(hart: usize) -> usize { PLIC_SCLAIM_BASE + hart * 0x2000 }
 
-pub const UART0_IRQ: u32 = 10;
-
-pub const VIRTIO0_IRQ: u32 = 1;
-
-pub struct Plic {}
+pub struct Plic {
+    /// PLIC MMIO base address
+    base: usize,
+}
 
 impl Plic {
-    pub const fn new() -> Self {
-        Self {}
+    pub const fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    /// Get pointer to register at `offset`
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
     }
+
     /// Get the next available interrupt. This is the "claim" process.
     /// The plic will automatically sort by priority and hand us the
     /// ID of the interrupt. For example, if the UART is interrupting
     /// and it's next, we will get the value 10.
     pub fn next(&mut self) -> Option<u32> {
-        let claim_reg = PLIC_SCLAIM(hart_id()) as *const u32;
+        let claim_reg = self.reg(PLIC_SCLAIM(hart_id())) as *const u32;
         let claim_no;
         // The claim register is filled with the highest-priority, enabled interrupt.
         unsafe {
@@ -71,7 +79,7 @@ impl Plic {
     /// Complete a pending interrupt by id. The id should come
     /// from the next() function above.
     pub fn complete(&mut self, id: u32) {
-        let complete_reg = PLIC_SCLAIM(hart_id()) as *mut u32;
+        let complete_reg = self.reg(PLIC_SCLAIM(hart_id()));
         unsafe {
             // We actually write a u32 into the entire complete_register.
             // This is the same register as the claim register, but it can
@@ -82,30 +90,30 @@ impl Plic {
 
     /// Initialize PLIC. Enable interrupt.
     pub unsafe fn init(&mut self, id: u32) {
-        let enables = PLIC_BASE as *mut u32;
-        enables.add(id as usize).write_volatile(1);
+        let priorities = self.reg(PLIC_PRIORITY);
+        priorities.add(id as usize).write_volatile(1);
     }
 
     /// See if a given interrupt id is pending.
     ///
     /// Should only be called with lock.
     pub unsafe fn is_pending(&mut self, id: u32) -> bool {
-        let pend = PLIC_PENDING as *const u32;
-        let actual_id = 1 << id;
-        
-        let pend_ids = pend.read_volatile();
+        let pend = self.reg(PLIC_PENDING) as *const u32;
+        let actual_id = 1 << (id % 32);
+
+        let pend_ids = pend.add(id as usize / 32).read_volatile();
         actual_id & pend_ids != 0
     }
 
     /// Enable a given interrupt id
     pub fn enable(&mut self, id: u32) {
-        let enables = PLIC_SENABLE(hart_id()) as *mut u32;
-        let actual_id = 1 << id;
+        let actual_id = 1 << (id % 32);
         unsafe {
             // Unlike the complete and claim registers, the plic_int_enable
-            // register is a bitset where the id is the bit index. The register
-            // is a 32-bit register, so that gives us enables for interrupts
-            // 31 through 1 (0 is hardwired to 0).
+            // register is a bitset where the id is the bit index. Each
+            // register is 32-bit, so interrupt id selects the register
+            // first (0 is hardwired to 0).
+            let enables = self.reg(PLIC_SENABLE(hart_id())).add(id as usize / 32);
             enables.write_volatile(enables.read_volatile() | actual_id);
         }
     }
@@ -119,7 +127,7 @@ impl Plic {
         // is a 3-bit 0b111. So, we and with 7 (0b111) to just get the
         // last three bits.
         let actual_tsh = tsh & 7;
-        let tsh_reg = PLIC_SPRIORITY(hart_id()) as *mut u32;
+        let tsh_reg = self.reg(PLIC_SPRIORITY(hart_id()));
         unsafe {
             tsh_reg.write_volatile(actual_tsh as u32);
         }
@@ -129,7 +137,7 @@ impl Plic {
     /// The priority must be [0..7]
     pub fn set_priority(&mut self, id: u32, prio: u8) {
         let actual_prio = prio as u32 & 7;
-        let prio_reg = PLIC_SPRIORITY(hart_id()) as *mut u32;
+        let prio_reg = self.reg(PLIC_SPRIORITY(hart_id()));
         unsafe {
             // The offset for the interrupt id is:
             // PLIC_PRIORITY + 4 * id
@@ -141,26 +149,36 @@ impl Plic {
 }
 
 /// PLIC driver object
-static mut __PLIC: Plic = Plic::new();
+static mut __PLIC: Plic = Plic::new(PLIC_BASE);
 
 /// Global function to get an instance of PLIC driver
 #[allow(non_snake_case)]
 pub fn PLIC() -> &'static mut Plic { unsafe { &mut __PLIC } }
 
+/// IRQs of devices with drivers, as found in FDT
+fn device_irqs() -> impl Iterator<Item = u32> {
+    let machine = machine();
+    core::iter::once(machine.uart.irq)
+        .chain(machine.virtio().iter().map(|d| d.irq))
+        .filter(|&irq| irq != 0)
+}
+
 /// Initialize PLIC
 ///
 /// This function should only be called from boot hart
 pub unsafe fn init() {
     let plic = PLIC();
-    plic.init(UART0_IRQ);
-    plic.init(VIRTIO0_IRQ);
+    plic.base = machine().plic.base;
+    for irq in device_irqs() {
+        plic.init(irq);
+    }
 }
 
 pub fn hartinit() {
     let plic = PLIC();
-    plic.enable(UART0_IRQ);
-    plic.enable(VIRTIO0_IRQ);
     plic.set_threshold(0);
-    plic.set_priority(UART0_IRQ, 1);
-    plic.set_priority(VIRTIO0_IRQ, 1);
+    for irq in device_irqs() {
+        plic.enable(irq);
+        plic.set_priority(irq, 1);
+    }
 }
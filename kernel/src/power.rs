@@ -0,0 +1,55 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Power control through SiFive test finisher
+//!
+//! Writing to the finisher on QEMU virt either terminates QEMU, with its
+//! exit status taken from the written value, or resets the machine.
+
+use crate::uart;
+
+/// Default test finisher base address on QEMU RISC-V, used if not in FDT
+pub const FINISHER_BASE: usize = 0x10_0000;
+
+/// Terminate with failure, exit code in upper 16 bits
+const FINISHER_FAIL: u32 = 0x3333;
+/// Terminate with success
+const FINISHER_PASS: u32 = 0x5555;
+/// Reset machine
+const FINISHER_RESET: u32 = 0x7777;
+
+/// Exit code of QEMU when kernel panics
+pub const PANIC_EXIT_CODE: u16 = 101;
+
+/// reboot command: power off
+pub const REBOOT_POWER_OFF: usize = 0;
+/// reboot command: restart
+pub const REBOOT_RESTART: usize = 1;
+
+fn finish(val: u32) -> ! {
+    uart::flush();
+    unsafe { (crate::fdt::machine().finisher.base as *mut u32).write_volatile(val); }
+    // finisher absent, e.g. on other machines
+    crate::abort();
+}
+
+/// Power off with exit code 0
+pub fn shutdown() -> ! {
+    finish(FINISHER_PASS)
+}
+
+/// Reset machine
+pub fn reboot() -> ! {
+    finish(FINISHER_RESET)
+}
+
+/// Power off, making QEMU exit with `code`
+pub fn exit(code: u16) -> ! {
+    if code == 0 {
+        shutdown()
+    } else {
+        finish((code as u32) << 16 | FINISHER_FAIL)
+    }
+}
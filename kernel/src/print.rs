@@ -21,8 +21,10 @@ pub fn _print(args: fmt::Arguments) {
 pub fn _panic_print(args: fmt::Arguments) {
     use core::fmt::Write;
     use crate::uart::*;
-    let mut uart = Uart::new(UART_BASE_ADDR);
+    // UART may be locked by panicking code
+    let mut uart = Uart::new(unsafe { UART().get() }.base_address());
     uart.write_fmt(args).unwrap();
+    uart.flush();
 }
 
 /// Print information
@@ -49,69 +51,73 @@ macro_rules! panic_println {
     })
 }
 
-/// Prints an info, with newline.
+/// Prints an info, with newline. Timestamp is UTC time of day.
 #[macro_export]
 macro_rules! info {
     ($string:expr) => ({
         #[allow(unused_imports)]
         let _info_locker = $crate::print::INFO_LOCK.lock();
 
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
+        let timestamp = $crate::rtc::realtime();
+        let timestamp_secs = timestamp.as_secs() % 86400;
 
         $crate::print::_print(format_args_nl!(
-            concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000
+            concat!("\x1b[0;36m[{:02}:{:02}:{:02}.{:06}]\x1b[0m ", $string),
+            timestamp_secs / 3600,
+            timestamp_secs / 60 % 60,
+            timestamp_secs % 60,
+            timestamp.subsec_micros()
         ));
     });
     ($format_string:expr, $($arg:tt)*) => ({
         #[allow(unused_imports)]
         let _info_locker = $crate::print::INFO_LOCK.lock();
 
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
+        let timestamp = $crate::rtc::realtime();
+        let timestamp_secs = timestamp.as_secs() % 86400;
 
         $crate::print::_print(format_args_nl!(
-            concat!("\x1b[0;36m[  {:>3}.{:03}{:03}]\x1b[0m ", $format_string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000,
+            concat!("\x1b[0;36m[{:02}:{:02}:{:02}.{:06}]\x1b[0m ", $format_string),
+            timestamp_secs / 3600,
+            timestamp_secs / 60 % 60,
+            timestamp_secs % 60,
+            timestamp.subsec_micros(),
             $($arg)*
         ));
     })
 }
 
-/// Prints a warning, with newline.
+/// Prints a warning, with newline. Timestamp is UTC time of day.
 #[macro_export]
 macro_rules! warn {
     ($string:expr) => ({
         #[allow(unused_imports)]
         let _info_locker = $crate::print::INFO_LOCK.lock();
 
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
+        let timestamp = $crate::rtc::realtime();
+        let timestamp_secs = timestamp.as_secs() % 86400;
 
         $crate::print::_print(format_args_nl!(
-            concat!("[W {:>3}.{:03}{:03}] ", $string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000
+            concat!("[W {:02}:{:02}:{:02}.{:06}] ", $string),
+            timestamp_secs / 3600,
+            timestamp_secs / 60 % 60,
+            timestamp_secs % 60,
+            timestamp.subsec_micros()
         ));
     });
     ($format_string:expr, $($arg:tt)*) => ({
         #[allow(unused_imports)]
         let _info_locker = $crate::print::INFO_LOCK.lock();
 
-        let timestamp = $crate::arch::time();
-        let timestamp_subsec_us = timestamp.subsec_micros();
+        let timestamp = $crate::rtc::realtime();
+        let timestamp_secs = timestamp.as_secs() % 86400;
 
         $crate::print::_print(format_args_nl!(
-            concat!("[W {:>3}.{:03}{:03}] ", $format_string),
-            timestamp.as_secs(),
-            timestamp_subsec_us / 1_000,
-            timestamp_subsec_us % 1_000,
+            concat!("[W {:02}:{:02}:{:02}.{:06}] ", $format_string),
+            timestamp_secs / 3600,
+            timestamp_secs / 60 % 60,
+            timestamp_secs % 60,
+            timestamp.subsec_micros(),
             $($arg)*
         ));
     })
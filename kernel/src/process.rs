@@ -14,6 +14,8 @@ mod context;
 pub use context::*;
 mod schedule;
 pub use schedule::*;
+pub mod vma;
+pub use vma::{Access, Backing, Fault, Vma, Vmas};
 
 use crate::symbols::*;
 use crate::spinlock::Mutex;
@@ -3,18 +3,22 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use crate::{page, info};
+use crate::{info, warn};
 use crate::symbols::*;
 use crate::mem;
 use crate::arch;
 use crate::trap::usertrapret;
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, Ordering};
 use crate::process::{my_proc, PROCS_POOL, ProcInPool, Register, put_back_proc, sched, TrapFrame};
 use crate::page::{Page, Table, EntryAttributes};
 use crate::spinlock::{Mutex, MutexGuard};
 use alloc::sync::Arc;
 use crate::file::{File, FsFile};
 use crate::process::context::{Context, ContextRegisters};
+use crate::process::{Access, Backing, Vma, Vmas};
+use crate::asid::{NO_ASID, asid_of};
+use crate::syscall::{ENOENT, ENOEXEC, ENOMEM};
 
 #[derive(PartialEq)]
 #[derive(Debug)]
@@ -30,6 +34,10 @@ pub enum ProcessState {
 #[repr(align(4096))]
 pub struct Process {
     pub pgtable: Box<Table>,
+    /// Areas of user memory, populated in `pgtable` on demand
+    pub vmas: Vmas,
+    /// ASID with its generation, see `asid`
+    pub asid: u64,
     pub trapframe: Box<TrapFrame>,
     pub context: Box<Context>,
     pub state: ProcessState,
@@ -39,23 +47,34 @@ pub struct Process {
     pub channel: usize,
     pub drop_on_put_back: Option<MutexGuard<'static, ()>>,
     pub files: [Option<Arc<File>>; 256],
+    /// Start of heap, after program image
+    pub heap_start: usize,
+    /// Program break, end of heap
+    pub brk: usize,
+    /// Maximum size of user stack, applied at exec
+    pub stack_limit: usize,
 }
 
 impl Process {
-    pub fn new(pid: i32) -> Self {
-        Self::from_exist(pid, Box::new(Table::new()), Box::new(TrapFrame::zero()))
+    /// Returns `None` if memory is low
+    pub fn new(pid: i32) -> Option<Self> {
+        Self::from_exist(pid, Table::try_new()?, Box::new(TrapFrame::zero()))
     }
 
-    pub fn from_exist(pid: i32, pgtable: Box<Table>, trapframe: Box<TrapFrame>) -> Self {
+    /// Returns `None` if memory is low
+    pub fn from_exist(pid: i32, pgtable: Box<Table>, trapframe: Box<TrapFrame>) -> Option<Self> {
         if pid < 0 {
             panic!("invalid pid");
         }
 
-        let kstack = mem::ALLOC().lock().allocate(PAGE_SIZE * 1024) as usize;
+        let kstack = mem::ALLOC().lock().allocate_user(PAGE_SIZE * 1024)? as usize;
+        KILLED[pid as usize].store(false, Ordering::SeqCst);
 
         let mut p = Self {
             trapframe,
             pgtable,
+            vmas: Vmas::new(),
+            asid: NO_ASID,
             context: Box::new(Context::zero()),
             state: ProcessState::UNUSED,
             kstack,
@@ -64,29 +83,37 @@ impl Process {
             channel: 0,
             drop_on_put_back: None,
             files: [const { None }; 256],
+            heap_start: 0,
+            brk: 0,
+            stack_limit: USER_STACK_LIMIT,
         };
 
-        // map trampoline
-        p.pgtable.kernel_map(
-            TRAMPOLINE_START,
-            TRAMPOLINE_TEXT_START(),
-            EntryAttributes::RX as usize,
-        );
-
-        let trapframe = &*p.trapframe as *const _ as usize;
-        // map trapframe
-        p.pgtable.kernel_map(
-            TRAPFRAME_START,
-            trapframe,
-            EntryAttributes::RW as usize,
-        );
+        map_kernel_pages(&mut p.pgtable, &p.trapframe);
         p.context.regs[ContextRegisters::ra as usize] = forkret as usize;
         p.context.regs[ContextRegisters::sp as usize] = p.kstack + PAGE_SIZE;
 
-        p
+        Some(p)
     }
 }
 
+/// Map trampoline and `trapframe` of process in `pgtable`
+fn map_kernel_pages(pgtable: &mut Table, trapframe: &TrapFrame) {
+    // map trampoline
+    pgtable.kernel_map(
+        TRAMPOLINE_START,
+        TRAMPOLINE_TEXT_START(),
+        EntryAttributes::RX as usize,
+    );
+
+    let trapframe = trapframe as *const _ as usize;
+    // map trapframe
+    pgtable.kernel_map(
+        TRAPFRAME_START,
+        trapframe,
+        EntryAttributes::RW as usize,
+    );
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
         let _kstack = unsafe { Box::from_raw(self.kstack as *mut Page) };
@@ -95,6 +122,12 @@ impl Drop for Process {
 
 #[no_mangle]
 pub extern "C" fn forkret() -> ! {
+    static FIRST: AtomicBool = AtomicBool::new(true);
+    // disk I/O sleeps, so disks are scanned in first process
+    if FIRST.swap(false, Ordering::SeqCst) {
+        crate::block::init();
+        crate::fs::mount_disks();
+    }
     usertrapret()
 }
 
@@ -108,14 +141,18 @@ fn init_code() -> &'static [u8] {
 
 /// Put init process into `PROCS_POOL`
 pub fn init_proc() {
-    let mut p = Process::new(0);
+    let mut p = Process::new(0).expect("no memory for init");
     // map init code
     let content = init_code();
     let mut page = Page::new();
     page.data[0..content.len()].copy_from_slice(content);
     p.pgtable.map(0, page, EntryAttributes::URX as usize);
     // map user stack
-    let sp = map_stack(&mut p.pgtable, 0x80001000);
+    let sp = map_stack(&mut p.vmas, p.stack_limit);
+    assert!(p.vmas.add(Vma::new(0, PAGE_SIZE, EntryAttributes::RX as usize, Backing::Zero)));
+    assert!(map_heap(&mut p.vmas, PAGE_SIZE));
+    p.heap_start = PAGE_SIZE;
+    p.brk = PAGE_SIZE;
     p.trapframe.epc = 0;
     p.trapframe.regs[Register::sp as usize] = sp;
     p.state = ProcessState::RUNNABLE;
@@ -130,82 +167,170 @@ pub fn find_available_pid() -> Option<i32> {
     None
 }
 
+/// fork syscall. Returns pid of child, -1 if no pid is available, or
+/// `-ENOMEM` if memory is low.
 pub fn fork() -> i32 {
     let p = my_proc();
-    let f_pid = find_available_pid();
-    if f_pid.is_none() {
-        panic!("pid unavailable");
-    }
-    let f_pid = f_pid.unwrap();
-    let pgtable = p.pgtable.clone();
+    let f_pid = match find_available_pid() {
+        Some(pid) => pid,
+        None => { return -1; }
+    };
+    let pgtable = match p.pgtable.try_clone() {
+        Some(pgtable) => pgtable,
+        None => { return -ENOMEM; }
+    };
     let trapframe = Box::new(*p.trapframe.clone());
-    let mut fork_p = Process::from_exist(f_pid, pgtable, trapframe);
+    let mut fork_p = match Process::from_exist(f_pid, pgtable, trapframe) {
+        Some(fork_p) => fork_p,
+        None => { return -ENOMEM; }
+    };
     for i in 0..fork_p.files.len() {
         fork_p.files[i] = p.files[i].as_ref().cloned()
     }
+    fork_p.vmas = p.vmas.clone();
+    fork_p.heap_start = p.heap_start;
+    fork_p.brk = p.brk;
+    fork_p.stack_limit = p.stack_limit;
     fork_p.trapframe.regs[Register::a0 as usize] = 0;
     fork_p.state = ProcessState::RUNNABLE;
     put_back_proc(Box::new(fork_p));
     f_pid
 }
 
-pub const USER_STACK_PAGE: usize = 4;
-
-/// map user stack in `pgtable` at `stack_begin` and returns `sp`
-pub fn map_stack(pgtable: &mut Table, stack_begin: usize) -> usize {
-    for i in 0..USER_STACK_PAGE {
-        let stack = Page::new();
-        pgtable.map(
-            stack_begin + i * PAGE_SIZE,
-            stack,
-            EntryAttributes::URW as usize,
-        );
-    }
+/// map user stack in empty `vmas` below `USER_STACK_TOP`, growing down to
+/// `limit` bytes, and returns `sp`
+pub fn map_stack(vmas: &mut Vmas, limit: usize) -> usize {
+    assert!(vmas.add(Vma::stack(USER_STACK_TOP, limit)));
+    USER_STACK_TOP
+}
 
-    stack_begin + PAGE_SIZE * USER_STACK_PAGE
+/// Begin empty heap in `vmas` at `heap_start`. Returns `false` if it's in
+/// another area.
+fn map_heap(vmas: &mut Vmas, heap_start: usize) -> bool {
+    vmas.add(Vma::new(heap_start, heap_start, EntryAttributes::RW as usize, Backing::Zero))
 }
 
-/// exec syscall
-pub fn exec(path: &str) {
+/// exec syscall. Only areas of new image are set up, and its pages are
+/// loaded on first touch. Returns `-ENOENT` if `path` is not found,
+/// `-ENOEXEC` if it's not a valid ELF file, or `-ENOMEM` if memory is low,
+/// in which cases current image is kept.
+pub fn exec(path: &str) -> i32 {
     let p = my_proc();
     info!("loading elf {}", path);
-    let mut content: Box<[u8; 131072]> = Box::new([0; 131072]);
-    {
-        let f = FsFile::open(path, 0);
-        // info!("FsFile: size = {}", f.sz);
-        let mut blk = [0; 1024];
-        let mut i = 0;
-        while f.read(&mut blk) == 1024 {
-            // content[i..i + 1024].copy_from_slice(&blk);
-            for j in 0..1024 {
-                content[i + j] = blk[j];
-            }
-            i += 1024;
-            if i + 1024 >= content.len() {
-                panic!("elf file too large!");
+    let f = match FsFile::open(path, 0) {
+        Some(f) => f,
+        None => { return -ENOENT; }
+    };
+    let mut vmas = Vmas::new();
+    // map user stack first, so that segments can't take its space
+    let sp = map_stack(&mut vmas, p.stack_limit);
+    let image = match crate::elf::parse_elf(&f.inode, &mut vmas) {
+        Ok(image) if map_heap(&mut vmas, image.end) => image,
+        Ok(_) => {
+            warn!("exec {}: no space for heap", path);
+            return -ENOEXEC;
+        }
+        Err(err) => {
+            warn!("exec {}: {:?}", path, err);
+            return -ENOEXEC;
+        }
+    };
+    let mut pgtable = match Table::try_new() {
+        Some(pgtable) => pgtable,
+        None => { return -ENOMEM; }
+    };
+    map_kernel_pages(&mut pgtable, &p.trapframe);
+    // old image is dropped
+    p.pgtable = pgtable;
+    p.vmas = vmas;
+    // this and other harts may have run this process with old mappings
+    crate::ipi::flush_tlb(None, Some(asid_of(p.asid)));
+    p.heap_start = image.end;
+    p.brk = image.end;
+    p.trapframe.epc = image.entry as usize;
+    p.trapframe.regs[Register::sp as usize] = sp;
+    0
+}
+
+/// sbrk syscall. Grows or shrinks heap by `increment` bytes, whose pages
+/// are allocated on first touch. Returns previous program break, -1 if
+/// heap would shrink below its start or grow into another area, or
+/// `-ENOMEM` if there's not enough free memory for the growth. The break
+/// is returned in full width, as it may not fit in `i32`.
+pub fn sbrk(increment: isize) -> isize {
+    let p = my_proc();
+    let old = p.brk;
+    let new = match old.checked_add_signed(increment) {
+        Some(new) if new >= p.heap_start => new,
+        _ => { return -1; }
+    };
+    let old_end = mem::align_val(old, PAGE_ORDER);
+    let new_end = mem::align_val(new, PAGE_ORDER);
+    if new_end > old_end && (new_end - old_end) / PAGE_SIZE > mem::ALLOC().lock().user_available() {
+        return -ENOMEM as isize;
+    }
+    if !p.vmas.resize(p.heap_start, new_end) {
+        return -1;
+    }
+    if new_end < old_end {
+        for vaddr in (new_end..old_end).step_by(PAGE_SIZE) {
+            p.pgtable.unmap(vaddr);
+        }
+        crate::ipi::flush_tlb(None, Some(asid_of(p.asid)));
+    }
+    p.brk = new;
+    old as isize
+}
+
+/// Resource of maximum size of user stack, in bytes
+pub const RLIMIT_STACK: usize = 3;
+
+/// setrlimit syscall. Sets `resource` of current process to `limit`, which
+/// is inherited on fork. Stack limit is rounded up to pages, and takes
+/// effect at next exec. Returns -1 for unknown `resource` or bad `limit`.
+pub fn setrlimit(resource: usize, limit: usize) -> i32 {
+    let p = my_proc();
+    match resource {
+        RLIMIT_STACK => {
+            let limit = match limit.checked_add(PAGE_SIZE - 1) {
+                Some(limit) => limit & !(PAGE_SIZE - 1),
+                None => { return -1; }
+            };
+            // guard region should fit below stack
+            if limit == 0 || limit > USER_STACK_TOP - USER_STACK_GUARD {
+                return -1;
             }
+            p.stack_limit = limit;
+            0
         }
+        _ => -1
     }
-    info!("parsing...");
-    p.pgtable.unmap_user();
-    let entry = crate::elf::parse_elf(
-        &*content,
-        &mut p.pgtable,
-    );
-    info!("done");
-    // map user stack
-    let sp = map_stack(&mut p.pgtable, 0x80001000);
-    p.trapframe.epc = entry as usize;
-    p.trapframe.regs[Register::sp as usize] = sp;
 }
 
-/// exit syscall
-pub fn exit(_status: i32) -> ! {
+/// Resolve page fault of current process on `vaddr`. The process is
+/// killed if it can't be resolved.
+pub fn page_fault(vaddr: usize, access: Access) {
+    let p = my_proc();
+    match p.vmas.fault(&mut p.pgtable, vaddr, access) {
+        // this hart may have cached the page as invalid
+        Ok(_) => crate::ipi::sfence_vma(Some(mem::page_down(vaddr)), Some(asid_of(p.asid))),
+        Err(fault) => {
+            warn!("process {}: {:?} on {:?} of {:#x}, epc {:#x}", p.pid, fault, access, vaddr, p.trapframe.epc);
+            KILLED[p.pid as usize].store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// exit syscall. When init exits, machine is powered off with its status.
+pub fn exit(status: i32) -> ! {
     {
         let p = my_proc();
         if p.pid == 0 {
-            panic!("init exiting");
+            info!("init exited with {}", status);
+            crate::power::exit(status as u16);
         }
+        // nobody waits for it, so free its memory now
+        p.pgtable.unmap_user();
         p.state = ProcessState::ZOMBIE;
     }
     arch::intr_off();
@@ -258,6 +383,71 @@ pub fn sleep<T, U>(channel: *const T, lck: MutexGuard<U>) -> MutexGuard<U> {
     return weak_lock.into_guard();
 }
 
+/// Whether each pid is killed, checked before returning to user space
+static KILLED: [AtomicBool; NMAXPROCS] = [const { AtomicBool::new(false) }; NMAXPROCS];
+
+/// Kill process `pid`. If it is sleeping, it is woken up, so that
+/// it exits once back to user space. Returns -1 if there's no such process,
+/// or if `pid` is init, which never exits.
+pub fn kill(pid: i32) -> i32 {
+    if pid <= 0 || pid as usize >= NMAXPROCS {
+        return -1;
+    }
+    let mut pool = PROCS_POOL.lock();
+    loop {
+        match &mut pool[pid as usize] {
+            ProcInPool::NoProc => { return -1; }
+            ProcInPool::Pooling(p) => {
+                if p.state == ProcessState::ZOMBIE {
+                    return -1;
+                }
+                KILLED[pid as usize].store(true, Ordering::SeqCst);
+                if p.state == ProcessState::SLEEPING {
+                    p.state = ProcessState::RUNNABLE;
+                }
+                return 0;
+            }
+            ProcInPool::Scheduled => {
+                KILLED[pid as usize].store(true, Ordering::SeqCst);
+                return 0;
+            }
+            ProcInPool::BeingSlept => {
+                // wait until it is put back, as in `wakeup`
+                let weak_lock = pool.into_weak();
+                PROCS_POOL_SLEEP.lock();
+                pool = weak_lock.into_guard();
+            }
+        }
+    }
+}
+
+/// Whether process `pid` is killed
+pub fn killed(pid: i32) -> bool {
+    KILLED[pid as usize].load(Ordering::SeqCst)
+}
+
+/// When kernel has run into reserved memory, kill the process using most
+/// user pages, other than init, so that its memory is freed once it exits.
+/// Only processes in `PROCS_POOL` are considered, not those running on
+/// other harts. Should be called without holding locks, e.g. by scheduler.
+pub fn oom_kill() {
+    if !mem::take_low_memory() {
+        return;
+    }
+    let victim = PROCS_POOL.lock().iter().filter_map(|p| match p {
+        ProcInPool::Pooling(p) if p.pid != 0 && p.state != ProcessState::ZOMBIE && !killed(p.pid) =>
+            Some((p.pgtable.user_pages(), p.pid)),
+        _ => None
+    }).max();
+    match victim {
+        Some((pages, pid)) => {
+            warn!("out of memory, killing process {} of {} pages", pid, pages);
+            kill(pid);
+        }
+        None => warn!("out of memory, but no process to kill"),
+    }
+}
+
 /// wakeup process on channel
 ///
 /// `channel` is an identifier of sleep lock channel. Should be the same as in `sleep`.
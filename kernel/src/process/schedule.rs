@@ -3,9 +3,9 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-use crate::{arch};
+use crate::{arch, timer};
 use crate::symbols::*;
-use crate::process::{ProcInPool, PROCS_POOL, ProcessState, swtch, Context, my_cpu, Process};
+use crate::process::{ProcInPool, PROCS_POOL, ProcessState, swtch, Context, my_cpu, Process, oom_kill};
 use alloc::boxed::Box;
 
 /// Find a runnable process whose pid >= `from_pid`
@@ -45,13 +45,16 @@ pub fn scheduler() -> ! {
     // info!("scheduling on {}", arch::hart_id());
     loop {
         arch::intr_on();
+        oom_kill();
         if let Some(p) = find_next_runnable_proc(lst_pid) {
             c.process = Some(p);
             let p = c.process.as_mut().unwrap();
             p.state = ProcessState::RUNNING;
             let ctx = core::mem::replace(&mut p.context, Box::new(Context::zero()));
             // info!("scheduler {}: switching to {}", arch::hart_id(), p.pid);
+            timer::start_slice();
             swtch(&mut c.scheduler_context, *ctx);
+            timer::stop_slice();
             // info!("scheduler {}: come back", arch::hart_id());
             let p = c.process.take().unwrap();
             lst_pid = p.pid as usize + 1;
@@ -60,8 +63,11 @@ pub fn scheduler() -> ! {
             }
             // info!("put back...");
             put_back_proc(p);
-        } else {
+        } else if lst_pid != 0 {
             lst_pid = 0;
+        } else {
+            // nothing to run, wait for interrupt without time slice
+            timer::idle();
         }
     }
 }
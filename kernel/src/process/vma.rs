@@ -0,0 +1,271 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Virtual memory areas of user space
+//!
+//! User memory is described by areas, and pages are only allocated when
+//! they're first touched, by user in page fault or by kernel in syscall.
+//! Zero-filled areas back bss, heap and stack. File-backed areas back ELF
+//! segments, whose pages are read from the file on first touch, with the
+//! part beyond file size zero-filled.
+//!
+//! Stack area grows down when a page below it is touched, up to its limit.
+//! Below the limit is a guard region where no other area may be placed,
+//! so that stack overflow faults, and the process is killed.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::fs::Inode;
+use crate::mem::page_down;
+use crate::page::{EntryAttributes, Page, Table};
+use crate::symbols::{PAGE_SIZE, USER_STACK_GUARD};
+
+/// Kind of access to user memory
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Why a page fault can't be resolved
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    /// No area at the address, or access is not allowed
+    Segfault,
+    /// Access to guard region below stack at its limit
+    StackOverflow,
+    /// No memory for the page
+    OutOfMemory,
+}
+
+/// Content of pages in an area
+#[derive(Clone)]
+pub enum Backing {
+    /// Zero-filled
+    Zero,
+    /// `size` bytes from `offset` of file, then zero-filled
+    File { inode: Arc<dyn Inode>, offset: usize, size: usize },
+}
+
+/// A page-aligned range of user memory
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    /// Page table entry flags of its pages
+    pub flags: usize,
+    pub backing: Backing,
+    /// Lowest `start` of area growing down, `None` if it doesn't grow
+    pub grow_limit: Option<usize>,
+}
+
+impl Vma {
+    pub fn new(start: usize, end: usize, flags: usize, backing: Backing) -> Self {
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 || start > end {
+            panic!("invalid vma {:x}-{:x}", start, end);
+        }
+        Self { start, end, flags: flags | EntryAttributes::U as usize, backing, grow_limit: None }
+    }
+
+    /// Zero-filled stack of one page below `top`, growing down to `limit`
+    /// bytes
+    pub fn stack(top: usize, limit: usize) -> Self {
+        let mut vma = Self::new(top - PAGE_SIZE, top, EntryAttributes::RW as usize, Backing::Zero);
+        vma.grow_limit = Some(top - limit);
+        vma
+    }
+
+    /// Start of address range taken by this area, including space it may
+    /// grow into and the guard region below
+    fn reserved_start(&self) -> usize {
+        match self.grow_limit {
+            Some(limit) => limit.saturating_sub(USER_STACK_GUARD),
+            None => self.start,
+        }
+    }
+
+    pub fn contains(&self, vaddr: usize) -> bool {
+        self.start <= vaddr && vaddr < self.end
+    }
+
+    /// Whether `access` is allowed
+    pub fn allows(&self, access: Access) -> bool {
+        let bit = match access {
+            Access::Read => EntryAttributes::R,
+            Access::Write => EntryAttributes::W,
+            Access::Execute => EntryAttributes::X,
+        };
+        self.flags & bit as usize != 0
+    }
+
+    /// Fill zeroed `page` to be mapped at `vaddr`
+    fn fill(&self, page: &mut Page, vaddr: usize) {
+        if let Backing::File { inode, offset, size } = &self.backing {
+            let off = vaddr - self.start;
+            if off < *size {
+                let len = (size - off).min(PAGE_SIZE);
+                // short read leaves the rest zero
+                inode.read_at(offset + off, &mut page.data[..len]);
+            }
+        }
+    }
+}
+
+/// Areas of a process, sorted by start address
+#[derive(Clone)]
+pub struct Vmas {
+    areas: Vec<Vma>,
+}
+
+impl Vmas {
+    pub const fn new() -> Self {
+        Self { areas: Vec::new() }
+    }
+
+    /// Add `vma`. Returns `false` if it overlaps others, or space reserved
+    /// below stack.
+    pub fn add(&mut self, vma: Vma) -> bool {
+        let pos = self.areas.iter().position(|a| a.start >= vma.start).unwrap_or(self.areas.len());
+        let overlaps_prev = pos > 0 && self.areas[pos - 1].end > vma.reserved_start();
+        let overlaps_next = pos < self.areas.len() && self.areas[pos].reserved_start() < vma.end;
+        if overlaps_prev || overlaps_next {
+            return false;
+        }
+        self.areas.insert(pos, vma);
+        true
+    }
+
+    /// Area containing `vaddr`
+    pub fn find(&self, vaddr: usize) -> Option<&Vma> {
+        self.areas.iter().find(|a| a.contains(vaddr))
+    }
+
+    /// Move end of area starting at `start` to `end`. Returns `false` if
+    /// there's no such area, or it would overlap the next one, including
+    /// space reserved below stack.
+    pub fn resize(&mut self, start: usize, end: usize) -> bool {
+        let pos = match self.areas.iter().position(|a| a.start == start) {
+            Some(pos) => pos,
+            None => { return false; }
+        };
+        if end < start || end % PAGE_SIZE != 0 {
+            return false;
+        }
+        if pos + 1 < self.areas.len() && self.areas[pos + 1].reserved_start() < end {
+            return false;
+        }
+        self.areas[pos].end = end;
+        true
+    }
+
+    /// Grow stack down to page of `vaddr`, if it's below a stack. Returns
+    /// index of the stack.
+    fn grow(&mut self, vaddr: usize) -> Result<usize, Fault> {
+        let pos = self.areas.iter()
+            .position(|a| a.grow_limit.is_some() && a.reserved_start() <= vaddr && vaddr < a.start)
+            .ok_or(Fault::Segfault)?;
+        let vma = &mut self.areas[pos];
+        if vaddr < vma.grow_limit.unwrap() {
+            return Err(Fault::StackOverflow);
+        }
+        vma.start = page_down(vaddr);
+        Ok(pos)
+    }
+
+    /// Resolve `access` to `vaddr`, populating its page in `pgtable` if it
+    /// is not yet, and growing stack if it's below one. Returns physical
+    /// address of the page.
+    pub fn fault(&mut self, pgtable: &mut Table, vaddr: usize, access: Access) -> Result<usize, Fault> {
+        let pos = match self.areas.iter().position(|a| a.contains(vaddr)) {
+            Some(pos) => pos,
+            None => self.grow(vaddr)?,
+        };
+        let vma = &self.areas[pos];
+        if !vma.allows(access) {
+            return Err(Fault::Segfault);
+        }
+        let vaddr = page_down(vaddr);
+        if let Some(paddr) = pgtable.paddr_of(vaddr) {
+            return Ok(paddr);
+        }
+        let mut page = Page::try_new().ok_or(Fault::OutOfMemory)?;
+        vma.fill(&mut page, vaddr);
+        let paddr = &*page as *const Page as usize;
+        pgtable.map(vaddr, page, vma.flags);
+        Ok(paddr)
+    }
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("zero fill", test_zero_fill),
+            ("areas", test_areas),
+            ("stack", test_stack),
+        ]
+    }
+
+    /// Test that pages are populated on first touch, and checked against
+    /// area flags
+    pub fn test_zero_fill() {
+        let mut vmas = Vmas::new();
+        assert!(vmas.add(Vma::new(0x10000, 0x12000, EntryAttributes::RW as usize, Backing::Zero)));
+        let mut pgtable = Box::new(Table::new());
+        assert_eq!(pgtable.user_pages(), 0);
+        let paddr = vmas.fault(&mut pgtable, 0x11008, Access::Write).unwrap();
+        assert_eq!(pgtable.user_pages(), 1);
+        assert_eq!(pgtable.paddr_of(0x11000), Some(paddr));
+        assert!(unsafe { (*(paddr as *const Page)).data.iter().all(|&b| b == 0) });
+        // populated once
+        assert_eq!(vmas.fault(&mut pgtable, 0x11000, Access::Read), Ok(paddr));
+        assert_eq!(vmas.fault(&mut pgtable, 0x10000, Access::Execute), Err(Fault::Segfault));
+        assert_eq!(vmas.fault(&mut pgtable, 0x12000, Access::Read), Err(Fault::Segfault));
+        assert_eq!(pgtable.user_pages(), 1);
+    }
+
+    /// Test adding and resizing areas
+    pub fn test_areas() {
+        let mut vmas = Vmas::new();
+        assert!(vmas.add(Vma::new(0x20000, 0x20000, EntryAttributes::RW as usize, Backing::Zero)));
+        assert!(vmas.add(Vma::new(0x10000, 0x11000, EntryAttributes::RX as usize, Backing::Zero)));
+        assert!(vmas.add(Vma::new(0x30000, 0x31000, EntryAttributes::RW as usize, Backing::Zero)));
+        assert!(vmas.find(0x20000).is_none());
+        assert!(!vmas.add(Vma::new(0x11000, 0x21000, EntryAttributes::RW as usize, Backing::Zero)));
+        assert!(!vmas.add(Vma::new(0x30000, 0x31000, EntryAttributes::RW as usize, Backing::Zero)));
+        assert!(vmas.resize(0x20000, 0x30000));
+        assert_eq!(vmas.find(0x2f000).unwrap().start, 0x20000);
+        assert!(!vmas.resize(0x20000, 0x31000));
+        assert!(vmas.resize(0x20000, 0x20000));
+        assert!(vmas.find(0x20000).is_none());
+    }
+
+    /// Test growing stack down to its limit, and the guard region below it
+    pub fn test_stack() {
+        let top = 0x100000;
+        let mut vmas = Vmas::new();
+        assert!(vmas.add(Vma::new(0x10000, 0x10000, EntryAttributes::RW as usize, Backing::Zero)));
+        assert!(vmas.add(Vma::stack(top, 4 * PAGE_SIZE)));
+        let mut pgtable = Box::new(Table::new());
+        vmas.fault(&mut pgtable, top - 8, Access::Write).unwrap();
+        assert!(vmas.find(top - 2 * PAGE_SIZE).is_none());
+        vmas.fault(&mut pgtable, top - 3 * PAGE_SIZE + 8, Access::Write).unwrap();
+        assert_eq!(vmas.find(top - 2 * PAGE_SIZE).unwrap().start, top - 3 * PAGE_SIZE);
+        // only touched pages are populated
+        assert_eq!(pgtable.user_pages(), 2);
+        vmas.fault(&mut pgtable, top - 4 * PAGE_SIZE, Access::Read).unwrap();
+        assert_eq!(vmas.fault(&mut pgtable, top - 4 * PAGE_SIZE - 8, Access::Write), Err(Fault::StackOverflow));
+        let guard_start = top - 4 * PAGE_SIZE - USER_STACK_GUARD;
+        assert_eq!(vmas.fault(&mut pgtable, guard_start, Access::Write), Err(Fault::StackOverflow));
+        assert_eq!(vmas.fault(&mut pgtable, guard_start - 8, Access::Write), Err(Fault::Segfault));
+        // heap can't grow into guard region, nor can areas be added there
+        assert!(!vmas.resize(0x10000, guard_start + PAGE_SIZE));
+        assert!(!vmas.add(Vma::new(guard_start, guard_start + PAGE_SIZE, EntryAttributes::RW as usize, Backing::Zero)));
+        assert!(vmas.resize(0x10000, guard_start));
+    }
+}
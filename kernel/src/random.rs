@@ -0,0 +1,254 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Kernel random number generator
+//!
+//! Output is generated by ChaCha20 under a 256-bit key. After each request
+//! the key is replaced with fresh generator output, so earlier output can't
+//! be recovered from current state. Entropy is mixed into the key at boot
+//! from timer jitter and the hardware entropy source, and the hardware source
+//! is consulted again every `RESEED_INTERVAL` bytes.
+
+use alloc::sync::Arc;
+use crate::arch::time;
+use crate::process::my_cpu;
+use crate::spinlock::Mutex;
+
+/// Hardware entropy source, such as virtio-rng
+pub trait EntropySource: Send + Sync {
+    /// Fill `buf` with random bytes, returns number of bytes filled
+    fn fill(&self, buf: &mut [u8]) -> usize;
+}
+
+/// Bytes to generate before reseeding from entropy source
+const RESEED_INTERVAL: usize = 1 << 20;
+
+/// Size of seed taken from entropy source
+const SEED_SIZE: usize = 32;
+
+/// Rounds of timer jitter sampling, each yielding a few bits at most
+const JITTER_ROUNDS: usize = 1024;
+
+/// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+/// ChaCha20 block of `key` at `counter` with zero nonce
+pub fn chacha20_block(key: &[u32; 8], counter: u64) -> [u8; 64] {
+    let mut init = [0u32; 16];
+    init[..4].copy_from_slice(&SIGMA);
+    init[4..12].copy_from_slice(key);
+    init[12] = counter as u32;
+    init[13] = (counter >> 32) as u32;
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0; 64];
+    for i in 0..16 {
+        out[i * 4..i * 4 + 4].copy_from_slice(&s[i].wrapping_add(init[i]).to_le_bytes());
+    }
+    out
+}
+
+/// State of generator
+pub struct Rng {
+    key: [u32; 8],
+    /// Whether any entropy source has been mixed in
+    seeded: bool,
+    /// Bytes generated since last reseed from entropy source
+    generated: usize,
+}
+
+impl Rng {
+    pub const fn new() -> Self {
+        Self { key: [0; 8], seeded: false, generated: 0 }
+    }
+
+    /// Replace key with generator output
+    fn rekey(&mut self, counter: u64) {
+        let block = chacha20_block(&self.key, counter);
+        for i in 0..8 {
+            self.key[i] = u32::from_le_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+    }
+
+    /// Mix `data` into key
+    pub fn add_entropy(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            for (i, b) in chunk.iter().enumerate() {
+                self.key[i / 4] ^= (*b as u32) << (i % 4 * 8);
+            }
+            self.rekey(0);
+        }
+    }
+
+    /// Fill `buf` with generator output
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        let mut counter = 0;
+        for chunk in buf.chunks_mut(64) {
+            let block = chacha20_block(&self.key, counter);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+            counter += 1;
+        }
+        self.rekey(counter);
+        self.generated += buf.len();
+    }
+}
+
+static RNG: Mutex<Rng> = Mutex::new(Rng::new(), "random");
+
+/// Entropy source registered by driver, only modified at boot
+static mut SOURCE: Option<Arc<dyn EntropySource>> = None;
+
+/// Register `source` as hardware entropy source
+pub fn register_source(source: Arc<dyn EntropySource>) {
+    unsafe { SOURCE = Some(source); }
+}
+
+/// Collect entropy from differences of timer readings around busy loops,
+/// whose length varies with cache and bus activity
+fn timer_jitter(buf: &mut [u8]) {
+    let mut last = time().as_nanos() as u64;
+    let mut acc: u64 = 0;
+    for round in 0..JITTER_ROUNDS {
+        let mut x = last as usize;
+        for _ in 0..(last as usize & 0x3f) + 16 {
+            x = unsafe { core::ptr::read_volatile(&x) }.wrapping_mul(31).wrapping_add(1);
+        }
+        let now = time().as_nanos() as u64;
+        acc = acc.rotate_left(7) ^ now.wrapping_sub(last) ^ x as u64;
+        last = now;
+        buf[round % buf.len()] ^= acc as u8;
+    }
+}
+
+/// Take seed from entropy source and mix it into generator.
+/// Returns whether source is available.
+fn reseed() -> bool {
+    let source = match unsafe { &SOURCE } {
+        Some(source) => source,
+        None => { return false; }
+    };
+    let mut seed = [0; SEED_SIZE];
+    // source may sleep, so generator is not locked
+    let len = source.fill(&mut seed);
+    let mut rng = RNG.lock();
+    rng.add_entropy(&seed[..len]);
+    rng.generated = 0;
+    rng.seeded |= len != 0;
+    len != 0
+}
+
+/// Seed generator from timer jitter and entropy source.
+///
+/// Should be called in booting hart after drivers are initialized.
+pub fn init() -> bool {
+    let mut seed = [0; SEED_SIZE];
+    timer_jitter(&mut seed);
+    {
+        let mut rng = RNG.lock();
+        rng.add_entropy(&seed);
+        rng.seeded = true;
+    }
+    reseed()
+}
+
+/// Mix `data` into generator, such as bytes written to `/dev/random`
+pub fn add_entropy(data: &[u8]) {
+    RNG.lock().add_entropy(data);
+}
+
+/// Fill `buf` with random bytes
+pub fn fill(buf: &mut [u8]) {
+    let stale = {
+        let rng = RNG.lock();
+        assert!(rng.seeded, "random: not seeded");
+        rng.generated >= RESEED_INTERVAL
+    };
+    // reseeding may sleep, which is only possible in process
+    if stale && my_cpu().process.is_some() {
+        reseed();
+    }
+    RNG.lock().fill(buf);
+}
+
+pub mod tests {
+    use super::*;
+    use crate::info;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("chacha20", test_chacha20),
+            ("key erasure", test_key_erasure),
+            ("fill", test_fill),
+            ("entropy source", test_source),
+        ]
+    }
+
+    /// Test ChaCha20 block with all-zero key and counter (RFC 7539 A.1 #1)
+    pub fn test_chacha20() {
+        let expected = [
+            0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86, 0xbd, 0x28,
+            0xbd, 0xd2, 0x19, 0xb8, 0xa0, 0x8d, 0xed, 0x1a, 0xa8, 0x36, 0xef, 0xcc, 0x8b, 0x77, 0x0d, 0xc7,
+            0xda, 0x41, 0x59, 0x7c, 0x51, 0x57, 0x48, 0x8d, 0x77, 0x24, 0xe0, 0x3f, 0xb8, 0xd8, 0x4a, 0x37,
+            0x6a, 0x43, 0xb8, 0xf4, 0x15, 0x18, 0xa1, 0x1c, 0xc3, 0x87, 0xb6, 0x69, 0xb2, 0xee, 0x65, 0x86,
+        ];
+        assert!(chacha20_block(&[0; 8], 0) == expected);
+        assert!(chacha20_block(&[0; 8], 1) != expected);
+    }
+
+    /// Test that output differs after each request
+    pub fn test_key_erasure() {
+        let mut rng = Rng::new();
+        let mut a = [0; 16];
+        let mut b = [0; 16];
+        rng.fill(&mut a);
+        rng.fill(&mut b);
+        assert!(a != b);
+        let mut other = Rng::new();
+        other.add_entropy(b"seed");
+        let mut c = [0; 16];
+        other.fill(&mut c);
+        assert!(a != c);
+    }
+
+    /// Test kernel generator
+    pub fn test_fill() {
+        let mut a = [0u8; 100];
+        let mut b = [0u8; 100];
+        fill(&mut a);
+        fill(&mut b);
+        assert!(a != b);
+        assert!(a.iter().any(|x| *x != 0));
+    }
+
+    /// Test reading hardware entropy source
+    pub fn test_source() {
+        let source = match unsafe { &SOURCE } {
+            Some(source) => source,
+            None => {
+                info!("      no entropy source, skipped");
+                return;
+            }
+        };
+        let mut buf = [0u8; 64];
+        let len = source.fill(&mut buf);
+        assert!(len > 0 && len <= buf.len());
+    }
+}
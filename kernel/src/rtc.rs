@@ -0,0 +1,106 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Goldfish real-time clock
+//!
+//! RTC is read once at boot to get the wall-clock time when `mtime` was
+//! zero. Afterwards, real time is computed from `mtime`, which is cheaper
+//! to read and never goes backwards.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use crate::arch;
+
+/// Default Goldfish RTC base address on QEMU RISC-V, used if not in FDT
+pub const RTC_BASE: usize = 0x10_1000;
+
+/// Low 32 bits of nanoseconds since Unix epoch. Reading it latches high bits.
+const RTC_TIME_LOW: usize = 0x00;
+/// High 32 bits of nanoseconds since Unix epoch
+const RTC_TIME_HIGH: usize = 0x04;
+
+/// Clock of wall-clock time
+pub const CLOCK_REALTIME: usize = 0;
+/// Clock of time since boot
+pub const CLOCK_MONOTONIC: usize = 1;
+
+/// Nanoseconds since Unix epoch when `mtime` was zero
+static BOOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Read nanoseconds since Unix epoch from RTC
+pub fn read() -> u64 {
+    let base = crate::fdt::machine().rtc.base;
+    unsafe {
+        let low = ((base + RTC_TIME_LOW) as *const u32).read_volatile();
+        let high = ((base + RTC_TIME_HIGH) as *const u32).read_volatile();
+        (high as u64) << 32 | low as u64
+    }
+}
+
+/// Record boot epoch. Should be called in booting hart before
+/// anything is logged.
+pub fn init() {
+    let now = read();
+    let uptime = arch::time().as_nanos() as u64;
+    BOOT_EPOCH.store(now.saturating_sub(uptime), Ordering::SeqCst);
+}
+
+/// Wall-clock time since Unix epoch
+pub fn realtime() -> Duration {
+    Duration::from_nanos(BOOT_EPOCH.load(Ordering::Relaxed)) + arch::time()
+}
+
+/// Time of `clock`, `None` if clock is not supported
+pub fn clock(clock: usize) -> Option<Duration> {
+    match clock {
+        CLOCK_REALTIME => Some(realtime()),
+        CLOCK_MONOTONIC => Some(arch::time()),
+        _ => None
+    }
+}
+
+/// Convert seconds since Unix epoch into UTC
+/// (year, month, day, hour, minute, second)
+pub fn civil(secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+    // days since 0000-03-01, so that leap day is the last day of year
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("civil", test_civil),
+            ("realtime", test_realtime),
+        ]
+    }
+
+    /// Test conversion to UTC
+    pub fn test_civil() {
+        assert_eq!(civil(0), (1970, 1, 1, 0, 0, 0));
+        assert_eq!(civil(951782400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(civil(1700000000), (2023, 11, 14, 22, 13, 20));
+    }
+
+    /// Test that real time follows RTC
+    pub fn test_realtime() {
+        let rtc = read();
+        let now = realtime().as_nanos() as u64;
+        // within one second
+        assert!(now.max(rtc) - now.min(rtc) < 1_000_000_000);
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! RISC-V Supervisor Binary Interface
+//!
+//! When kernel is booted as a supervisor-mode payload, e.g. by OpenSBI,
+//! machine-mode resources are not accessible. Timer is then programmed by
+//! TIME extension, other harts are started by HSM extension and IPIs are
+//! sent by IPI extension. Console falls back to legacy SBI console when
+//! there's no UART in FDT.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Base extension
+const EID_BASE: usize = 0x10;
+/// Timer extension
+const EID_TIME: usize = 0x5449_4d45;
+/// IPI extension
+const EID_IPI: usize = 0x0073_5049;
+/// Hart state management extension
+const EID_HSM: usize = 0x0048_534d;
+/// Legacy console putchar
+const EID_LEGACY_PUTCHAR: usize = 0x01;
+/// Legacy console getchar
+const EID_LEGACY_GETCHAR: usize = 0x02;
+
+const FID_PROBE_EXTENSION: usize = 3;
+const FID_SET_TIMER: usize = 0;
+const FID_SEND_IPI: usize = 0;
+const FID_HART_START: usize = 0;
+
+/// Error returned by SBI call
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+
+/// Whether kernel runs under SBI. Set by booting hart after `.bss` is
+/// cleared. Other harts are started later, and don't clear `.bss`.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether kernel is booted as a supervisor-mode payload
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record that kernel runs under SBI. Should be called by booting hart.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Call function `fid` of extension `eid`. Returns `(error, value)`.
+fn call(eid: usize, fid: usize, arg0: usize, arg1: usize, arg2: usize) -> (isize, usize) {
+    let error: isize;
+    let value: usize;
+    unsafe {
+        asm!("ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") eid);
+    }
+    (error, value)
+}
+
+/// Whether extension `eid` is implemented
+pub fn probe(eid: usize) -> bool {
+    let (error, value) = call(EID_BASE, FID_PROBE_EXTENSION, eid, 0, 0);
+    error == 0 && value != 0
+}
+
+/// Raise supervisor timer interrupt when `time` reaches `deadline`, and
+/// clear pending one
+pub fn set_timer(deadline: u64) {
+    call(EID_TIME, FID_SET_TIMER, deadline as usize, 0, 0);
+}
+
+/// Send supervisor software interrupt to harts in `hart_mask`, whose
+/// bit 0 is hart `hart_mask_base`
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> isize {
+    call(EID_IPI, FID_SEND_IPI, hart_mask, hart_mask_base, 0).0
+}
+
+/// Start `hart` in supervisor mode at `start_addr`, with hart ID in `a0`
+/// and `opaque` in `a1`
+pub fn hart_start(hart: usize, start_addr: usize, opaque: usize) -> isize {
+    call(EID_HSM, FID_HART_START, hart, start_addr, opaque).0
+}
+
+/// Write a byte to SBI console
+pub fn console_putchar(c: u8) {
+    call(EID_LEGACY_PUTCHAR, 0, c as usize, 0, 0);
+}
+
+/// Read a byte from SBI console, `None` if there's no input
+pub fn console_getchar() -> Option<u8> {
+    let (c, _) = call(EID_LEGACY_GETCHAR, 0, 0, 0, 0);
+    if c < 0 { None } else { Some(c as u8) }
+}
+
+/// Check extensions needed by kernel, returning the first missing one
+pub fn check() -> Result<(), &'static str> {
+    if !probe(EID_TIME) {
+        return Err("TIME");
+    }
+    if !probe(EID_IPI) {
+        return Err("IPI");
+    }
+    if !probe(EID_HSM) {
+        return Err("HSM");
+    }
+    Ok(())
+}
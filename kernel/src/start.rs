@@ -1,10 +1,20 @@
 use core::arch::asm;
 use riscv::register::*;
-use crate::{clint, info, mem, plic, process, trap, uart, virtio};
+use crate::{asid, block, clint, fdt, fs, info, ipi, mem, plic, process, random, rtc, sbi, timer, trap, uart, virtio, warn};
 use crate::arch::hart_id;
+use crate::symbols::NCPUS;
+
+extern "C" {
+    /// Entry of harts started by SBI, in `boot.S`
+    fn _start_secondary();
+}
+
+/// FDT address passed by firmware. Not zero-initialized, so that it is
+/// placed in `.data` and not cleared with `.bss` by other harts.
+static mut FDT_ADDR: usize = usize::MAX;
 
 #[no_mangle]
-extern "C" fn kinit() {
+extern "C" fn kinit(_hart: usize, fdt: usize) {
     // We created kinit, which runs in super-duper mode
     // 3 called "machine mode".
     // The job of kinit() is to get us into supervisor mode
@@ -12,10 +22,29 @@ extern "C" fn kinit() {
     // Interrupts are disabled for the duration of kinit()
 
     unsafe {
+        if mhartid::read() == 0 {
+            FDT_ADDR = fdt;
+        }
         // configure Physical Memory Protection to give supervisor mode
         // access to all of physical memory.
         pmpaddr0::write(0x3fffffffffffff);
         pmpcfg0::write(0xf);
+        // allow supervisor mode to read `time`
+        mcounteren::set_tm();
+        // enable `stimecmp` of Sstc. menvcfg may not exist, in which case
+        // the trap skips to the end.
+        asm!(
+            "la {tmp}, 2f",
+            "csrrw {old}, mtvec, {tmp}",
+            "li {tmp}, 1",
+            "slli {tmp}, {tmp}, 63",
+            "csrs 0x30a, {tmp}",
+            ".align 2",
+            "2:",
+            "csrw mtvec, {old}",
+            tmp = out(reg) _,
+            old = out(reg) _,
+        );
         // next mode is supervisor mode
         mstatus::set_mpp(mstatus::MPP::Supervisor);
         // mret jump to kmain
@@ -37,35 +66,111 @@ extern "C" fn kinit() {
     }
 }
 
+/// Entry of booting hart in supervisor mode, when kernel is the payload
+/// of SBI firmware. Firmware has already delegated traps, so only hart ID
+/// is saved. Booting hart may be any hart, while hart 0 should initialize
+/// kernel, so hart 0 is started if it's not the booting one.
+#[no_mangle]
+extern "C" fn kinit_sbi(hart: usize, fdt: usize) -> ! {
+    unsafe {
+        FDT_ADDR = fdt;
+        asm!("mv tp, {0}", in(reg) hart);
+    }
+    sbi::enable();
+    if hart != 0 {
+        sbi::hart_start(0, _start_secondary as usize, 0);
+    }
+    kmain();
+    unreachable!()
+}
+
+/// Entry of harts started by SBI HSM extension
+#[no_mangle]
+extern "C" fn kinit_secondary(hart: usize) -> ! {
+    unsafe { asm!("mv tp, {0}", in(reg) hart); }
+    kmain();
+    unreachable!()
+}
+
+/// Start harts other than hart 0 and booting hart through SBI
+fn start_harts() {
+    let harts = fdt::machine().harts.min(NCPUS);
+    for hart in 1..harts {
+        let err = sbi::hart_start(hart, _start_secondary as usize, 0);
+        if err != 0 && err != sbi::SBI_ERR_ALREADY_AVAILABLE {
+            warn!("  failed to start hart {}: {}", hart, err);
+        }
+    }
+}
+
 /// Controls whether other harts may start boot procedure
 static mut MAY_BOOT: bool = false;
 
 #[no_mangle]
 extern "C" fn kmain() {
     if hart_id() == 0 {
+        let from_fdt = unsafe { fdt::init(FDT_ADDR) };
         unsafe { uart::init(); }
+        rtc::init();
         info!("booting LTOS on hart {}...", hart_id());
+        let (year, month, day, hour, minute, second) = rtc::civil(rtc::realtime().as_secs());
+        info!("  RTC... \x1b[0;32m{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC\x1b[0m", year, month, day, hour, minute, second);
         info!("  UART... \x1b[0;32minitialized\x1b[0m");
+        let machine = fdt::machine();
+        if from_fdt {
+            info!("  FDT... \x1b[0;32m{} MiB memory, {} harts, {} virtio slots\x1b[0m",
+                machine.memory.size >> 20, machine.harts, machine.virtio_count);
+        } else {
+            warn!("  FDT... not found at {:#x}, using QEMU virt defaults", unsafe { FDT_ADDR });
+        }
+        if machine.harts > NCPUS {
+            warn!("  only {} of {} harts are supported", NCPUS, machine.harts);
+        }
+        if sbi::enabled() {
+            match sbi::check() {
+                Ok(()) => info!("  SBI... \x1b[0;32msupervisor-mode payload\x1b[0m"),
+                Err(ext) => panic!("SBI {} extension not available", ext),
+            }
+        }
+        let backend = unsafe { timer::init() };
+        info!("  Timer... \x1b[0;32mone-shot, {:?}\x1b[0m", backend);
         unsafe { mem::init(); }
         info!("  kernel page table... \x1b[0;32minitialized\x1b[0m");
+        let stats = mem::ALLOC().lock().stats();
+        info!("  Page allocator... \x1b[0;32m{} of {} pages free\x1b[0m", stats.free, stats.total);
         unsafe { virtio::init(); }
         info!("  virt-io... \x1b[0;32minitialized\x1b[0m");
+        if random::init() {
+            info!("  Random... \x1b[0;32mseeded\x1b[0m");
+        } else {
+            info!("  Random... \x1b[0;33mseeded from timer jitter only\x1b[0m");
+        }
+        info!("  Block device... \x1b[0;32m{} found\x1b[0m", block::count());
+        fs::init();
+        info!("  Filesystem... \x1b[0;32mmounted\x1b[0m");
         unsafe { plic::init(); }
         info!("  PLIC... \x1b[0;32minitialized\x1b[0m");
         mem::hartinit();
         info!("kernel page table configured");
+        let asid_bits = asid::init();
+        info!("  ASID... \x1b[0;32m{} bits\x1b[0m", asid_bits);
         info!("  Trap... \x1b[0;32minitialized\x1b[0m");
-        info!("  Timer... \x1b[0;32minitialized\x1b[0m");
         plic::hartinit();
         info!("  PLIC... \x1b[0;32minitialized\x1b[0m");
         unsafe { trap::hartinit(); }
         info!("  Interrupt... \x1b[0;32minitialized\x1b[0m");
         process::init_proc();
         info!("  Process... \x1b[0;32minitialized\x1b[0m");
+        timer::hartinit();
+        ipi::hartinit();
+        uart::poll();
         unsafe {
             asm!("fence");
             MAY_BOOT = true
         }
+        if sbi::enabled() {
+            start_harts();
+        }
     } else {
         loop {
             if unsafe { MAY_BOOT } {
@@ -76,6 +181,8 @@ extern "C" fn kmain() {
         mem::hartinit();
         unsafe { trap::hartinit(); }
         plic::hartinit();
+        timer::hartinit();
+        ipi::hartinit();
     }
 
     process::scheduler()
@@ -43,6 +43,17 @@ pub const NCPUS : usize = 8;
 /// Maximum process on machine.
 pub const NMAXPROCS: usize = 256;
 
+/// Top of user stack, where `sp` begins
+pub const USER_STACK_TOP: usize = 0x80005000;
+
+/// Default maximum size of user stack, which grows down on page fault,
+/// 1 MiB. Each process may change its own with `setrlimit`.
+pub const USER_STACK_LIMIT: usize = 0x100000;
+
+/// Size of unmapped region below user stack at its limit, so that stack
+/// overflow always faults
+pub const USER_STACK_GUARD: usize = 16 * PAGE_SIZE;
+
 /// Scheduler timer interrupt interval
 pub const SCHEDULER_INTERVAL: usize = 1_000_000;
 
@@ -31,3 +31,7 @@ extern "C" { static _kernel_stack_end: usize; }
 #[inline] pub fn KERNEL_STACK_END() -> usize { unsafe { &_kernel_stack_end as *const _ as _ } }
 extern "C" { static _trampoline_text_start: usize; }
 #[inline] pub fn TRAMPOLINE_TEXT_START() -> usize { unsafe { &_trampoline_text_start as *const _ as _ } }
+extern "C" { static _initramfs_start: usize; }
+#[inline] pub fn INITRAMFS_START() -> usize { unsafe { &_initramfs_start as *const _ as _ } }
+extern "C" { static _initramfs_end: usize; }
+#[inline] pub fn INITRAMFS_END() -> usize { unsafe { &_initramfs_end as *const _ as _ } }
@@ -15,18 +15,27 @@
 
 mod gen;
 mod file;
+mod socket;
 
 pub use gen::*;
-use crate::process::{TrapFrame, Register, my_proc, fork, exec, exit, Process};
-use crate::{info};
-use crate::page;
+use crate::process::{TrapFrame, Register, my_proc, fork, exec, exit, kill, sbrk, setrlimit, Process, Access, Fault};
+use crate::{info, warn};
 use crate::mem::{page_down};
 use crate::symbols::{PAGE_SIZE};
 use file::*;
+use socket::*;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::file::File;
 
 
+/// Error number of no such file, returned negated
+pub const ENOENT: i32 = 2;
+/// Error number of bad executable, returned negated
+pub const ENOEXEC: i32 = 8;
+/// Error number of out of memory, returned negated
+pub const ENOMEM: i32 = 12;
 
 /// Get the `pos`th argument from syscall
 pub fn argraw(tf: &TrapFrame, pos: usize) -> usize {
@@ -55,20 +64,62 @@ pub fn arg_uint(tf: &TrapFrame, pos: usize) -> usize {
     sz as usize
 }
 
-/// Get the `pos`th argument as a pointer from syscall, return kernel-space pointer (involve security issues!)
-pub fn arg_ptr(pgtable: &page::Table, tf: &TrapFrame, pos: usize, sz: usize) -> *const u8 {
-    let ptr = argraw(tf, pos);
-    let pg_begin = page_down(ptr);
-    if ptr + sz >= pg_begin + PAGE_SIZE {
-        panic!("out of bound!");
+/// Physical address of user page at `vaddr` for `access`, populating it
+/// if it's not yet
+pub fn user_page(p: &mut Process, vaddr: usize, access: Access) -> Result<usize, Fault> {
+    p.vmas.fault(&mut p.pgtable, vaddr, access)
+}
+
+/// Call `f` on each part of user buffer at `ptr` of `sz` bytes for
+/// `access`, where each part lies in one page
+fn user_pages<F: FnMut(&mut [u8])>(p: &mut Process, ptr: usize, sz: usize, access: Access, mut f: F) -> Result<(), Fault> {
+    let mut done = 0;
+    while done < sz {
+        let va = ptr.checked_add(done).ok_or(Fault::Segfault)?;
+        let pg_begin = page_down(va);
+        let len = (pg_begin + PAGE_SIZE - va).min(sz - done);
+        let paddr = user_page(p, pg_begin, access)?;
+        f(unsafe { core::slice::from_raw_parts_mut((paddr + va - pg_begin) as *mut u8, len) });
+        done += len;
     }
-    let paddr = pgtable.paddr_of(pg_begin).unwrap();
-    unsafe { (paddr as *const u8).add(ptr - pg_begin) }
+    Ok(())
 }
 
-/// Get the `pos`th argument as a pointer from syscall, return kernel-space mutable pointer (involve security issues!)
-pub fn arg_ptr_mut(pgtable: &page::Table, tf: &TrapFrame, pos: usize, sz: usize) -> *mut u8 {
-    arg_ptr(pgtable, tf, pos, sz) as *mut u8
+/// Kill current process `p` for `fault` on user pointer `ptr`
+fn user_fault(p: &Process, fault: Fault, ptr: usize) -> ! {
+    warn!("process {}: {:?} on user pointer {:#x}", p.pid, fault, ptr);
+    exit(-1);
+}
+
+/// Copy user buffer at `ptr` of `sz` bytes into kernel. Process is killed
+/// if buffer is not readable.
+pub fn user_bytes(p: &mut Process, ptr: usize, sz: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(sz);
+    if let Err(fault) = user_pages(p, ptr, sz, Access::Read, |buf| data.extend_from_slice(buf)) {
+        user_fault(p, fault, ptr);
+    }
+    data
+}
+
+/// Get the `pos`th argument as user buffer of `sz` bytes, copied into
+/// kernel. Process is killed if buffer is not readable.
+pub fn arg_bytes(p: &mut Process, pos: usize, sz: usize) -> Vec<u8> {
+    let ptr = argraw(&p.trapframe, pos);
+    user_bytes(p, ptr, sz)
+}
+
+/// Copy `data` to user buffer of the `pos`th argument. Process is killed
+/// if buffer is not writable.
+pub fn arg_copy_out(p: &mut Process, pos: usize, data: &[u8]) {
+    let ptr = argraw(&p.trapframe, pos);
+    let mut done = 0;
+    let copied = user_pages(p, ptr, data.len(), Access::Write, |buf| {
+        buf.copy_from_slice(&data[done..done + buf.len()]);
+        done += buf.len();
+    });
+    if let Err(fault) = copied {
+        user_fault(p, fault, ptr);
+    }
 }
 
 
@@ -92,20 +143,17 @@ fn sys_exec() -> i32 {
     {
         let p = my_proc();
         let sz = arg_uint(&p.trapframe, 1);
-        let ptr = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
-        path = unsafe {
-            // First, we build a &[u8]...
-            let slice = core::slice::from_raw_parts(ptr, sz);
-            // ... and then convert that slice into a string slice
-            core::str::from_utf8(slice).unwrap()
+        // copied, as old image is dropped
+        path = match String::from_utf8(arg_bytes(p, 0, sz)) {
+            Ok(path) => path,
+            Err(_) => { return -1; }
         };
     }
     if path == "/init" {
         info!("running tests before init...");
         crate::test::run_tests();
     }
-    exec(path);
-    0
+    exec(&path)
 }
 
 /// exit syscall entry
@@ -118,23 +166,126 @@ fn sys_exit() -> i32 {
     exit(code);
 }
 
-/// Process all syscall
-pub fn syscall() -> i32 {
+/// sbrk syscall entry
+fn sys_sbrk() -> isize {
+    let increment = argraw(&my_proc().trapframe, 0) as isize;
+    sbrk(increment)
+}
+
+/// kill syscall entry
+fn sys_kill() -> i32 {
+    let pid = arg_int(&my_proc().trapframe, 0);
+    kill(pid)
+}
+
+/// setrlimit syscall entry
+fn sys_setrlimit() -> i32 {
+    let tf = &my_proc().trapframe;
+    setrlimit(argraw(tf, 0), argraw(tf, 1))
+}
+
+/// Call `f` on each part of user buffer at `ptr` of `sz` bytes to be
+/// written, where each part lies in one page. Returns `false` if buffer is
+/// not writable.
+pub fn for_user_pages<F: FnMut(&mut [u8])>(p: &mut Process, ptr: usize, sz: usize, f: F) -> bool {
+    user_pages(p, ptr, sz, Access::Write, f).is_ok()
+}
+
+/// Copy `data` to user buffer at `ptr`. Returns `false` if buffer is not writable.
+pub fn copy_out(p: &mut Process, ptr: usize, data: &[u8]) -> bool {
+    let mut done = 0;
+    for_user_pages(p, ptr, data.len(), |buf| {
+        buf.copy_from_slice(&data[done..done + buf.len()]);
+        done += buf.len();
+    })
+}
+
+/// reboot syscall entry, powers off or restarts machine
+fn sys_reboot() -> i32 {
+    use crate::power;
+    match argraw(&my_proc().trapframe, 0) {
+        power::REBOOT_POWER_OFF => power::shutdown(),
+        power::REBOOT_RESTART => power::reboot(),
+        _ => -1
+    }
+}
+
+/// getrandom syscall entry, buffer may span multiple pages
+fn sys_getrandom() -> i32 {
+    let p = my_proc();
+    let ptr = argraw(&p.trapframe, 0);
+    let sz = arg_uint(&p.trapframe, 1);
+    if !for_user_pages(p, ptr, sz, crate::random::fill) {
+        return -1;
+    }
+    sz as i32
+}
+
+/// clock_gettime syscall entry, writes `timespec` of seconds and nanoseconds
+fn sys_clock_gettime() -> i32 {
+    let p = my_proc();
+    let clock = argraw(&p.trapframe, 0);
+    let ptr = argraw(&p.trapframe, 1);
+    let time = match crate::rtc::clock(clock) {
+        Some(time) => time,
+        None => { return -1; }
+    };
+    let mut ts = [0; 16];
+    ts[..8].copy_from_slice(&(time.as_secs() as i64).to_le_bytes());
+    ts[8..].copy_from_slice(&(time.subsec_nanos() as i64).to_le_bytes());
+    if copy_out(p, ptr, &ts) { 0 } else { -1 }
+}
+
+/// sleep syscall entry, sleeps for milliseconds. Returns -1 if killed.
+fn sys_sleep() -> i32 {
+    let ms = argraw(&my_proc().trapframe, 0);
+    if crate::timer::sleep_for(core::time::Duration::from_millis(ms as u64)) { 0 } else { -1 }
+}
+
+/// uptime syscall entry, returns milliseconds since boot
+fn sys_uptime() -> i32 {
+    crate::arch::time().as_millis() as i32
+}
+
+/// Process all syscall. Returns value for `a0`.
+pub fn syscall() -> isize {
     let syscall_id;
     {
         let p = my_proc();
         let tf = &p.trapframe;
         syscall_id = tf.regs[Register::a7 as usize] as i64;
     }
-    match syscall_id {
+    // program break doesn't fit in `i32`
+    if syscall_id == SYS_SBRK {
+        return sys_sbrk();
+    }
+    let ret = match syscall_id {
         SYS_WRITE => sys_write(),
         SYS_READ => sys_read(),
         SYS_FORK => sys_fork(),
         SYS_EXEC => sys_exec(),
         SYS_EXIT => sys_exit(),
+        SYS_KILL => sys_kill(),
         SYS_DUP => sys_dup(),
         SYS_OPEN => sys_open(),
         SYS_CLOSE => sys_close(),
+        SYS_UNLINK => sys_unlink(),
+        SYS_MKDIR => sys_mkdir(),
+        SYS_SOCKET => sys_socket(),
+        SYS_BIND => sys_bind(),
+        SYS_LISTEN => sys_listen(),
+        SYS_ACCEPT => sys_accept(),
+        SYS_CONNECT => sys_connect(),
+        SYS_SEND => sys_send(),
+        SYS_RECV => sys_recv(),
+        SYS_GETRANDOM => sys_getrandom(),
+        SYS_IOCTL => sys_ioctl(),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(),
+        SYS_REBOOT => sys_reboot(),
+        SYS_SLEEP => sys_sleep(),
+        SYS_UPTIME => sys_uptime(),
+        SYS_SETRLIMIT => sys_setrlimit(),
         _ => unreachable!()
-    }
+    };
+    ret as isize
 }
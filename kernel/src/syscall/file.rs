@@ -6,50 +6,75 @@
 //! File-related syscalls
 
 use alloc::boxed::Box;
-use crate::process::my_proc;
-use crate::syscall::{arg_int, arg_uint, arg_ptr, arg_fd, arg_ptr_mut};
-use crate::file::{File, Console, FsFile};
+use alloc::string::String;
+use alloc::vec;
+use crate::process::{my_proc, Process};
+use crate::syscall::{argraw, arg_int, arg_uint, arg_bytes, arg_copy_out, arg_fd, user_bytes};
+use crate::file::{self, File, Console, FsFile, Random};
+use crate::fs;
 use alloc::sync::Arc;
 
 
-use crate::virtio::BSIZE;
+use crate::block::BSIZE;
 
-/// write syscall
+/// write syscall, copying at most `BSIZE` bytes into kernel at a time.
+/// Stops at a short or failed write, and returns bytes written before it
+/// if there are any.
 pub fn sys_write() -> i32 {
     let p = my_proc();
-    let sz = arg_uint(&p.trapframe, 2);
-    if sz > BSIZE {
-        panic!("size > BSIZE not supported");
+    let sz = arg_int(&p.trapframe, 2);
+    if sz < 0 {
+        return -1;
     }
-    let content = arg_ptr(&p.pgtable, &p.trapframe, 1, sz);
-    let u8_slice = unsafe { core::slice::from_raw_parts(content, sz) };
-    let file = arg_fd(p, 0);
-    match (*file).as_ref() {
-        File::Device(dev) => dev.write(u8_slice),
-        File::FsFile(file) => file.write(u8_slice),
-        _ => { unimplemented!(); }
+    let sz = sz as usize;
+    let ptr = argraw(&p.trapframe, 1);
+    let file = arg_fd(p, 0).clone();
+    let mut written = 0;
+    while written < sz {
+        let len = BSIZE.min(sz - written);
+        let content = user_bytes(p, ptr + written, len);
+        let ret = match file.as_ref() {
+            File::Device(dev) => dev.write(&content),
+            File::FsFile(file) => file.write(&content),
+            File::Socket(sock) => sock.send(&content),
+            File::UnixSocket(sock) => sock.send(&content),
+            _ => { unimplemented!(); }
+        };
+        if ret < 0 {
+            return if written == 0 { ret } else { written as i32 };
+        }
+        written += ret as usize;
+        if (ret as usize) < len {
+            break;
+        }
     }
+    written as i32
 }
 
-/// read syscall
+/// read syscall, reads at most `BSIZE` bytes at a time
 pub fn sys_read() -> i32 {
     let p = my_proc();
-    let sz = arg_uint(&p.trapframe, 2);
-    if sz > BSIZE {
-        panic!("size > BSIZE not supported");
+    let sz = arg_int(&p.trapframe, 2);
+    if sz < 0 {
+        return -1;
     }
-    let content = arg_ptr_mut(&p.pgtable, &p.trapframe, 1, sz);
-    let u8_slice = unsafe { core::slice::from_raw_parts_mut(content, sz) };
-    let file = arg_fd(p, 0);
-    match (*file).as_ref() {
-        File::Device(dev) => dev.read(u8_slice),
-        File::FsFile(file) => file.read(u8_slice),
+    let mut content = vec![0; (sz as usize).min(BSIZE)];
+    let file = arg_fd(p, 0).clone();
+    let read_sz = match file.as_ref() {
+        File::Device(dev) => dev.read(&mut content),
+        File::FsFile(file) => file.read(&mut content),
+        File::Socket(sock) => sock.recv(&mut content),
+        File::UnixSocket(sock) => sock.recv(&mut content),
         _ => { unimplemented!(); }
+    };
+    if read_sz > 0 {
+        arg_copy_out(p, 1, &content[..read_sz as usize]);
     }
+    read_sz
 }
 
 /// find a available file descriptor from files array in process
-fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
+pub fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
     for i in 0..files.len() {
         match files[i] {
             None => { return Some(i); }
@@ -59,25 +84,52 @@ fn next_available_fd<T>(files: &[Option<T>]) -> Option<usize> {
     None
 }
 
-/// open syscall, currently only support `/console` file.
+/// Get path from the `pos`th (pointer) and `pos + 1`th (size) argument,
+/// `None` if it's not UTF-8
+fn arg_path(p: &mut Process, pos: usize) -> Option<String> {
+    let sz = arg_uint(&p.trapframe, pos + 1);
+    String::from_utf8(arg_bytes(p, pos, sz)).ok()
+}
+
+/// open syscall, supports `/console`, `/dev/random`, `/dev/urandom`,
+/// devices registered by drivers and files in mounted filesystems.
 pub fn sys_open() -> i32 {
     let p = my_proc();
-    let sz = arg_uint(&p.trapframe, 1);
     let mode = arg_uint(&p.trapframe, 2);
-    let content = arg_ptr(&p.pgtable, &p.trapframe, 0, sz);
-    let path = core::str::from_utf8(unsafe { core::slice::from_raw_parts(content, sz) }).unwrap();
+    let path = match arg_path(p, 0) {
+        Some(path) => path,
+        None => { return -1; }
+    };
     let fd = match next_available_fd(&p.files) {
         Some(fd) => fd,
         None => { return -1; }
     };
     if path == "/console" {
         p.files[fd] = Some(Arc::new(File::Device(Box::new(Console {}))));
+    } else if path == "/dev/random" || path == "/dev/urandom" {
+        p.files[fd] = Some(Arc::new(File::Device(Box::new(Random {}))));
+    } else if let Some(device) = path.strip_prefix("/dev/").and_then(file::lookup) {
+        p.files[fd] = Some(Arc::new(File::Device(Box::new(device))));
     } else {
-        p.files[fd] = Some(Arc::new(File::FsFile(FsFile::open(path, mode))));
+        match FsFile::open(&path, mode) {
+            Some(f) => { p.files[fd] = Some(Arc::new(File::FsFile(f))); }
+            None => { return -1; }
+        }
     }
     fd as i32
 }
 
+/// ioctl syscall, only supported by devices
+pub fn sys_ioctl() -> i32 {
+    let p = my_proc();
+    let request = argraw(&p.trapframe, 1);
+    let arg = argraw(&p.trapframe, 2);
+    match &**arg_fd(p, 0) {
+        File::Device(device) => device.ioctl(request, arg),
+        _ => -1
+    }
+}
+
 /// close syscall
 pub fn sys_close() -> i32 {
     let p = my_proc();
@@ -98,3 +150,21 @@ pub fn sys_dup() -> i32 {
     
     fd as i32
 }
+
+/// unlink syscall
+pub fn sys_unlink() -> i32 {
+    let p = my_proc();
+    match arg_path(p, 0) {
+        Some(path) => fs::unlink(&path),
+        None => -1,
+    }
+}
+
+/// mkdir syscall
+pub fn sys_mkdir() -> i32 {
+    let p = my_proc();
+    match arg_path(p, 0) {
+        Some(path) => fs::mkdir(&path),
+        None => -1,
+    }
+}
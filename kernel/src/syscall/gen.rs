@@ -43,3 +43,27 @@ pub const SYS_SBRK : i64 = 18;
 pub const SYS_SLEEP : i64 = 19;
 /// `20`: uptime
 pub const SYS_UPTIME : i64 = 20;
+/// `21`: socket
+pub const SYS_SOCKET : i64 = 21;
+/// `22`: bind
+pub const SYS_BIND : i64 = 22;
+/// `23`: listen
+pub const SYS_LISTEN : i64 = 23;
+/// `24`: accept
+pub const SYS_ACCEPT : i64 = 24;
+/// `25`: connect
+pub const SYS_CONNECT : i64 = 25;
+/// `26`: send
+pub const SYS_SEND : i64 = 26;
+/// `27`: recv
+pub const SYS_RECV : i64 = 27;
+/// `28`: getrandom
+pub const SYS_GETRANDOM : i64 = 28;
+/// `29`: ioctl
+pub const SYS_IOCTL : i64 = 29;
+/// `30`: clock_gettime
+pub const SYS_CLOCK_GETTIME : i64 = 30;
+/// `31`: reboot
+pub const SYS_REBOOT : i64 = 31;
+/// `32`: setrlimit
+pub const SYS_SETRLIMIT : i64 = 32;
@@ -0,0 +1,130 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Socket-related syscalls, for both IPv4 and Unix-domain sockets
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::block::BSIZE;
+use crate::file::File;
+use crate::net::{Socket, UnixSocket, AF_UNIX};
+use crate::process::{my_proc, Process};
+use crate::syscall::{arg_uint, arg_bytes, arg_copy_out, arg_fd};
+use super::file::next_available_fd;
+
+/// Get socket address from the `pos`th (pointer) and `pos + 1`th (size) argument
+fn arg_sockaddr(p: &mut Process, pos: usize) -> Vec<u8> {
+    let sz = arg_uint(&p.trapframe, pos + 1);
+    arg_bytes(p, pos, sz)
+}
+
+/// Put file into a new file descriptor
+fn install(p: &mut Process, file: File) -> i32 {
+    match next_available_fd(&p.files) {
+        Some(fd) => {
+            p.files[fd] = Some(Arc::new(file));
+            fd as i32
+        }
+        None => -1
+    }
+}
+
+/// socket syscall
+pub fn sys_socket() -> i32 {
+    let p = my_proc();
+    let domain = arg_uint(&p.trapframe, 0);
+    let ty = arg_uint(&p.trapframe, 1);
+    let file = if domain == AF_UNIX {
+        UnixSocket::new(ty).map(File::UnixSocket)
+    } else {
+        Socket::new(domain, ty).map(File::Socket)
+    };
+    match file {
+        Some(file) => install(p, file),
+        None => -1
+    }
+}
+
+/// bind syscall
+pub fn sys_bind() -> i32 {
+    let p = my_proc();
+    let addr = arg_sockaddr(p, 1);
+    match arg_fd(p, 0).as_ref() {
+        File::Socket(sock) => sock.bind(&addr),
+        File::UnixSocket(sock) => sock.bind(&addr),
+        _ => -1
+    }
+}
+
+/// listen syscall
+pub fn sys_listen() -> i32 {
+    let p = my_proc();
+    let backlog = arg_uint(&p.trapframe, 1);
+    match arg_fd(p, 0).as_ref() {
+        File::Socket(sock) => sock.listen(backlog),
+        File::UnixSocket(sock) => sock.listen(backlog),
+        _ => -1
+    }
+}
+
+/// accept syscall
+pub fn sys_accept() -> i32 {
+    let p = my_proc();
+    let conn = match arg_fd(p, 0).as_ref() {
+        File::Socket(sock) => sock.accept().map(File::Socket),
+        File::UnixSocket(sock) => sock.accept().map(File::UnixSocket),
+        _ => None
+    };
+    match conn {
+        Some(conn) => install(p, conn),
+        None => -1
+    }
+}
+
+/// connect syscall
+pub fn sys_connect() -> i32 {
+    let p = my_proc();
+    let addr = arg_sockaddr(p, 1);
+    match arg_fd(p, 0).as_ref() {
+        File::Socket(sock) => sock.connect(&addr),
+        File::UnixSocket(sock) => sock.connect(&addr),
+        _ => -1
+    }
+}
+
+/// send syscall
+pub fn sys_send() -> i32 {
+    let p = my_proc();
+    let sz = arg_uint(&p.trapframe, 2);
+    if sz > BSIZE {
+        return -1;
+    }
+    let content = arg_bytes(p, 1, sz);
+    match arg_fd(p, 0).as_ref() {
+        File::Socket(sock) => sock.send(&content),
+        File::UnixSocket(sock) => sock.send(&content),
+        _ => -1
+    }
+}
+
+/// recv syscall
+pub fn sys_recv() -> i32 {
+    let p = my_proc();
+    let sz = arg_uint(&p.trapframe, 2);
+    if sz > BSIZE {
+        return -1;
+    }
+    let mut content = vec![0; sz];
+    let recv_sz = match arg_fd(p, 0).clone().as_ref() {
+        File::Socket(sock) => sock.recv(&mut content),
+        File::UnixSocket(sock) => sock.recv(&mut content),
+        _ => -1
+    };
+    if recv_sz > 0 {
+        arg_copy_out(p, 1, &content[..recv_sz as usize]);
+    }
+    recv_sz
+}
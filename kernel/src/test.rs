@@ -13,7 +13,24 @@ type TestSuite = fn() -> &'static [(&'static str, fn())];
 /// Run all tests in core os
 pub fn run_tests() {
     let suites = [
+        ("fdt", crate::fdt::tests::tests as TestSuite),
+        ("mem", crate::mem::tests::tests as TestSuite),
+        ("uart", crate::uart::tests::tests as TestSuite),
+        ("rtc", crate::rtc::tests::tests as TestSuite),
+        ("timer", crate::timer::tests::tests as TestSuite),
+        ("ipi", crate::ipi::tests::tests as TestSuite),
+        ("asid", crate::asid::tests::tests as TestSuite),
+        ("vma", crate::process::vma::tests::tests as TestSuite),
+        ("elf", crate::elf::tests::tests as TestSuite),
+        ("tty", crate::tty::tests::tests as TestSuite),
         ("virtio", crate::virtio::tests::tests as TestSuite),
+        ("partition", crate::partition::tests::tests as TestSuite),
+        ("random", crate::random::tests::tests as TestSuite),
+        ("net", crate::net::tests::tests as TestSuite),
+        ("tcp", crate::net::tcp::tests::tests as TestSuite),
+        ("unix", crate::net::unix::tests::tests as TestSuite),
+        ("fs", crate::fs::tests::tests as TestSuite),
+        ("tmpfs", crate::fs::tmpfs::tests::tests as TestSuite),
         ("fsfile", crate::file::tests::tests as TestSuite)];
     for (name, suite) in &suites {
         let tests = suite();
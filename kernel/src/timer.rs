@@ -0,0 +1,220 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Supervisor timer
+//!
+//! Each hart programs one-shot deadlines, in `mtime` ticks, to the earliest
+//! of its time slice and its next kernel event. Deadlines are written to
+//! `stimecmp` when Sstc is present, set through SBI when kernel runs under
+//! SBI, or written to CLINT `mtimecmp` otherwise, in which case `timervec`
+//! forwards the interrupt as a supervisor software interrupt. An idle hart
+//! has no time slice, so it's not interrupted until its next event.
+//!
+//! Kernel events are timers in a per-hart timer wheel, see `wheel`.
+
+use core::arch::asm;
+use crate::arch::{self, hart_id};
+use crate::clint::CLINT_MTIMECMP_OFFSET;
+use crate::fdt::machine;
+use crate::process::my_cpu;
+use crate::sbi;
+use crate::symbols::{NCPUS, SCHEDULER_INTERVAL};
+
+mod wheel;
+pub use wheel::*;
+
+/// No deadline
+pub const NEVER: u64 = u64::MAX;
+
+/// How deadlines are programmed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Backend {
+    /// `stimecmp` CSR
+    Sstc,
+    /// SBI TIME extension
+    Sbi,
+    /// CLINT `mtimecmp`, forwarded by `timervec`
+    Clint,
+}
+
+/// Deadlines of a hart
+#[derive(Clone, Copy)]
+struct HartTimer {
+    /// End of time slice of running process
+    slice: u64,
+    /// Next kernel event
+    event: u64,
+    /// Deadline programmed into hardware
+    programmed: u64,
+}
+
+/// Deadlines of each hart, only accessed by its own hart with interrupt off
+static mut TIMERS: [HartTimer; NCPUS] = [HartTimer { slice: NEVER, event: NEVER, programmed: NEVER }; NCPUS];
+
+/// Backend in use, decided by booting hart
+static mut BACKEND: Backend = Backend::Clint;
+
+/// Backend in use
+pub fn backend() -> Backend {
+    unsafe { BACKEND }
+}
+
+/// Choose backend. Should be called by booting hart after FDT is parsed.
+pub unsafe fn init() -> Backend {
+    BACKEND = if machine().sstc {
+        Backend::Sstc
+    } else if sbi::enabled() {
+        Backend::Sbi
+    } else {
+        Backend::Clint
+    };
+    BACKEND
+}
+
+fn my_timer() -> &'static mut HartTimer {
+    unsafe { &mut TIMERS[hart_id()] }
+}
+
+/// Write `deadline` to hardware of current hart
+fn write(deadline: u64) {
+    match backend() {
+        // stimecmp of Sstc extension
+        Backend::Sstc => unsafe { asm!("csrw 0x14d, {0}", in(reg) deadline); },
+        Backend::Sbi => sbi::set_timer(deadline),
+        Backend::Clint => {
+            let mtimecmp = machine().clint.base + CLINT_MTIMECMP_OFFSET + 8 * hart_id();
+            unsafe { (mtimecmp as *mut u64).write_volatile(deadline); }
+        }
+    }
+}
+
+/// Program the earliest deadline of current hart into hardware.
+/// Should be called with interrupt off.
+fn program() {
+    let timer = my_timer();
+    let deadline = timer.slice.min(timer.event);
+    if deadline != timer.programmed {
+        timer.programmed = deadline;
+        write(deadline);
+    }
+}
+
+/// Initialize timer of current hart, with no deadline
+pub fn hartinit() {
+    let timer = my_timer();
+    *timer = HartTimer { slice: NEVER, event: NEVER, programmed: 0 };
+    program();
+}
+
+/// Start a time slice on current hart
+pub fn start_slice() {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    my_timer().slice = arch::ticks() + SCHEDULER_INTERVAL as u64;
+    program();
+}
+
+/// Stop time slice on current hart, such as when it becomes idle
+pub fn stop_slice() {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    my_timer().slice = NEVER;
+    program();
+}
+
+/// Request timer interrupt on current hart at `deadline`, if it's earlier
+/// than next event. Events before now fire right away.
+pub fn set_event(deadline: u64) {
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let timer = my_timer();
+    if deadline < timer.event {
+        timer.event = deadline;
+        program();
+    }
+}
+
+/// Result of timer interrupt
+#[derive(PartialEq, Debug)]
+pub struct Expired {
+    /// Time slice ended, so running process should yield
+    pub slice: bool,
+    /// Kernel event is due
+    pub event: bool,
+}
+
+/// Process timer interrupt on current hart, clearing expired deadlines
+/// and programming the next one. Should be called with interrupt off.
+pub fn interrupt() -> Expired {
+    let now = arch::ticks();
+    let timer = my_timer();
+    let expired = Expired { slice: timer.slice <= now, event: timer.event <= now };
+    if expired.slice {
+        timer.slice = NEVER;
+    }
+    if expired.event {
+        timer.event = NEVER;
+    }
+    // interrupt is pending until deadline is moved
+    timer.programmed = 0;
+    program();
+    expired
+}
+
+/// Wait for interrupt on idle hart. Only next kernel event is programmed.
+pub fn idle() {
+    arch::intr_off();
+    my_timer().slice = NEVER;
+    program();
+    unsafe { asm!("wfi"); }
+    arch::intr_on();
+}
+
+pub mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("wheel", test_wheel),
+            ("wheel turn", test_wheel_turn),
+        ]
+    }
+
+    /// Test expiring and cancelling timers in wheel
+    pub fn test_wheel() {
+        let mut wheel = Wheel::new();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let mut ids = alloc::vec::Vec::new();
+        for deadline in [30_000, 10_000, 25_000, 10_500] {
+            let fired = fired.clone();
+            ids.push(wheel.add(deadline, Box::new(move || { fired.fetch_add(1, Ordering::SeqCst); })));
+        }
+        assert_eq!(wheel.next_deadline(), 10_000);
+        assert!(wheel.cancel(ids[2], 25_000));
+        assert!(!wheel.cancel(ids[2], 25_000));
+        // 10_500 is in the same slot as 10_000, but not expired yet
+        for callback in wheel.expire(10_200) {
+            callback();
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+        assert_eq!(wheel.next_deadline(), 10_500);
+        assert_eq!(wheel.expire(20_000).len(), 1);
+        assert_eq!(wheel.next_deadline(), 30_000);
+        assert_eq!(wheel.expire(40_000).len(), 1);
+        assert_eq!(wheel.next_deadline(), NEVER);
+    }
+
+    /// Test timers more than a turn of wheel away
+    pub fn test_wheel_turn() {
+        let mut wheel = Wheel::new();
+        let far = 1_000_000_000;
+        wheel.add(far + 5, Box::new(|| {}));
+        wheel.add(far % (256 * 10_000), Box::new(|| {}));
+        // shares slot with the first timer, which stays
+        assert_eq!(wheel.expire(far % (256 * 10_000)).len(), 1);
+        assert_eq!(wheel.expire(far).len(), 0);
+        assert_eq!(wheel.expire(far + 5).len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Per-hart timer wheel
+//!
+//! Kernel timers are kept in a wheel of `WHEEL_SLOTS` slots on the hart
+//! scheduling them, each slot covering `WHEEL_TICK` ticks. A timer far in
+//! the future stays in its slot until the wheel comes around to its
+//! deadline. The earliest deadline is programmed as next event of the hart,
+//! and callbacks of expired timers run in timer interrupt, outside the
+//! wheel lock. Callbacks should be short, e.g. waking up a process.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::time::Duration;
+use crate::arch::{self, hart_id};
+use crate::process::{my_cpu, my_proc, killed, sleep, wakeup};
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::symbols::NCPUS;
+use super::{set_event, NEVER};
+
+/// Number of slots in wheel
+const WHEEL_SLOTS: usize = 256;
+
+/// Ticks covered by one slot, 1 ms
+const WHEEL_TICK: u64 = 10_000;
+
+/// Callback of timer
+pub type Callback = Box<dyn FnOnce() + Send>;
+
+/// Handle of a scheduled timer, for cancelling it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimerId {
+    hart: usize,
+    id: u64,
+    deadline: u64,
+}
+
+struct Entry {
+    id: u64,
+    deadline: u64,
+    callback: Callback,
+}
+
+/// Timer wheel of a hart
+pub struct Wheel {
+    slots: [Vec<Entry>; WHEEL_SLOTS],
+    /// Slot tick processed last, i.e. `deadline / WHEEL_TICK`
+    current: u64,
+    next_id: u64,
+}
+
+const fn slot_of(deadline: u64) -> usize {
+    (deadline / WHEEL_TICK) as usize % WHEEL_SLOTS
+}
+
+impl Wheel {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Vec::new() }; WHEEL_SLOTS],
+            current: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Add a timer firing at `deadline`, returning its id
+    pub fn add(&mut self, deadline: u64, callback: Callback) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.slots[slot_of(deadline)].push(Entry { id, deadline, callback });
+        id
+    }
+
+    /// Remove timer `id` at `deadline`. Returns `false` if it has fired.
+    pub fn cancel(&mut self, id: u64, deadline: u64) -> bool {
+        let slot = &mut self.slots[slot_of(deadline)];
+        match slot.iter().position(|e| e.id == id) {
+            Some(pos) => { slot.swap_remove(pos); true }
+            None => false
+        }
+    }
+
+    /// Take callbacks of timers expired at `now`
+    pub fn expire(&mut self, now: u64) -> Vec<Callback> {
+        let now_tick = now / WHEEL_TICK;
+        // a full turn covers all slots
+        let from = self.current.max(now_tick.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        let mut expired = Vec::new();
+        for tick in from..=now_tick {
+            let slot = &mut self.slots[tick as usize % WHEEL_SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].deadline <= now {
+                    expired.push(slot.swap_remove(i).callback);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        // slot of `now_tick` may still have timers later in this tick
+        self.current = now_tick;
+        expired
+    }
+
+    /// Earliest deadline, `NEVER` if there's no timer
+    pub fn next_deadline(&self) -> u64 {
+        self.slots.iter().flatten().map(|e| e.deadline).min().unwrap_or(NEVER)
+    }
+}
+
+static WHEELS: [Mutex<Wheel>; NCPUS] = [const { Mutex::new(Wheel::new(), "timer wheel") }; NCPUS];
+
+/// Ticks after `duration` from now
+pub fn deadline_after(duration: Duration) -> u64 {
+    arch::ticks() + (duration.as_nanos() / 100) as u64
+}
+
+/// Run `callback` in timer interrupt of current hart at `deadline` ticks
+pub fn add_timer(deadline: u64, callback: Callback) -> TimerId {
+    // stay on this hart until next event is programmed
+    let _intr_lock = my_cpu().intr_lock.lock();
+    let hart = hart_id();
+    let id = WHEELS[hart].lock().add(deadline, callback);
+    set_event(deadline);
+    TimerId { hart, id, deadline }
+}
+
+/// Cancel timer `id`. Returns `false` if it has fired or is firing.
+pub fn cancel_timer(id: TimerId) -> bool {
+    WHEELS[id.hart].lock().cancel(id.id, id.deadline)
+}
+
+/// Run expired timers of current hart, and program the next one.
+/// Should be called in timer interrupt.
+pub fn run_timers() {
+    let hart = hart_id();
+    let expired = WHEELS[hart].lock().expire(arch::ticks());
+    for callback in expired {
+        callback();
+    }
+    set_event(WHEELS[hart].lock().next_deadline());
+}
+
+/// Sleep on `chan` like `sleep`, but also wake up at `deadline`.
+/// Returns whether deadline has passed.
+pub fn sleep_until<'a, T, U>(chan: *const T, lck: MutexGuard<'a, U>, deadline: u64) -> (MutexGuard<'a, U>, bool) {
+    let chan_addr = chan as usize;
+    // timer of this hart only fires after this process sleeps, as
+    // interrupt is off while `lck` is held
+    let id = add_timer(deadline, Box::new(move || wakeup(chan_addr as *const u8)));
+    let lck = sleep(chan, lck);
+    cancel_timer(id);
+    (lck, arch::ticks() >= deadline)
+}
+
+/// Lock of processes sleeping for some time
+static SLEEP_LOCK: Mutex<()> = Mutex::new((), "timed sleep");
+
+/// Sleep current process for `duration`. Returns `false` if it is killed
+/// before that.
+pub fn sleep_for(duration: Duration) -> bool {
+    let deadline = deadline_after(duration);
+    let pid = my_proc().pid;
+    let mut lck = SLEEP_LOCK.lock();
+    loop {
+        if killed(pid) {
+            return false;
+        }
+        if arch::ticks() >= deadline {
+            return true;
+        }
+        let chan = &deadline as *const u64;
+        lck = sleep_until(chan, lck, deadline).0;
+    }
+}
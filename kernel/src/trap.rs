@@ -1,8 +1,8 @@
-use crate::{println, arch, page, syscall, process};
+use crate::{println, arch, asid, page, syscall, process};
 use crate::arch::hart_id;
 use crate::intr::devintr;
 use crate::intr::Intr::Timer;
-use crate::process::{my_cpu, my_proc, Register, yield_cpu};
+use crate::process::{my_cpu, my_proc, Access, Register, yield_cpu};
 use crate::symbols::{kernelvec, TRAMPOLINE_START, TRAMPOLINE_TEXT_START, TRAPFRAME_START, userret, uservec};
 
 /// Process interrupt from supervisor mode
@@ -117,12 +117,23 @@ pub extern "C" fn usertrap() -> ! {
     let p = my_proc();
     p.trapframe.epc = sepc::read();
     let scause = scause::read().bits();
+    // read before interrupt is on, as other traps overwrite it
+    let stval = stval::read();
 
     let mut intr = None;
     if scause == 8 {
         p.trapframe.epc += 4;
         arch::intr_on();
         p.trapframe.regs[Register::a0 as usize] = syscall::syscall() as usize;
+    } else if scause == 12 || scause == 13 || scause == 15 {
+        let access = match scause {
+            12 => Access::Execute,
+            13 => Access::Read,
+            _ => Access::Write,
+        };
+        // file-backed pages may be read from disk
+        arch::intr_on();
+        process::page_fault(stval, access);
     } else {
         intr = devintr();
         if intr.is_none() { panic!("unexpected scause {:x}", scause) }
@@ -132,6 +143,10 @@ pub extern "C" fn usertrap() -> ! {
         yield_cpu();
     }
 
+    if process::killed(my_proc().pid) {
+        process::exit(-1);
+    }
+
     usertrapret();
 }
 
@@ -185,7 +200,8 @@ pub fn usertrapret() -> ! {
 
         // tell trampoline.S the user page table to switch to.
         let root_ppn = &mut *p.pgtable as *mut page::Table as usize;
-        satp_val = arch::build_satp(8, 0, root_ppn);
+        let asid = asid::activate(&mut p.asid);
+        satp_val = arch::build_satp(8, asid, root_ppn);
     }
     // jump to trampoline.S at the top of memory, which
     // switches to the user page table, restores user registers,
@@ -0,0 +1,313 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! TTY line discipline of console
+//!
+//! Bytes received by UART are passed to `input`. In canonical mode, input
+//! is collected into a line, which is edited by backspace and Ctrl-U, and
+//! becomes readable after newline or Ctrl-D. Ctrl-D on empty line makes
+//! next read return 0. With `ISIG`, Ctrl-C discards current line and kills
+//! the foreground process, which is the one set by `TIOCSPGRP`, or the
+//! last one reading from console. Modes are switched by `TCSETS` ioctl.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use crate::process::{kill, killed, my_cpu, my_proc, sleep, wakeup};
+use crate::spinlock::Mutex;
+use crate::uart::UART;
+
+/// Local mode: Ctrl-C kills foreground process
+pub const ISIG: usize = 0o1;
+/// Local mode: canonical mode with line editing
+pub const ICANON: usize = 0o2;
+/// Local mode: echo input
+pub const ECHO: usize = 0o10;
+
+/// ioctl: get local mode as return value
+pub const TCGETS: usize = 0x5401;
+/// ioctl: set local mode to argument
+pub const TCSETS: usize = 0x5402;
+/// ioctl: set foreground process to argument
+pub const TIOCSPGRP: usize = 0x5410;
+
+/// Ctrl-C
+const INTR: u8 = 0x03;
+/// Ctrl-D
+const EOF: u8 = 0x04;
+/// Ctrl-U
+const KILL: u8 = 0x15;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Maximum length of line being edited, and of unread input
+const MAX_INPUT: usize = 1024;
+
+/// State of TTY
+pub struct Tty {
+    /// Local mode, combination of `ISIG`, `ICANON` and `ECHO`
+    lflag: usize,
+    /// Line being edited in canonical mode
+    line: Vec<u8>,
+    /// Input readable by processes
+    input: VecDeque<u8>,
+    /// Number of pending end-of-file, each making one read return 0
+    eof: usize,
+    /// Process set by `TIOCSPGRP`
+    foreground: Option<i32>,
+    /// Process last reading from TTY
+    reader: Option<i32>,
+}
+
+/// Effect of a received byte, besides echo
+#[derive(PartialEq, Debug)]
+pub enum InputEffect {
+    None,
+    /// Input becomes readable
+    Readable,
+    /// Foreground process should be interrupted
+    Interrupt,
+}
+
+impl Tty {
+    pub const fn new() -> Self {
+        Self {
+            lflag: ISIG | ICANON | ECHO,
+            line: Vec::new(),
+            input: VecDeque::new(),
+            eof: 0,
+            foreground: None,
+            reader: None,
+        }
+    }
+
+    /// Process received byte `c`, putting echo into `echo`
+    pub fn receive(&mut self, c: u8, echo: &mut Vec<u8>) -> InputEffect {
+        let do_echo = self.lflag & ECHO != 0;
+        if self.lflag & ISIG != 0 && c == INTR {
+            if do_echo {
+                echo.extend_from_slice(b"^C\n");
+            }
+            self.line.clear();
+            return InputEffect::Interrupt;
+        }
+        if self.lflag & ICANON == 0 {
+            if self.input.len() >= MAX_INPUT {
+                return InputEffect::None;
+            }
+            self.input.push_back(c);
+            if do_echo {
+                echo.push(c);
+            }
+            return InputEffect::Readable;
+        }
+        match c {
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() && do_echo {
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+                InputEffect::None
+            }
+            KILL => {
+                while self.line.pop().is_some() {
+                    if do_echo {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                }
+                InputEffect::None
+            }
+            EOF => {
+                if self.line.is_empty() {
+                    self.eof += 1;
+                } else {
+                    self.input.extend(self.line.drain(..));
+                }
+                InputEffect::Readable
+            }
+            b'\n' | b'\r' => {
+                if do_echo {
+                    echo.push(b'\n');
+                }
+                self.line.push(b'\n');
+                self.input.extend(self.line.drain(..));
+                InputEffect::Readable
+            }
+            _ => {
+                if self.line.len() + self.input.len() < MAX_INPUT - 1 {
+                    self.line.push(c);
+                    if do_echo {
+                        echo.push(c);
+                    }
+                }
+                InputEffect::None
+            }
+        }
+    }
+
+    /// Take readable input into `content`. In canonical mode, at most one
+    /// line is taken. Returns `None` if nothing is readable.
+    pub fn take(&mut self, content: &mut [u8]) -> Option<usize> {
+        if self.input.is_empty() {
+            if self.eof > 0 {
+                self.eof -= 1;
+                return Some(0);
+            }
+            return None;
+        }
+        let mut len = 0;
+        while len < content.len() {
+            match self.input.pop_front() {
+                Some(c) => {
+                    content[len] = c;
+                    len += 1;
+                    if c == b'\n' && self.lflag & ICANON != 0 {
+                        break;
+                    }
+                }
+                None => { break; }
+            }
+        }
+        Some(len)
+    }
+
+    /// Process to interrupt on Ctrl-C
+    fn target(&self) -> Option<i32> {
+        self.foreground.or(self.reader)
+    }
+}
+
+static TTY: Mutex<Tty> = Mutex::new(Tty::new(), "tty");
+
+/// Process bytes received by UART. Should not be called with UART locked.
+pub fn input(bytes: &[u8]) {
+    let mut echo = Vec::new();
+    let mut readable = false;
+    let mut interrupt = None;
+    {
+        let mut tty = TTY.lock();
+        for &c in bytes {
+            match tty.receive(c, &mut echo) {
+                InputEffect::None => {}
+                InputEffect::Readable => { readable = true; }
+                InputEffect::Interrupt => { interrupt = tty.target(); }
+            }
+        }
+        if readable {
+            wakeup(&tty.input as *const VecDeque<u8>);
+        }
+    }
+    if !echo.is_empty() {
+        // may be in interrupt, so busy-wait instead of sleeping if UART is busy
+        let mut uart = UART().lock();
+        for c in echo {
+            uart.put(c);
+        }
+    }
+    if let Some(pid) = interrupt {
+        kill(pid);
+    }
+}
+
+/// Read from console. Sleeps until input is readable.
+pub fn read(content: &mut [u8]) -> i32 {
+    let mut tty = TTY.lock();
+    if let Some(p) = &my_cpu().process {
+        tty.reader = Some(p.pid);
+    }
+    loop {
+        if let Some(len) = tty.take(content) {
+            return len as i32;
+        }
+        if my_cpu().process.is_none() {
+            return 0;
+        }
+        if killed(my_proc().pid) {
+            return -1;
+        }
+        let chan = &tty.input as *const VecDeque<u8>;
+        tty = sleep(chan, tty);
+    }
+}
+
+/// Control console by `request` with `arg`
+pub fn ioctl(request: usize, arg: usize) -> i32 {
+    let mut tty = TTY.lock();
+    match request {
+        TCGETS => tty.lflag as i32,
+        TCSETS => {
+            if tty.lflag & ICANON != 0 && arg & ICANON == 0 {
+                // line being edited becomes readable in raw mode
+                let line: Vec<u8> = tty.line.drain(..).collect();
+                tty.input.extend(line);
+                wakeup(&tty.input as *const VecDeque<u8>);
+            }
+            tty.lflag = arg & (ISIG | ICANON | ECHO);
+            0
+        }
+        TIOCSPGRP => {
+            tty.foreground = if arg == 0 { None } else { Some(arg as i32) };
+            0
+        }
+        _ => -1
+    }
+}
+
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("line editing", test_line_editing),
+            ("eof", test_eof),
+            ("raw mode", test_raw),
+        ]
+    }
+
+    fn feed(tty: &mut Tty, bytes: &[u8]) -> (Vec<u8>, Vec<InputEffect>) {
+        let mut echo = Vec::new();
+        let effects = bytes.iter().map(|&c| tty.receive(c, &mut echo)).collect();
+        (echo, effects)
+    }
+
+    /// Test backspace, kill-line and Ctrl-C in canonical mode
+    pub fn test_line_editing() {
+        let mut tty = Tty::new();
+        let mut buf = [0; 16];
+        let (echo, _) = feed(&mut tty, b"ab\x7fc");
+        assert_eq!(&echo[..], b"ab\x08 \x08c");
+        assert_eq!(tty.take(&mut buf), None);
+        feed(&mut tty, b"\r");
+        feed(&mut tty, b"xyz\x15ok\n");
+        assert_eq!(tty.take(&mut buf), Some(3));
+        assert_eq!(&buf[..3], b"ac\n");
+        assert_eq!(tty.take(&mut buf), Some(3));
+        assert_eq!(&buf[..3], b"ok\n");
+        let (echo, effects) = feed(&mut tty, b"no\x03");
+        assert_eq!(&echo[..], b"no^C\n");
+        assert_eq!(effects[2], InputEffect::Interrupt);
+        assert_eq!(tty.take(&mut buf), None);
+    }
+
+    /// Test Ctrl-D on empty and non-empty line
+    pub fn test_eof() {
+        let mut tty = Tty::new();
+        let mut buf = [0; 16];
+        feed(&mut tty, b"ab\x04\x04");
+        assert_eq!(tty.take(&mut buf), Some(2));
+        assert_eq!(&buf[..2], b"ab");
+        assert_eq!(tty.take(&mut buf), Some(0));
+        assert_eq!(tty.take(&mut buf), None);
+    }
+
+    /// Test raw mode without echo
+    pub fn test_raw() {
+        let mut tty = Tty::new();
+        tty.lflag = 0;
+        let mut buf = [0; 16];
+        let (echo, _) = feed(&mut tty, b"a\x7f\x03\x04");
+        assert!(echo.is_empty());
+        assert_eq!(tty.take(&mut buf), Some(4));
+        assert_eq!(&buf[..4], b"a\x7f\x03\x04");
+    }
+}
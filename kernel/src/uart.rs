@@ -4,20 +4,95 @@
 // https://opensource.org/licenses/MIT
 
 //! UART driver module
+//!
+//! Output is put into a transmit ring, and moved into UART whenever
+//! transmitter holding register is empty, either right away or in
+//! `uartintr`. Writers only busy-wait when the ring is full. Input is
+//! passed to TTY line discipline by `uartintr`.
+//!
+//! When kernel runs under SBI and there's no UART in FDT, console falls
+//! back to SBI. Such UART has base address 0, and input is polled on
+//! timer interrupt.
 
 use core::convert::TryInto;
 use core::fmt::Write;
 use core::fmt::Error;
-use crate::spinlock::Mutex;
-use crate::{println, print};
+use crate::arch::hart_id;
+use crate::process::{my_cpu, sleep, wakeup};
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::sbi;
 
-/// UART base address on QEMU RISC-V
+/// Default UART base address on QEMU RISC-V, used if not in FDT
 pub const UART_BASE_ADDR: usize = 0x1000_0000;
 
+/// Size of transmit ring
+const TX_BUF_SIZE: usize = 1024;
+
+/// Maximum bytes taken from UART in one interrupt. Remaining bytes
+/// raise another interrupt.
+const RX_BATCH: usize = 64;
+
+/// Interrupt enable register bits
+const IER_RX_ENABLE: u8 = 1 << 0;
+const IER_TX_ENABLE: u8 = 1 << 1;
+
+/// Line status register bits
+const LSR_RX_READY: u8 = 1 << 0;
+const LSR_TX_IDLE: u8 = 1 << 5;
+
+/// Fixed-size ring buffer of bytes
+pub struct Ring<const N: usize> {
+    buf: [u8; N],
+    /// Index of next byte to read
+    r: usize,
+    /// Index of next byte to write, `w - r` is number of bytes in ring
+    w: usize,
+}
+
+impl<const N: usize> Ring<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], r: 0, w: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.w - self.r
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.r == self.w
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Put `c` into ring. Returns `false` if ring is full.
+    pub fn push(&mut self, c: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[self.w % N] = c;
+        self.w += 1;
+        true
+    }
+
+    /// Take the oldest byte
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let c = self.buf[self.r % N];
+        self.r += 1;
+        Some(c)
+    }
+}
+
 /// UART driver
 pub struct Uart {
     /// UART MMIO base address
     base_address: usize,
+    /// Bytes waiting to be transmitted
+    tx: Ring<TX_BUF_SIZE>,
 }
 
 impl Write for Uart {
@@ -32,12 +107,26 @@ impl Write for Uart {
 impl Uart {
     pub const fn new(base_address: usize) -> Self {
         Uart {
-            base_address
+            base_address,
+            tx: Ring::new(),
         }
     }
 
+    /// UART MMIO base address, 0 for SBI console
+    pub fn base_address(&self) -> usize {
+        self.base_address
+    }
+
+    /// Whether console goes through SBI instead of UART
+    fn is_sbi(&self) -> bool {
+        self.base_address == 0
+    }
+
     /// Initialize UART driver
     pub fn init(&mut self) {
+        if self.is_sbi() {
+            return;
+        }
         let ptr = self.base_address as *mut u8;
         unsafe {
             // First, set the word length, which
@@ -57,8 +146,9 @@ impl Uart {
             ptr.add(2).write_volatile(1 << 0);
 
             // Enable receiver buffer interrupts, which is at bit index
-            // 0 of the interrupt enable register (IER at offset 1).
-            ptr.add(1).write_volatile(1 << 0);
+            // 0 of the interrupt enable register (IER at offset 1), and
+            // transmitter holding register empty interrupts at bit index 1.
+            ptr.add(1).write_volatile(IER_RX_ENABLE | IER_TX_ENABLE);
 
             // If we cared about the divisor, the code below would set the divisor
             // from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
@@ -101,59 +191,105 @@ impl Uart {
         }
     }
 
-    /// Put a character into UART
-    pub fn put(&mut self, c: u8) {
-        let ptr = self.base_address as *mut u8;
-        loop {
-            // Wait until previous data is flushed
-            if unsafe { ptr.add(5).read_volatile() } & (1 << 5) != 0 {
-                break;
+    /// Line status register. SBI console is always ready to transmit.
+    fn lsr(&self) -> u8 {
+        if self.is_sbi() {
+            return LSR_TX_IDLE;
+        }
+        unsafe { (self.base_address as *const u8).add(5).read_volatile() }
+    }
+
+    /// Read interrupt identification register, which clears pending
+    /// transmitter holding register empty interrupt
+    fn ack_interrupt(&self) {
+        if self.is_sbi() {
+            return;
+        }
+        unsafe { (self.base_address as *const u8).add(2).read_volatile(); }
+    }
+
+    /// Move bytes from transmit ring into UART while it is ready
+    fn start(&mut self) {
+        while self.lsr() & LSR_TX_IDLE != 0 {
+            match self.tx.pop() {
+                Some(c) if self.is_sbi() => sbi::console_putchar(c),
+                Some(c) => unsafe { (self.base_address as *mut u8).write_volatile(c) },
+                None => { break; }
             }
         }
-        unsafe {
-            // Write data
-            ptr.add(0).write_volatile(c);
+    }
+
+    /// Put a character into transmit ring. Busy-waits if ring is full.
+    pub fn put(&mut self, c: u8) {
+        while self.tx.is_full() {
+            self.start();
+        }
+        self.tx.push(c);
+        self.start();
+    }
+
+    /// Transmit all bytes in ring, busy-waiting on UART
+    pub fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            self.start();
         }
     }
 
     /// Get a character from UART
-    pub fn get(&mut self) -> Option<u8> {
-        let ptr = self.base_address as *mut u8;
-        unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
-                // The DR bit is 0, meaning no data
-                None
-            } else {
-                // The DR bit is 1, meaning data!
-                Some(ptr.add(0).read_volatile())
+    fn get(&mut self) -> Option<u8> {
+        if self.is_sbi() {
+            sbi::console_getchar()
+        } else if self.lsr() & LSR_RX_READY == 0 {
+            // The DR bit is 0, meaning no data
+            None
+        } else {
+            // The DR bit is 1, meaning data!
+            Some(unsafe { (self.base_address as *const u8).read_volatile() })
+        }
+    }
+
+    /// Put `content` into transmit ring. Sleeps while ring is full,
+    /// except at boot time when there's no process to sleep.
+    pub fn write<'a>(mut uart: MutexGuard<'a, Uart>, content: &[u8]) -> MutexGuard<'a, Uart> {
+        for &c in content {
+            while uart.tx.is_full() {
+                uart.start();
+                if uart.tx.is_full() && my_cpu().process.is_some() {
+                    let chan = &uart.tx as *const Ring<TX_BUF_SIZE>;
+                    uart = sleep(chan, uart);
+                }
             }
+            uart.tx.push(c);
         }
+        uart.start();
+        uart
     }
 }
 
 /// Process UART interrupt. Should only be called when interrupt.
+///
+/// Received bytes are passed to TTY, and transmission continues from
+/// transmit ring.
 pub fn uartintr() {
-    let mut uart = UART().lock();
-    if let Some(c) = uart.get() {
-        drop(uart);
-        match c {
-            8 => {
-                // This is a backspace, so we
-                // essentially have to write a space and
-                // backup again:
-                print!("{} {}", 8 as char, 8 as char);
-            }
-            10 | 13 => {
-                // Newline or carriage-return
-                println!();
-            }
-            _ => {
-                print!("{}", c as char);
-                // if c == 97 {
-                //     crate::process::debug();
-                // }
+    let mut received = [0; RX_BATCH];
+    let mut len = 0;
+    {
+        let mut uart = UART().lock();
+        uart.ack_interrupt();
+        while len < RX_BATCH {
+            match uart.get() {
+                Some(c) => { received[len] = c; len += 1; }
+                None => { break; }
             }
         }
+        uart.start();
+        if !uart.tx.is_full() {
+            wakeup(&uart.tx as *const Ring<TX_BUF_SIZE>);
+        }
+    }
+    // TTY may echo, so UART lock is released
+    if len != 0 {
+        crate::tty::input(&received[..len]);
     }
 }
 
@@ -164,6 +300,53 @@ static __UART: Mutex<Uart> = Mutex::new(Uart::new(UART_BASE_ADDR), "uart driver"
 #[allow(non_snake_case)]
 pub fn UART() -> &'static Mutex<Uart> { &__UART }
 
+/// Initialize UART found in FDT, or SBI console
 pub unsafe fn init() {
-    UART().get().init();
-}
\ No newline at end of file
+    let machine = crate::fdt::machine();
+    let uart = UART().get();
+    uart.base_address = if sbi::enabled() && !machine.uart_in_fdt { 0 } else { machine.uart.base };
+    uart.init();
+}
+
+/// Interval of polling SBI console, in `mtime` ticks
+const SBI_POLL_INTERVAL: u64 = 100_000;
+
+/// Poll input of SBI console on hart 0, as it raises no interrupt, and
+/// schedule next poll
+pub fn poll() {
+    if hart_id() == 0 && UART().lock().is_sbi() {
+        uartintr();
+        crate::timer::set_event(crate::arch::ticks() + SBI_POLL_INTERVAL);
+    }
+}
+
+/// Transmit all pending output, such as before machine halts
+pub fn flush() {
+    UART().lock().flush();
+}
+pub mod tests {
+    use super::*;
+
+    pub fn tests() -> &'static [(&'static str, fn())] {
+        &[
+            ("ring", test_ring),
+        ]
+    }
+
+    /// Test ring buffer with wraparound
+    pub fn test_ring() {
+        let mut ring = Ring::<4>::new();
+        assert_eq!(ring.pop(), None);
+        for round in 0..3u8 {
+            for i in 0..4 {
+                assert!(ring.push(round * 4 + i));
+            }
+            assert!(ring.is_full());
+            assert!(!ring.push(0));
+            for i in 0..4 {
+                assert_eq!(ring.pop(), Some(round * 4 + i));
+            }
+            assert!(ring.is_empty());
+        }
+    }
+}
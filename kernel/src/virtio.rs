@@ -3,27 +3,65 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
-//! virt-io driver
-
-use crate::spinlock::{Mutex};
+//! virt-io MMIO transport
+//!
+//! virtio-mmio slots are found in FDT, at most `VIRTIO_MMIO_NUM` of them,
+//! sorted by address. All of them are probed at boot, and drivers are
+//! created for devices found.
+//!
+//! Both legacy (version 1) and modern (version 2) transports are supported.
+//! Legacy devices locate a virtqueue by its page number, while modern
+//! devices take physical addresses of descriptor table, avail ring and
+//! used ring separately.
+//!
+//! Size of each virtqueue is negotiated when it is set up, as the largest
+//! power of two within both `DESC_NUM` and the maximum of device.
+
+use crate::spinlock::Mutex;
 use crate::symbols::{PAGE_SIZE, PAGE_ORDER};
-use crate::process::{wakeup, sleep};
+use crate::process::wakeup;
+use crate::block;
 use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use crate::arch::__sync_synchronize;
 use crate::info;
 
-/// VIRTIO base address on QEMU RISC-V
+mod blk;
+pub use blk::*;
+mod net;
+pub use net::*;
+mod rng;
+pub use rng::*;
+mod console;
+pub use console::*;
+
+/// Default VIRTIO base address on QEMU RISC-V, used if not in FDT
 pub const VIRTIO_MMIO_BASE: usize = 0x10001000;
 
+/// Size of MMIO region of one virtio-mmio slot
+pub const VIRTIO_MMIO_SIZE: usize = 0x1000;
+
+/// Time to wait for a request before checking used ring, in case its
+/// interrupt is lost
+pub const IO_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(1);
+
+/// Maximum number of virtio-mmio slots, which is the number on QEMU RISC-V
+pub const VIRTIO_MMIO_NUM: usize = 8;
+
 /// VIRTIO MMIO address offset
 #[allow(non_camel_case_types)]
+#[derive(Clone, Copy)]
 pub enum VIRTIO_MMIO {
     MAGIC_VALUE = 0x0,
     VERSION = 0x4,
     DEVICE_ID = 0x8,
     VENDOR_ID = 0xc,
     DEVICE_FEATURES = 0x10,
+    DEVICE_FEATURES_SEL = 0x14,
     DRIVER_FEATURES = 0x20,
+    DRIVER_FEATURES_SEL = 0x24,
     GUEST_PAGE_SIZE = 0x28,
     QUEUE_SEL = 0x30,
     QUEUE_NUM_MAX = 0x34,
@@ -35,16 +73,224 @@ pub enum VIRTIO_MMIO {
     INTERRUPT_STATUS = 0x60,
     INTERRUPT_ACK = 0x64,
     STATUS = 0x70,
+    QUEUE_DESC_LOW = 0x80,
+    QUEUE_DESC_HIGH = 0x84,
+    QUEUE_DRIVER_LOW = 0x90,
+    QUEUE_DRIVER_HIGH = 0x94,
+    QUEUE_DEVICE_LOW = 0xa0,
+    QUEUE_DEVICE_HIGH = 0xa4,
+    CONFIG_GENERATION = 0xfc,
+    CONFIG = 0x100,
+}
+
+/// Virtio device ID
+#[allow(non_camel_case_types)]
+pub enum VIRTIO_DEVICE {
+    NONE = 0,
+    NET = 1,
+    BLOCK = 2,
+    CONSOLE = 3,
+    ENTROPY = 4,
+}
+
+/// Legacy virtio-mmio transport
+pub const VIRTIO_MMIO_VERSION_LEGACY: u32 = 1;
+
+/// Modern virtio-mmio transport
+pub const VIRTIO_MMIO_VERSION_MODERN: u32 = 2;
+
+/// Registers of one virtio-mmio slot
+#[derive(Clone, Copy)]
+pub struct Mmio {
+    base: usize,
 }
 
-impl VIRTIO_MMIO {
-    /// Get address of MMIO from enum
-    pub const fn val(self) -> usize {
-        self as usize + VIRTIO_MMIO_BASE
+impl Mmio {
+    pub const fn new(base: usize) -> Self {
+        Self { base }
     }
-    /// Get pointer to MMIO register from enum
-    pub const fn ptr(self) -> *mut u32 {
-        self.val() as _
+
+    /// MMIO of `slot`th virtio-mmio slot in FDT
+    pub fn slot(slot: usize) -> Self {
+        Self::new(crate::fdt::machine().virtio[slot].base)
+    }
+
+    /// Get pointer to MMIO register
+    pub const fn ptr(&self, reg: VIRTIO_MMIO) -> *mut u32 {
+        (self.base + reg as usize) as _
+    }
+
+    pub fn read(&self, reg: VIRTIO_MMIO) -> u32 {
+        unsafe { self.ptr(reg).read_volatile() }
+    }
+
+    pub fn write(&self, reg: VIRTIO_MMIO, val: u32) {
+        unsafe { self.ptr(reg).write_volatile(val) }
+    }
+
+    /// Pointer to device-specific configuration at `offset`
+    pub fn config<T>(&self, offset: usize) -> *mut T {
+        (self.base + VIRTIO_MMIO::CONFIG as usize + offset) as _
+    }
+
+    /// Read 64-bit field of device-specific configuration at `offset`.
+    ///
+    /// Device only accepts accesses up to 32 bits, so the field is read in
+    /// two halves. On modern devices, it is read again if configuration
+    /// changes in between.
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        loop {
+            let generation = self.generation();
+            let low = unsafe { self.config::<u32>(offset).read_volatile() } as u64;
+            let high = unsafe { self.config::<u32>(offset + 4).read_volatile() } as u64;
+            if self.generation() == generation {
+                return high << 32 | low;
+            }
+        }
+    }
+
+    /// Configuration generation, always 0 on legacy devices
+    fn generation(&self) -> u32 {
+        if self.is_legacy() {
+            0
+        } else {
+            self.read(VIRTIO_MMIO::CONFIG_GENERATION)
+        }
+    }
+
+    /// Whether device uses legacy transport
+    pub fn is_legacy(&self) -> bool {
+        self.read(VIRTIO_MMIO::VERSION) == VIRTIO_MMIO_VERSION_LEGACY
+    }
+
+    /// Write 64-bit `val` into register pair `low` and `high`
+    fn write_u64(&self, low: VIRTIO_MMIO, high: VIRTIO_MMIO, val: u64) {
+        self.write(low, val as u32);
+        self.write(high, (val >> 32) as u32);
+    }
+
+    /// Features offered by device
+    pub fn device_features(&self) -> u64 {
+        use VIRTIO_MMIO::*;
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES) as u64;
+        high << 32 | low
+    }
+
+    /// Tell device features accepted by driver
+    fn set_driver_features(&self, features: u64) {
+        use VIRTIO_MMIO::*;
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+    }
+
+    /// Device ID in this slot, `None` if the slot is not a virtio device
+    pub fn device_id(&self) -> Option<u32> {
+        use VIRTIO_MMIO::*;
+        if self.read(MAGIC_VALUE) != 0x74726976 {
+            return None;
+        }
+        if self.read(VENDOR_ID) != 0x554d4551 {
+            return None;
+        }
+        match self.read(DEVICE_ID) {
+            0 => None,
+            id => Some(id)
+        }
+    }
+
+    /// Reset device, negotiate features and set up page size.
+    ///
+    /// Only features in both `accepted` and device features are enabled.
+    /// `VIRTIO_F_VERSION_1` is always accepted on modern devices.
+    /// Queues should be set up afterwards, and then `driver_ok` is called.
+    pub fn begin_init(&self, accepted: u64) -> u64 {
+        use VIRTIO_MMIO::*;
+        use VIRTIO_CONFIG_S::*;
+
+        let version = self.read(VERSION);
+        let accepted = match version {
+            VIRTIO_MMIO_VERSION_LEGACY => accepted,
+            VIRTIO_MMIO_VERSION_MODERN => accepted | VIRTIO_FEATURE::F_VERSION_1.bit(),
+            _ => panic!("virtio at {:x}: unsupported version {}", self.base, version)
+        };
+
+        self.write(STATUS, 0);
+
+        let mut status: u32 = 0;
+        status |= ACKNOWLDGE.val();
+        self.write(STATUS, status);
+
+        status |= DRIVER.val();
+        self.write(STATUS, status);
+
+        let features = self.device_features() & accepted;
+        if version == VIRTIO_MMIO_VERSION_MODERN && features & VIRTIO_FEATURE::F_VERSION_1.bit() == 0 {
+            panic!("virtio at {:x}: modern device without VIRTIO_F_VERSION_1", self.base);
+        }
+        self.set_driver_features(features);
+
+        status |= FEATURES_OK.val();
+        self.write(STATUS, status);
+
+        if version == VIRTIO_MMIO_VERSION_LEGACY {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else if self.read(STATUS) & FEATURES_OK.val() == 0 {
+            panic!("virtio at {:x}: features not accepted", self.base);
+        }
+        features
+    }
+
+    /// Tell device `queue` is at `vq`, and negotiate size of `vq`
+    pub fn setup_queue(&self, queue: u32, vq: &mut VirtQueue) {
+        use VIRTIO_MMIO::*;
+
+        self.write(QUEUE_SEL, queue);
+        let legacy = self.is_legacy();
+        let in_use = if legacy { self.read(QUEUE_PFN) != 0 } else { self.read(QUEUE_READY) != 0 };
+        if in_use {
+            panic!("virtio at {:x}: queue {} in use", self.base, queue);
+        }
+        let max = self.read(QUEUE_NUM_MAX);
+        if max == 0 {
+            panic!("virtio at {:x}: no queue {}", self.base, queue);
+        }
+        // ring indices wrap at 2^16, so size should be a power of two
+        let num = (max as usize).min(DESC_NUM);
+        vq.num = 1 << (usize::BITS - 1 - num.leading_zeros());
+        self.write(QUEUE_NUM, vq.num as u32);
+        if legacy {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, ((vq as *const _ as usize) >> PAGE_ORDER) as u32);
+        } else {
+            self.write_u64(QUEUE_DESC_LOW, QUEUE_DESC_HIGH, &vq.desc as *const _ as u64);
+            self.write_u64(QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, &vq.avail as *const _ as u64);
+            self.write_u64(QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, &vq.used as *const _ as u64);
+            self.write(QUEUE_READY, 1);
+        }
+    }
+
+    /// Finish initialization
+    pub fn driver_ok(&self) {
+        use VIRTIO_MMIO::*;
+        use VIRTIO_CONFIG_S::*;
+        let status = self.read(STATUS) | DRIVER_OK.val();
+        self.write(STATUS, status);
+    }
+
+    /// Notify device of new buffers in `queue`
+    pub fn notify(&self, queue: u32) {
+        self.write(VIRTIO_MMIO::QUEUE_NOTIFY, queue);
+    }
+
+    /// Acknowledge interrupt
+    pub fn ack_interrupt(&self) {
+        let status = self.read(VIRTIO_MMIO::INTERRUPT_STATUS);
+        self.write(VIRTIO_MMIO::INTERRUPT_ACK, status & 0x3);
     }
 }
 
@@ -62,6 +308,7 @@ impl VIRTIO_CONFIG_S {
 
 #[allow(non_camel_case_types)]
 pub enum VIRTIO_FEATURE {
+    BLK_F_SEG_MAX = 2,
     BLK_F_RO = 5,
     BLK_F_SCSI = 7,
     BLK_F_CONFIG_WCE = 11,
@@ -69,15 +316,17 @@ pub enum VIRTIO_FEATURE {
     F_ANY_LAYOUT = 27,
     RING_F_INDIRECT_DESC = 28,
     RING_F_EVENT_IDX = 29,
+    F_VERSION_1 = 32,
 }
 
 impl VIRTIO_FEATURE {
-    pub fn bit(self) -> u32 {
-        (1 << self as usize) as u32
+    pub fn bit(self) -> u64 {
+        1 << self as usize
     }
 }
 
-pub const DESC_NUM: usize = 8;
+/// Maximum size of virtqueue
+pub const DESC_NUM: usize = 128;
 
 #[repr(C)]
 pub struct VRingDesc {
@@ -100,8 +349,10 @@ impl VRingDesc {
 
 pub const VRING_DESC_F_NEXT: u16 = 1;
 pub const VRING_DESC_F_WRITE: u16 = 2;
+pub const VRING_DESC_F_INDIRECT: u16 = 4;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct VRingUsedElem {
     pub id: u32,
     pub len: u32,
@@ -116,9 +367,6 @@ impl VRingUsedElem {
     }
 }
 
-pub const VIRTIO_BLK_T_IN: u32 = 0;
-pub const VIRTIO_BLK_T_OUT: u32 = 1;
-
 #[repr(C)]
 pub struct UsedArea {
     pub flags: u16,
@@ -136,74 +384,54 @@ impl UsedArea {
     }
 }
 
-pub struct InflightOp {
-    pub buf: Box<Buf>,
-    pub status: u8,
-}
-
 /// Size of avail array
 const AVAIL_SZ: usize = (PAGE_SIZE - DESC_NUM * core::mem::size_of::<VRingDesc>()) / core::mem::size_of::<u16>();
 
+/// A virtqueue in legacy layout
+///
+/// Device accesses this structure by physical address, so it should be
+/// boxed and never moved. Modern devices also accept this layout, as
+/// each part of it is located separately. Rings have room for `DESC_NUM`
+/// entries, and only the first `num` of them are used, so used ring stays
+/// at the next page for any size.
 #[repr(C)]
 #[repr(align(4096))]
-pub struct VirtIOData {
+pub struct VirtQueue {
     /// VIRTIO MMIO descriptor register
     pub desc: [VRingDesc; DESC_NUM],
     /// VIRTIO MMIO descriptor avail register (padding to page size)
     pub avail: [u16; AVAIL_SZ],
     /// VIRTIO MMIO descriptor used register
-    pub used: [UsedArea; DESC_NUM],
+    pub used: UsedArea,
 
     /// is descriptor free
     pub free: [bool; DESC_NUM],
     /// used index of used array
     pub used_idx: u16,
-    /// in-flight operations
-    pub info: [Option<InflightOp>; DESC_NUM],
-}
-
-pub struct VirtIO(Mutex<VirtIOData>);
-
-/// VIRTIO buffer size
-pub const BSIZE: usize = 1024;
-
-/// VIRTIO Buffer
-#[repr(C)]
-pub struct Buf {
-    pub valid: bool,
-    /// TODO: this can be removed
-    pub disk: i32,
-    /// device ID
-    pub dev: u32,
-    /// block number
-    pub blockno: u32,
-    /// buffer data
-    pub data: [u8; BSIZE],
+    /// Number of descriptors, negotiated in `Mmio::setup_queue`
+    pub num: usize,
 }
 
-impl Buf {
-    pub const fn new() -> Self {
-        Self {
-            valid: false,
-            disk: 0,
-            dev: 0,
-            blockno: 0,
-            data: [0; BSIZE],
-        }
+impl VirtQueue {
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            desc: [const { VRingDesc::new() }; DESC_NUM],
+            avail: [0; AVAIL_SZ],
+            used: UsedArea::new(),
+            free: [true; DESC_NUM],
+            used_idx: 0,
+            num: 0,
+        })
     }
-}
 
-#[repr(C)]
-pub struct BlkOutHdr {
-    pub blk_type: u32,
-    reserved: u32,
-    sector: usize,
-}
+    /// Empty slots of driver data, indexed by descriptor
+    pub fn slots<T>(&self) -> Vec<Option<T>> {
+        (0..self.num).map(|_| None).collect()
+    }
 
-impl VirtIOData {
     /// Free one descriptor
-    fn free_desc(&mut self, i: usize) {
-        if i >= DESC_NUM {
+    pub fn free_desc(&mut self, i: usize) {
+        if i >= self.num {
             panic!("invalid desc");
         }
         if self.free[i] {
@@ -215,8 +443,8 @@ impl VirtIOData {
     }
 
     /// Allocate one descriptor
-    fn alloc_desc(&mut self) -> Option<usize> {
-        for i in 0..DESC_NUM {
+    pub fn alloc_desc(&mut self) -> Option<usize> {
+        for i in 0..self.num {
             if self.free[i] {
                 self.free[i] = false;
                 return Some(i);
@@ -225,10 +453,10 @@ impl VirtIOData {
         None
     }
 
-    /// Allocate three descriptors, return array of indices
-    fn alloc3_desc(&mut self) -> Option<[usize; 3]> {
-        let mut idx = [0; 3];
-        for i in 0..3 {
+    /// Allocate `N` descriptors, return array of indices
+    pub fn alloc_descs<const N: usize>(&mut self) -> Option<[usize; N]> {
+        let mut idx = [0; N];
+        for i in 0..N {
             match self.alloc_desc() {
                 Some(x) => idx[i] = x,
                 None => {
@@ -242,233 +470,137 @@ impl VirtIOData {
         Some(idx)
     }
 
+    /// Allocate `n` descriptors, return vector of indices
+    pub fn alloc_chain(&mut self, n: usize) -> Option<Vec<usize>> {
+        let mut idx = Vec::with_capacity(n);
+        for _ in 0..n {
+            match self.alloc_desc() {
+                Some(x) => idx.push(x),
+                None => {
+                    for &i in idx.iter() {
+                        self.free_desc(i);
+                    }
+                    return None;
+                }
+            }
+        }
+        Some(idx)
+    }
+
     /// Free descriptor chain
-    fn free_chain(&mut self, mut i: usize) {
+    pub fn free_chain(&mut self, mut i: usize) {
         loop {
+            let flags = self.desc[i].flags;
+            let next = self.desc[i].next as usize;
             self.free_desc(i);
-            if self.desc[i].flags & VRING_DESC_F_NEXT != 0 {
-                i = self.desc[i].next as usize;
+            if flags & VRING_DESC_F_NEXT != 0 {
+                i = next;
             } else {
                 break;
             }
         }
     }
-}
 
-impl VirtIO {
-    pub const fn new() -> Self {
-        Self(
-            Mutex::new(VirtIOData {
-                desc: [const { VRingDesc::new() }; DESC_NUM],
-                avail: [0; AVAIL_SZ],
-                used: [const { UsedArea::new() }; DESC_NUM],
-                free: [true; DESC_NUM],
-                used_idx: 0,
-                info: [const { None }; DESC_NUM],
-            }, "vdisk"))
-    }
-
-    /// Initialize VIRTIO driver.
-    ///
-    /// Should be called in booting hart.
-    pub unsafe fn init(&mut self) {
-        use VIRTIO_MMIO::*;
-        use VIRTIO_CONFIG_S::*;
-        use VIRTIO_FEATURE::*;
+    /// Put descriptor chain beginning at `head` into avail ring.
+    /// Device should be notified afterwards.
+    pub fn submit(&mut self, head: usize) {
+        let idx_id = 2 + self.avail[1] as usize % self.num;
+        self.avail[idx_id] = head as u16;
 
-        let vio = self.0.get();
+        __sync_synchronize();
 
-        if MAGIC_VALUE.ptr().read_volatile() != 0x74726976 {
-            panic!("cannot find virtio disk: magic value");
-        }
-        if VERSION.ptr().read_volatile() != 1 {
-            panic!("cannot find virtio disk: version");
-        }
-        if DEVICE_ID.ptr().read_volatile() != 2 {
-            panic!("cannot find virtio disk: device id");
-        }
-        if VENDOR_ID.ptr().read_volatile() != 0x554d4551 {
-            panic!("cannot find virtio disk: vendor id");
-        }
-
-        let mut status: u32 = 0;
-        status |= ACKNOWLDGE.val();
-        STATUS.ptr().write_volatile(status);
-
-        status |= DRIVER.val();
-        STATUS.ptr().write_volatile(status);
-
-        let mut features: u32 = DEVICE_FEATURES.ptr().read_volatile();
-        features &= !BLK_F_RO.bit();
-        features &= !BLK_F_SCSI.bit();
-        features &= !BLK_F_CONFIG_WCE.bit();
-        features &= !BLK_F_MQ.bit();
-        features &= !F_ANY_LAYOUT.bit();
-        features &= !RING_F_EVENT_IDX.bit();
-        features &= !RING_F_INDIRECT_DESC.bit();
-        DEVICE_FEATURES.ptr().write_volatile(features);
-
-        status |= FEATURES_OK.val();
-        STATUS.ptr().write_volatile(status);
-
-        status |= DRIVER_OK.val();
-        STATUS.ptr().write_volatile(status);
+        self.avail[1] = self.avail[1].wrapping_add(1);
 
-        GUEST_PAGE_SIZE.ptr().write_volatile(PAGE_SIZE as u32);
-
-        QUEUE_SEL.ptr().write_volatile(0);
-        let max = QUEUE_NUM_MAX.ptr().read_volatile();
-        if max == 0 {
-            panic!("virtio disk has no queue");
-        }
-        if max < DESC_NUM as u32 {
-            panic!("virtio disk max queue too short {} < {}", max, DESC_NUM);
-        }
-        QUEUE_NUM.ptr().write_volatile(DESC_NUM as u32);
-
-        QUEUE_PFN.ptr().write_volatile(((vio as *mut _ as usize) >> PAGE_ORDER) as u32);
+        __sync_synchronize();
+    }
 
-        for i in 0..DESC_NUM {
-            vio.free[i] = true;
+    /// Take next element device has put into used ring
+    pub fn pop_used(&mut self) -> Option<VRingUsedElem> {
+        __sync_synchronize();
+        let device_idx = unsafe { core::ptr::read_volatile(&self.used.id) };
+        if self.used_idx == device_idx {
+            return None;
         }
+        let elem = self.used.elems[self.used_idx as usize % self.num];
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Some(elem)
     }
+}
 
-    /// Read-write operation
-    fn rw(&mut self, mut b: Box<Buf>, write: bool) -> Box<Buf> {
-        use VIRTIO_MMIO::*;
+/// A driver of virtio device
+pub trait VirtIODevice: Send + Sync {
+    /// Process interrupt from device. Interrupt is already acknowledged.
+    fn intr(&self);
+}
 
-        let sector = b.blockno as usize * (BSIZE / 512);
+/// Drivers of virtio-mmio slots, only modified at boot
+static mut DEVICES: [Option<(Mmio, Arc<dyn VirtIODevice>)>; VIRTIO_MMIO_NUM] = [const { None }; VIRTIO_MMIO_NUM];
 
-        let mut vio = self.0.lock();
+/// Lock for probing devices
+static PROBE_LOCK: Mutex<()> = Mutex::new((), "virtio probe");
 
-        let idx: [usize; 3] = loop {
-            if let Some(idx) = vio.alloc3_desc() {
-                break idx;
+/// Probe all virtio-mmio slots and create drivers for devices found.
+///
+/// Should be called in booting hart.
+pub unsafe fn init() {
+    let _lock = PROBE_LOCK.lock();
+    let mut disks = 0;
+    for slot in 0..crate::fdt::machine().virtio_count {
+        let mmio = Mmio::slot(slot);
+        let device: Arc<dyn VirtIODevice> = match mmio.device_id() {
+            None => { continue; }
+            Some(id) if id == VIRTIO_DEVICE::BLOCK as u32 => {
+                let blk = Arc::new(VirtIOBlk::new(mmio));
+                let name = format!("vd{}", (b'a' + disks) as char);
+                info!("    {}: virtio-blk at slot {} ({}), {} sectors",
+                    name, slot, if mmio.is_legacy() { "legacy" } else { "modern" }, blk.capacity());
+                block::register(name, blk.clone());
+                disks += 1;
+                blk
             }
-            vio = sleep(&vio.free[0] as *const _, vio);
-        };
-
-        // info!("3 desc: {:?}", idx);
-
-        let buf0 = BlkOutHdr {
-            reserved: 0,
-            sector,
-            blk_type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
-        };
-
-
-        // VIRTIO 5.2.6.4
-        // MUST use a single 8-byte descriptor containing type, reserved and sector,
-        // followed by descriptors for data, then finally a separate 1-byte descriptor for status.
-
-        {
-            let desc0 = &mut vio.desc[idx[0]];
-            desc0.addr = &buf0 as *const _ as usize;
-            desc0.len = core::mem::size_of::<BlkOutHdr>() as u32;
-            desc0.flags = VRING_DESC_F_NEXT;
-            desc0.next = idx[1] as u16;
-        }
-
-        {
-            let desc1 = &mut vio.desc[idx[1]];
-            desc1.addr = b.data.as_mut_ptr() as usize;
-            desc1.len = BSIZE as u32;
-            desc1.flags = if write { 0 } else { VRING_DESC_F_WRITE };
-            desc1.flags |= VRING_DESC_F_NEXT;
-            desc1.next = idx[2] as u16;
-        }
-
-        b.disk = 1;
-        vio.info[idx[0]] = Some(InflightOp {
-            buf: b,
-            status: 0,
-        });
-
-        {
-            let addr = &vio.info[idx[0]].as_ref().unwrap().status as *const _ as usize;
-            {
-                let desc2 = &mut vio.desc[idx[2]];
-
-                desc2.addr = addr;
-                desc2.len = 1;
-                desc2.flags = VRING_DESC_F_WRITE;
-                desc2.next = 0;
+            Some(id) if id == VIRTIO_DEVICE::NET as u32 => {
+                let dev = Arc::new(VirtIONet::new(mmio));
+                let mac = crate::net::NetDevice::mac(&*dev);
+                info!("    virtio-net at slot {}, mac {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                    slot, mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]);
+                crate::net::register(dev.clone());
+                dev
             }
-
-            let idx_id = 2 + vio.avail[1] as usize % DESC_NUM;
-            vio.avail[idx_id] = idx[0] as u16;
-
-            __sync_synchronize();
-
-            vio.avail[1] += 1;
-
-            unsafe { QUEUE_NOTIFY.ptr().write_volatile(0); }
-            // info!("{:x}", &vio.info[idx[0]].as_ref().unwrap().status as *const _ as usize);
-            let buf_addr = &*vio.info[idx[0]].as_ref().unwrap().buf as *const _;
-            // info!("sleep {:x} id={}", buf_addr as usize, vio.info[idx[0]].as_ref().unwrap().status);
-            while vio.info[idx[0]].as_ref().unwrap().buf.disk == 1 {
-                vio = sleep(buf_addr, vio);
+            Some(id) if id == VIRTIO_DEVICE::ENTROPY as u32 => {
+                let dev = Arc::new(VirtIORng::new(mmio));
+                info!("    virtio-rng at slot {}", slot);
+                crate::random::register_source(dev.clone());
+                dev
             }
-        }
-        let result = vio.info[idx[0]].take();
-        unsafe { vio.info[idx[0]] = core::mem::zeroed(); }
-        vio.info[idx[0]] = None;
-        vio.free_chain(idx[0]);
-        result.unwrap().buf
-    }
-
-    /// Read from device and block number
-    pub fn read(&mut self, dev: u32, blockno: u32) -> Box<Buf> {
-        let mut buf = Box::new(Buf::new());
-        buf.dev = dev;
-        buf.blockno = blockno;
-        self.rw(buf, false)
-    }
-
-    /// Write buffer to disk
-    pub fn write(&mut self, buf: Box<Buf>) {
-        self.rw(buf, true);
+            Some(id) if id == VIRTIO_DEVICE::CONSOLE as u32 => {
+                info!("    virtio-console at slot {} ({})", slot, if mmio.is_legacy() { "legacy" } else { "modern" });
+                VirtIOConsole::new(mmio, slot)
+            }
+            Some(id) => {
+                info!("    unsupported virtio device {} at slot {}", id, slot);
+                continue;
+            }
+        };
+        DEVICES[slot] = Some((mmio, device));
     }
 }
 
-/// VirtIO driver object
-static mut __VIRTIO: VirtIO = VirtIO::new();
-
-/// Global function to get an instance of VirtIO driver
-#[allow(non_snake_case)]
-pub fn VIRTIO() -> &'static mut VirtIO { unsafe { &mut __VIRTIO } }
-
-pub unsafe fn init() {
-    VIRTIO().init();
+/// IRQ of `slot`th virtio-mmio slot
+pub fn irq_of(slot: usize) -> u32 {
+    crate::fdt::machine().virtio[slot].irq
 }
 
-
-/// VIRTIO interrupt
-pub fn virtiointr() {
-    
-    let mut disk = VIRTIO().0.lock();
-    while disk.used_idx as usize % DESC_NUM != disk.used[0].id as usize % DESC_NUM {
-
-        let id = disk.used[0].elems[disk.used_idx as usize].id as usize;
-
-        if disk.info[id].is_none() {
-            panic!("invalid id");
-        }
-
-        let info = disk.info[id].as_mut().unwrap();
-
-        // info!("processing 0x{:x}", &*info.buf as *const _ as usize);
-
-        if info.status != 0 {
-            panic!("virtio_disk_intr status status={} id={}", info.status, id);
-        }
-
-        info.buf.disk = 0;
-
-        wakeup(&*info.buf);
-
-        disk.used_idx = ((disk.used_idx + 1) as usize % DESC_NUM) as u16;
+/// VIRTIO interrupt of `irq`. Returns `false` if `irq` is not of any slot.
+pub fn virtiointr(irq: u32) -> bool {
+    let slot = match crate::fdt::machine().virtio().iter().position(|d| d.irq == irq) {
+        Some(slot) => slot,
+        None => { return false; }
+    };
+    if let Some((mmio, device)) = unsafe { &DEVICES[slot] } {
+        mmio.ack_interrupt();
+        device.intr();
     }
+    true
 }
 
 pub mod tests {
@@ -477,42 +609,64 @@ pub mod tests {
     pub fn tests() -> &'static [(&'static str, fn())] {
         &[
             ("memory layout", test_memory_layout),
-            ("read and write", test_rw)
+            ("small queue", test_small_queue),
+            ("read and write", test_rw),
+            ("batched read", test_read_blocks),
         ]
     }
 
-    /// Test virtio memory layout
+    /// Test virtqueue memory layout
     pub fn test_memory_layout() {
-        let virtio = VIRTIO().0.lock();
-        assert_eq!(&virtio.desc as *const _ as usize % PAGE_SIZE, 0);
-        assert_eq!(&virtio.used as *const _ as usize % PAGE_SIZE, 0);
-        assert_eq!(&virtio.used as *const _ as usize - &virtio.desc as *const _ as usize, PAGE_SIZE);
+        let vq = VirtQueue::new();
+        assert_eq!(&vq.desc as *const _ as usize % PAGE_SIZE, 0);
+        assert_eq!(&vq.used as *const _ as usize % PAGE_SIZE, 0);
+        assert_eq!(&vq.used as *const _ as usize - &vq.desc as *const _ as usize, PAGE_SIZE);
     }
 
-    use crate::{print, println};
+    /// Test descriptors and rings of a queue smaller than `DESC_NUM`
+    pub fn test_small_queue() {
+        let mut vq = VirtQueue::new();
+        vq.num = 8;
+        assert!(vq.alloc_chain(9).is_none());
+        let idx = vq.alloc_chain(8).unwrap();
+        assert!(idx.iter().all(|&i| i < 8));
+        assert!(vq.alloc_desc().is_none());
+        for _ in 0..10 {
+            vq.submit(idx[0]);
+        }
+        assert_eq!(vq.avail[1], 10);
+        assert_eq!(vq.avail[2 + 1], idx[0] as u16);
+        assert_eq!(vq.avail[2 + 8], 0);
+        vq.free_desc(idx[0]);
+        assert_eq!(vq.alloc_desc(), Some(idx[0]));
+    }
 
     /// Test read and write
     pub fn test_rw() {
-        let virtio = VIRTIO();
-        {
-            // I don't know why this is needed
-            // but I have to set all descriptors to free again here
-            let mut vio = virtio.0.lock();
-            for i in 0..DESC_NUM {
-                vio.free[i] = true;
-            }
+        if block::count() == 0 {
+            info!("      no disk attached, skipped");
+            return;
         }
-        let b = virtio.read(1, 0);
-        unsafe { info!("size: {}", core::ptr::read(b.data.as_ptr() as *const usize)); }
-        unsafe { info!("offset: {}", core::ptr::read(b.data.as_ptr().add(8) as *const usize)); }
-        info!("filename: ");
-        for i in 16..b.data.len() {
-            let d = b.data[i];
-            if d == 0 {
-                break;
-            }
-            print!("{}", b.data[i] as char);
+        // write first block back and read it again
+        let b = block::read(0, 0).unwrap();
+        let data = b.data;
+        block::write(b).unwrap();
+        let b = block::read(0, 0).unwrap();
+        assert!(b.data == data);
+        let sectors = block::get(0).sectors();
+        assert_eq!(block::read(0, (sectors / 2) as u32).err(), Some(block::IoError::OutOfRange));
+    }
+
+    /// Test reading consecutive blocks in one batch
+    pub fn test_read_blocks() {
+        if block::count() == 0 {
+            info!("      no disk attached, skipped");
+            return;
+        }
+        let bufs = block::read_blocks(0, 0, 40).unwrap();
+        for (i, b) in bufs.iter().enumerate() {
+            assert_eq!(b.blockno, i as u32);
+            assert!(b.data == block::read(0, i as u32).unwrap().data);
         }
-        println!();
     }
 }
@@ -0,0 +1,343 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virtio-blk driver
+//!
+//! Requests wait in `pending` until the virtqueue has room for them. A
+//! request covers consecutive sectors in several data segments, and a new
+//! segment right after the last pending request is merged into it. The
+//! interrupt handler completes requests and submits pending ones.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use crate::block::{BlockDevice, IoError, SECTOR_SIZE};
+use crate::process::wakeup;
+use crate::timer::{deadline_after, sleep_until};
+use crate::warn;
+use crate::spinlock::{Mutex, MutexGuard};
+use crate::virtio::{Mmio, VirtIODevice, VirtQueue, VRingDesc, VIRTIO_FEATURE, IO_TIMEOUT};
+use crate::virtio::{VRING_DESC_F_NEXT, VRING_DESC_F_WRITE, VRING_DESC_F_INDIRECT};
+
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+
+/// Offset of `capacity` in device configuration
+const VIRTIO_BLK_CONFIG_CAPACITY: usize = 0;
+
+/// Offset of `seg_max` in device configuration
+const VIRTIO_BLK_CONFIG_SEG_MAX: usize = 12;
+
+/// Maximum number of data segments in one request
+const MAX_SEGS: usize = 32;
+
+#[repr(C)]
+pub struct BlkOutHdr {
+    pub blk_type: u32,
+    reserved: u32,
+    sector: usize,
+}
+
+/// A request of consecutive sectors
+///
+/// Device accesses `hdr`, `status` and `table`, so requests are boxed.
+struct Request {
+    hdr: BlkOutHdr,
+    status: u8,
+    /// Data segments of (address, length)
+    segs: Vec<(usize, usize)>,
+    /// Number of sectors in all segments
+    sectors: usize,
+    /// Indirect descriptor table, built on submission
+    table: Vec<VRingDesc>,
+    /// Set by interrupt handler when device finishes this request
+    done: bool,
+    /// Number of segments whose callers have not collected the result
+    waiters: usize,
+}
+
+impl Request {
+    fn is_write(&self) -> bool {
+        self.hdr.blk_type == VIRTIO_BLK_T_OUT
+    }
+}
+
+pub struct VirtIOBlkData {
+    vq: Box<VirtQueue>,
+    /// Requests not yet collected by all callers, by request ID
+    requests: BTreeMap<usize, Box<Request>>,
+    /// Requests not yet submitted
+    pending: VecDeque<usize>,
+    /// Submitted requests, indexed by first descriptor
+    inflight: Vec<Option<usize>>,
+    /// ID of next new request
+    next_id: usize,
+    /// Whether indirect descriptors are negotiated
+    indirect: bool,
+    /// Maximum number of data segments in one request
+    max_segs: usize,
+}
+
+/// virtio-blk device
+pub struct VirtIOBlk {
+    mmio: Mmio,
+    /// Number of sectors
+    capacity: usize,
+    data: Mutex<VirtIOBlkData>,
+}
+
+impl VirtIOBlkData {
+    /// Queue a segment at `sector`, returns ID of request holding it
+    fn enqueue(&mut self, sector: usize, addr: usize, len: usize, write: bool) -> usize {
+        if let Some(&id) = self.pending.back() {
+            let req = self.requests.get_mut(&id).unwrap();
+            if req.is_write() == write
+                && req.hdr.sector + req.sectors == sector
+                && req.segs.len() < self.max_segs {
+                req.segs.push((addr, len));
+                req.sectors += len / SECTOR_SIZE;
+                req.waiters += 1;
+                return id;
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.insert(id, Box::new(Request {
+            hdr: BlkOutHdr {
+                reserved: 0,
+                sector,
+                blk_type: if write { VIRTIO_BLK_T_OUT } else { VIRTIO_BLK_T_IN },
+            },
+            status: 0xff,
+            segs: alloc::vec![(addr, len)],
+            sectors: len / SECTOR_SIZE,
+            table: Vec::new(),
+            done: false,
+            waiters: 1,
+        }));
+        self.pending.push_back(id);
+        id
+    }
+
+    /// Put pending requests into virtqueue while there are free descriptors.
+    /// Returns whether any request is submitted.
+    fn submit_pending(&mut self) -> bool {
+        let mut submitted = false;
+        while let Some(&id) = self.pending.front() {
+            let req = self.requests.get_mut(&id).unwrap();
+
+            // VIRTIO 5.2.6.4
+            // MUST use a single 8-byte descriptor containing type, reserved and sector,
+            // followed by descriptors for data, then finally a separate 1-byte descriptor for status.
+            let data_flags = if req.is_write() { 0 } else { VRING_DESC_F_WRITE };
+            let mut chain = Vec::with_capacity(req.segs.len() + 2);
+            chain.push((&req.hdr as *const _ as usize, core::mem::size_of::<BlkOutHdr>() as u32, 0));
+            for &(addr, len) in req.segs.iter() {
+                chain.push((addr, len as u32, data_flags));
+            }
+            chain.push((&req.status as *const _ as usize, 1, VRING_DESC_F_WRITE));
+
+            let head = if self.indirect {
+                let head = match self.vq.alloc_desc() {
+                    Some(head) => head,
+                    None => { break; }
+                };
+                let last = chain.len() - 1;
+                req.table = chain.iter().enumerate().map(|(i, &(addr, len, flags))| VRingDesc {
+                    addr,
+                    len,
+                    flags: if i == last { flags } else { flags | VRING_DESC_F_NEXT },
+                    next: if i == last { 0 } else { i as u16 + 1 },
+                }).collect();
+                let desc = &mut self.vq.desc[head];
+                desc.addr = req.table.as_ptr() as usize;
+                desc.len = (req.table.len() * core::mem::size_of::<VRingDesc>()) as u32;
+                desc.flags = VRING_DESC_F_INDIRECT;
+                desc.next = 0;
+                head
+            } else {
+                let idx = match self.vq.alloc_chain(chain.len()) {
+                    Some(idx) => idx,
+                    None => { break; }
+                };
+                for (i, &(addr, len, flags)) in chain.iter().enumerate() {
+                    let desc = &mut self.vq.desc[idx[i]];
+                    desc.addr = addr;
+                    desc.len = len;
+                    if i + 1 < idx.len() {
+                        desc.flags = flags | VRING_DESC_F_NEXT;
+                        desc.next = idx[i + 1] as u16;
+                    } else {
+                        desc.flags = flags;
+                        desc.next = 0;
+                    }
+                }
+                idx[0]
+            };
+
+            self.vq.submit(head);
+            self.inflight[head] = Some(id);
+            self.pending.pop_front();
+            submitted = true;
+        }
+        submitted
+    }
+
+    /// Mark all finished requests in used ring as done
+    fn process_used(&mut self) {
+        while let Some(elem) = self.vq.pop_used() {
+            let head = elem.id as usize;
+            let id = match self.inflight[head].take() {
+                Some(id) => id,
+                None => panic!("invalid id")
+            };
+            self.vq.free_chain(head);
+            let req = self.requests.get_mut(&id).unwrap();
+            req.done = true;
+            wakeup(&**req as *const Request);
+        }
+    }
+}
+
+impl VirtIOBlk {
+    /// Initialize virtio-blk device in `mmio`
+    pub fn new(mmio: Mmio) -> Self {
+        let features = mmio.begin_init(
+            VIRTIO_FEATURE::RING_F_INDIRECT_DESC.bit() | VIRTIO_FEATURE::BLK_F_SEG_MAX.bit()
+        );
+        let mut vq = VirtQueue::new();
+        mmio.setup_queue(0, &mut vq);
+        mmio.driver_ok();
+        let capacity = mmio.config_u64(VIRTIO_BLK_CONFIG_CAPACITY) as usize;
+        let indirect = features & VIRTIO_FEATURE::RING_F_INDIRECT_DESC.bit() != 0;
+        let mut max_segs = if features & VIRTIO_FEATURE::BLK_F_SEG_MAX.bit() != 0 {
+            let seg_max = unsafe { mmio.config::<u32>(VIRTIO_BLK_CONFIG_SEG_MAX).read_volatile() } as usize;
+            seg_max.clamp(1, MAX_SEGS)
+        } else {
+            MAX_SEGS
+        };
+        if !indirect {
+            // header and status take two more descriptors in the ring
+            max_segs = max_segs.min(vq.num.saturating_sub(2)).max(1);
+        }
+        let inflight = vq.slots();
+        Self {
+            mmio,
+            capacity,
+            data: Mutex::new(VirtIOBlkData {
+                vq,
+                requests: BTreeMap::new(),
+                pending: VecDeque::new(),
+                inflight,
+                next_id: 0,
+                indirect,
+                max_segs,
+            }, "vdisk"),
+        }
+    }
+
+    /// Number of sectors
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Submit pending requests and notify device
+    fn kick(&self, vio: &mut VirtIOBlkData) {
+        if vio.submit_pending() {
+            self.mmio.notify(0);
+        }
+    }
+
+    /// Complete finished requests and submit pending ones
+    fn complete(&self, vio: &mut VirtIOBlkData) {
+        vio.process_used();
+        self.kick(vio);
+    }
+
+    /// Wait until request `id` is done and collect status of one segment in it
+    fn collect<'a>(&self, mut vio: MutexGuard<'a, VirtIOBlkData>, id: usize) -> (MutexGuard<'a, VirtIOBlkData>, u8) {
+        let mut deadline = deadline_after(IO_TIMEOUT);
+        loop {
+            let req = vio.requests.get_mut(&id).unwrap();
+            if req.done {
+                req.waiters -= 1;
+                let (status, waiters) = (req.status, req.waiters);
+                if waiters == 0 {
+                    vio.requests.remove(&id);
+                }
+                return (vio, status);
+            }
+            let chan = &**req as *const Request;
+            let timed_out;
+            (vio, timed_out) = sleep_until(chan, vio, deadline);
+            if timed_out {
+                warn!("virtio disk: request {} timed out, checking used ring", id);
+                self.complete(&mut vio);
+                deadline = deadline_after(IO_TIMEOUT);
+            }
+        }
+    }
+
+    /// Read-write consecutive sectors beginning at `sector` from or into
+    /// segments of (address, length)
+    fn rw(&self, sector: usize, segs: &[(usize, usize)], write: bool) -> Result<(), IoError> {
+        let mut sectors = 0;
+        for &(_, len) in segs {
+            if len % SECTOR_SIZE != 0 {
+                panic!("virtio disk: unaligned length {}", len);
+            }
+            sectors += len / SECTOR_SIZE;
+        }
+        if sector > self.capacity || sectors > self.capacity - sector {
+            return Err(IoError::OutOfRange);
+        }
+
+        let mut vio = self.data.lock();
+        let mut ids = Vec::with_capacity(segs.len());
+        let mut cur = sector;
+        for &(addr, len) in segs {
+            ids.push(vio.enqueue(cur, addr, len, write));
+            cur += len / SECTOR_SIZE;
+        }
+        self.kick(&mut vio);
+
+        for id in ids {
+            let (guard, status) = self.collect(vio, id);
+            vio = guard;
+            if status != 0 {
+                panic!("virtio disk: status {} at sector {}", status, sector);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtIOBlk {
+    fn sectors(&self) -> usize {
+        self.capacity
+    }
+
+    fn read_sectors(&self, sector: usize, data: &mut [u8]) -> Result<(), IoError> {
+        self.rw(sector, &[(data.as_mut_ptr() as usize, data.len())], false)
+    }
+
+    fn write_sectors(&self, sector: usize, data: &[u8]) -> Result<(), IoError> {
+        self.rw(sector, &[(data.as_ptr() as usize, data.len())], true)
+    }
+
+    fn read_vectored(&self, sector: usize, bufs: &mut [&mut [u8]]) -> Result<(), IoError> {
+        let segs: Vec<(usize, usize)> = bufs.iter_mut()
+            .map(|buf| (buf.as_mut_ptr() as usize, buf.len()))
+            .collect();
+        self.rw(sector, &segs, false)
+    }
+}
+
+impl VirtIODevice for VirtIOBlk {
+    fn intr(&self) {
+        let mut vio = self.data.lock();
+        self.complete(&mut vio);
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virtio-console driver
+//!
+//! With `VIRTIO_CONSOLE_F_MULTIPORT`, device announces ports through control
+//! queue, and each port has its own pair of queues. Ports are registered as
+//! devices `vport<slot>p<id>`, and also by their name if host gives one
+//! (e.g. `-device virtserialport,name=debug` becomes `/dev/debug`). Console
+//! ports are additionally registered as `hvc<n>`.
+//!
+//! Queues of at most `MAX_PORTS` ports are set up at boot, and ports with
+//! larger ID are ignored.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use crate::arch::time;
+use crate::file::{self, Device};
+use crate::info;
+use crate::process::{my_cpu, sleep, wakeup};
+use crate::spinlock::Mutex;
+use crate::virtio::{Mmio, VirtIODevice, VirtQueue, VRING_DESC_F_WRITE};
+
+/// Device supports multiple ports and control queue
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+/// Offset of `max_nr_ports` in device configuration
+const VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS: usize = 4;
+
+const CONTROL_RX_QUEUE: u32 = 2;
+const CONTROL_TX_QUEUE: u32 = 3;
+
+/// Control events, in `event` field of `virtio_console_control`
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// Size of `virtio_console_control`
+const CONTROL_LEN: usize = 8;
+
+/// Maximum number of ports supported
+const MAX_PORTS: usize = 8;
+
+/// Size of receive buffer
+const RX_BUF_SIZE: usize = 512;
+
+/// Number of receive buffers of each port and of control queue, fewer if
+/// receive queue is smaller
+const RX_BUFFERS: usize = 8;
+
+/// Time to wait for ports announced at boot
+const BOOT_PROBE_NANOS: u128 = 100_000_000;
+
+/// Receive and transmit queue of port `id`
+const fn port_queues(id: usize) -> (u32, u32) {
+    if id == 0 {
+        (0, 1)
+    } else {
+        (2 * id as u32 + 2, 2 * id as u32 + 3)
+    }
+}
+
+/// A pair of receive and transmit queues with their buffers
+struct Channel {
+    rx: Box<VirtQueue>,
+    tx: Box<VirtQueue>,
+    /// Receive buffers, indexed by descriptor
+    rx_bufs: Vec<Option<Box<[u8; RX_BUF_SIZE]>>>,
+    /// Data being sent, indexed by descriptor
+    tx_bufs: Vec<Option<Vec<u8>>>,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            rx: VirtQueue::new(),
+            tx: VirtQueue::new(),
+            rx_bufs: Vec::new(),
+            tx_bufs: Vec::new(),
+        }
+    }
+
+    /// Tell device queues `rx` and `tx` are at this channel, and give
+    /// receive buffers to device
+    fn setup(&mut self, mmio: &Mmio, rx: u32, tx: u32) {
+        mmio.setup_queue(rx, &mut self.rx);
+        mmio.setup_queue(tx, &mut self.tx);
+        self.rx_bufs = self.rx.slots();
+        self.tx_bufs = self.tx.slots();
+        for _ in 0..RX_BUFFERS.min(self.rx.num) {
+            self.post_rx(Box::new([0; RX_BUF_SIZE]));
+        }
+    }
+
+    /// Give receive buffer `buf` to device
+    fn post_rx(&mut self, buf: Box<[u8; RX_BUF_SIZE]>) {
+        let idx = self.rx.alloc_desc().unwrap();
+        let desc = &mut self.rx.desc[idx];
+        desc.addr = buf.as_ptr() as usize;
+        desc.len = RX_BUF_SIZE as u32;
+        desc.flags = VRING_DESC_F_WRITE;
+        desc.next = 0;
+        self.rx_bufs[idx] = Some(buf);
+        self.rx.submit(idx);
+    }
+
+    /// Free buffers of sent data
+    fn reclaim_tx(&mut self) {
+        while let Some(elem) = self.tx.pop_used() {
+            let idx = elem.id as usize;
+            self.tx_bufs[idx] = None;
+            self.tx.free_desc(idx);
+        }
+    }
+
+    /// Put `data` into transmit queue. Returns `false` if queue is full.
+    fn send(&mut self, data: Vec<u8>) -> bool {
+        self.reclaim_tx();
+        let idx = match self.tx.alloc_desc() {
+            Some(idx) => idx,
+            None => { return false; }
+        };
+        let desc = &mut self.tx.desc[idx];
+        desc.addr = data.as_ptr() as usize;
+        desc.len = data.len() as u32;
+        desc.flags = 0;
+        desc.next = 0;
+        self.tx_bufs[idx] = Some(data);
+        self.tx.submit(idx);
+        true
+    }
+
+    /// Take received data, and give buffers back to device.
+    /// Returns whether any buffer is given back.
+    fn receive(&mut self, mut f: impl FnMut(&[u8])) -> bool {
+        let mut reposted = false;
+        while let Some(elem) = self.rx.pop_used() {
+            let idx = elem.id as usize;
+            let buf = self.rx_bufs[idx].take().unwrap();
+            self.rx.free_desc(idx);
+            f(&buf[..(elem.len as usize).min(RX_BUF_SIZE)]);
+            self.post_rx(buf);
+            reposted = true;
+        }
+        reposted
+    }
+}
+
+/// State of a port
+struct Port {
+    channel: Channel,
+    /// Whether device has added this port
+    added: bool,
+    /// Data received, not yet read
+    input: VecDeque<u8>,
+}
+
+pub struct VirtIOConsoleData {
+    ports: Vec<Port>,
+    /// Control queues, only with `VIRTIO_CONSOLE_F_MULTIPORT`
+    control: Option<Channel>,
+}
+
+/// Port events which need registering devices, done without device lock
+enum PortEvent {
+    Added(usize),
+    Console(usize),
+    Named(usize, String),
+}
+
+/// virtio-console device
+pub struct VirtIOConsole {
+    mmio: Mmio,
+    /// virtio-mmio slot of device, used in port names
+    slot: usize,
+    /// This device, for creating port devices in interrupt
+    this: Weak<VirtIOConsole>,
+    data: Mutex<VirtIOConsoleData>,
+}
+
+/// Number of console ports registered as `hvc<n>`
+static CONSOLES: Mutex<usize> = Mutex::new(0, "virtio-console hvc");
+
+/// Control message of `id`, `event` and `value`
+fn control_message(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(CONTROL_LEN);
+    msg.extend_from_slice(&id.to_le_bytes());
+    msg.extend_from_slice(&event.to_le_bytes());
+    msg.extend_from_slice(&value.to_le_bytes());
+    msg
+}
+
+impl VirtIOConsole {
+    /// Initialize virtio-console device in `mmio` of `slot`
+    pub fn new(mmio: Mmio, slot: usize) -> Arc<Self> {
+        let features = mmio.begin_init(VIRTIO_CONSOLE_F_MULTIPORT);
+        let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        let nr_ports = if multiport {
+            let max = unsafe { mmio.config::<u32>(VIRTIO_CONSOLE_CONFIG_MAX_NR_PORTS).read_volatile() };
+            (max as usize).min(MAX_PORTS).max(1)
+        } else {
+            1
+        };
+        let mut data = VirtIOConsoleData {
+            ports: (0..nr_ports).map(|_| Port {
+                channel: Channel::new(),
+                added: !multiport,
+                input: VecDeque::new(),
+            }).collect(),
+            control: if multiport { Some(Channel::new()) } else { None },
+        };
+        for (id, port) in data.ports.iter_mut().enumerate() {
+            let (rx, tx) = port_queues(id);
+            port.channel.setup(&mmio, rx, tx);
+        }
+        if let Some(control) = &mut data.control {
+            control.setup(&mmio, CONTROL_RX_QUEUE, CONTROL_TX_QUEUE);
+        }
+        mmio.driver_ok();
+        for id in 0..nr_ports {
+            mmio.notify(port_queues(id).0);
+        }
+        if multiport {
+            mmio.notify(CONTROL_RX_QUEUE);
+        }
+
+        let console = Arc::new_cyclic(|this| Self {
+            mmio,
+            slot,
+            this: this.clone(),
+            data: Mutex::new(data, "virtio-console"),
+        });
+        if multiport {
+            console.send_control(&mut console.data.lock(), 0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+            // no interrupt at boot, poll control queue for ports announced
+            let begin = time().as_nanos();
+            while time().as_nanos() - begin < BOOT_PROBE_NANOS {
+                console.process_control();
+            }
+        } else {
+            console.register_port(0);
+        }
+        console
+    }
+
+    /// Send control message, dropped if control queue is full
+    fn send_control(&self, data: &mut VirtIOConsoleData, id: u32, event: u16, value: u16) {
+        if let Some(control) = &mut data.control {
+            if control.send(control_message(id, event, value)) {
+                self.mmio.notify(CONTROL_TX_QUEUE);
+            }
+        }
+    }
+
+    /// Process control messages from device
+    fn process_control(&self) {
+        let mut events = Vec::new();
+        {
+            let mut data = self.data.lock();
+            let mut messages = Vec::new();
+            let reposted = match &mut data.control {
+                Some(control) => {
+                    control.reclaim_tx();
+                    control.receive(|msg| messages.push(Vec::from(msg)))
+                }
+                None => { return; }
+            };
+            if reposted {
+                self.mmio.notify(CONTROL_RX_QUEUE);
+            }
+            for msg in messages {
+                if msg.len() < CONTROL_LEN {
+                    continue;
+                }
+                let id = u32::from_le_bytes([msg[0], msg[1], msg[2], msg[3]]);
+                let event = u16::from_le_bytes([msg[4], msg[5]]);
+                let port = id as usize;
+                match event {
+                    VIRTIO_CONSOLE_DEVICE_ADD if port < data.ports.len() => {
+                        if !data.ports[port].added {
+                            data.ports[port].added = true;
+                            events.push(PortEvent::Added(port));
+                        }
+                        self.send_control(&mut data, id, VIRTIO_CONSOLE_PORT_READY, 1);
+                    }
+                    VIRTIO_CONSOLE_DEVICE_ADD => {
+                        // no queues for this port
+                        self.send_control(&mut data, id, VIRTIO_CONSOLE_PORT_READY, 0);
+                    }
+                    VIRTIO_CONSOLE_DEVICE_REMOVE if port < data.ports.len() => {
+                        data.ports[port].added = false;
+                        wakeup(&data.ports[port].input as *const VecDeque<u8>);
+                    }
+                    VIRTIO_CONSOLE_CONSOLE_PORT if port < data.ports.len() => {
+                        events.push(PortEvent::Console(port));
+                        self.send_control(&mut data, id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+                    }
+                    VIRTIO_CONSOLE_PORT_OPEN if port < data.ports.len() => {
+                        // guest side of port is always open
+                        self.send_control(&mut data, id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+                    }
+                    VIRTIO_CONSOLE_PORT_NAME if port < data.ports.len() => {
+                        if let Ok(name) = core::str::from_utf8(&msg[CONTROL_LEN..]) {
+                            let name = name.trim_end_matches('\0');
+                            if !name.is_empty() {
+                                events.push(PortEvent::Named(port, String::from(name)));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // registry takes its own lock, so device lock is released
+        for event in events {
+            match event {
+                PortEvent::Added(id) => self.register_port(id),
+                PortEvent::Console(id) => {
+                    let mut consoles = CONSOLES.lock();
+                    self.register_name(format!("hvc{}", *consoles), id);
+                    *consoles += 1;
+                }
+                PortEvent::Named(id, name) => self.register_name(name, id),
+            }
+        }
+    }
+
+    /// Register port `id` as `vport<slot>p<id>`
+    fn register_port(&self, id: usize) {
+        self.register_name(format!("vport{}p{}", self.slot, id), id);
+    }
+
+    /// Register port `id` as `/dev/<name>`
+    fn register_name(&self, name: String, id: usize) {
+        info!("    virtio-console port {} at slot {}: /dev/{}", id, self.slot, name);
+        let console = self.this.upgrade().unwrap();
+        file::register(name, Arc::new(ConsolePort { console, id }));
+    }
+
+    /// Process data received by ports
+    fn process_ports(&self) {
+        let mut data = self.data.lock();
+        for (id, port) in data.ports.iter_mut().enumerate() {
+            port.channel.reclaim_tx();
+            let input = &mut port.input;
+            if port.channel.receive(|buf| input.extend(buf.iter())) {
+                self.mmio.notify(port_queues(id).0);
+                wakeup(&port.input as *const VecDeque<u8>);
+            }
+        }
+    }
+
+    /// Read from port `id`, sleep until some data is received
+    fn read(&self, id: usize, content: &mut [u8]) -> i32 {
+        let mut data = self.data.lock();
+        loop {
+            let port = &mut data.ports[id];
+            if !port.input.is_empty() || content.is_empty() {
+                let len = content.len().min(port.input.len());
+                for (i, b) in port.input.drain(..len).enumerate() {
+                    content[i] = b;
+                }
+                return len as i32;
+            }
+            if !port.added {
+                return -1;
+            }
+            if my_cpu().process.is_none() {
+                return 0;
+            }
+            let chan = &port.input as *const VecDeque<u8>;
+            data = sleep(chan, data);
+        }
+    }
+
+    /// Write to port `id`, sleep while transmit queue is full
+    fn write(&self, id: usize, content: &[u8]) -> i32 {
+        let mut data = self.data.lock();
+        if !data.ports[id].added {
+            return -1;
+        }
+        loop {
+            let channel = &mut data.ports[id].channel;
+            if channel.send(Vec::from(content)) {
+                self.mmio.notify(port_queues(id).1);
+                return content.len() as i32;
+            }
+            if my_cpu().process.is_none() {
+                // poll used ring at boot time
+                continue;
+            }
+            let chan = &channel.tx.free[0] as *const bool;
+            data = sleep(chan, data);
+        }
+    }
+}
+
+impl VirtIODevice for VirtIOConsole {
+    fn intr(&self) {
+        self.process_control();
+        self.process_ports();
+    }
+}
+
+/// A port of virtio-console, opened as device
+pub struct ConsolePort {
+    console: Arc<VirtIOConsole>,
+    id: usize,
+}
+
+impl Device for ConsolePort {
+    fn read(&self, content: &mut [u8]) -> i32 {
+        self.console.read(self.id, content)
+    }
+
+    fn write(&self, content: &[u8]) -> i32 {
+        self.console.write(self.id, content)
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virtio-net driver
+//!
+//! Receive queue is filled with `RX_BUFFERS` buffers, or fewer if the queue
+//! is smaller, which are given back to device after frames in them are
+//! passed to network stack. Frames are dropped if transmit queue is full.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::net::{self, MacAddr, NetDevice};
+use crate::spinlock::Mutex;
+use crate::virtio::{Mmio, VirtIODevice, VirtQueue, VIRTIO_FEATURE, VRING_DESC_F_WRITE};
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+/// Device has given MAC address
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Offset of `mac` in device configuration
+const VIRTIO_NET_CONFIG_MAC: usize = 0;
+
+/// Size of receive buffer, holding header and a full ethernet frame
+const RX_BUF_SIZE: usize = 2048;
+
+/// Number of receive buffers
+const RX_BUFFERS: usize = 32;
+
+/// Size of `virtio_net_hdr` in legacy devices without `VIRTIO_NET_F_MRG_RXBUF`
+const NET_HDR_LEN_LEGACY: usize = 10;
+
+/// Size of `virtio_net_hdr` in modern devices, including `num_buffers`
+const NET_HDR_LEN_MODERN: usize = 12;
+
+pub struct VirtIONetData {
+    rx: Box<VirtQueue>,
+    tx: Box<VirtQueue>,
+    /// Receive buffers, indexed by descriptor
+    rx_bufs: Vec<Option<Box<[u8; RX_BUF_SIZE]>>>,
+    /// Frames being sent, indexed by descriptor
+    tx_bufs: Vec<Option<Vec<u8>>>,
+}
+
+/// virtio-net device
+pub struct VirtIONet {
+    mmio: Mmio,
+    mac: MacAddr,
+    /// Size of header before each frame
+    hdr_len: usize,
+    data: Mutex<VirtIONetData>,
+}
+
+impl VirtIONetData {
+    /// Give receive buffer `buf` to device
+    fn post_rx(&mut self, buf: Box<[u8; RX_BUF_SIZE]>) {
+        let idx = self.rx.alloc_desc().unwrap();
+        let desc = &mut self.rx.desc[idx];
+        desc.addr = buf.as_ptr() as usize;
+        desc.len = RX_BUF_SIZE as u32;
+        desc.flags = VRING_DESC_F_WRITE;
+        desc.next = 0;
+        self.rx_bufs[idx] = Some(buf);
+        self.rx.submit(idx);
+    }
+
+    /// Free buffers of sent frames
+    fn reclaim_tx(&mut self) {
+        while let Some(elem) = self.tx.pop_used() {
+            let idx = elem.id as usize;
+            self.tx_bufs[idx] = None;
+            self.tx.free_desc(idx);
+        }
+    }
+}
+
+impl VirtIONet {
+    /// Initialize virtio-net device in `mmio`
+    pub fn new(mmio: Mmio) -> Self {
+        let features = mmio.begin_init(VIRTIO_NET_F_MAC | VIRTIO_FEATURE::F_ANY_LAYOUT.bit());
+        let hdr_len = if features & VIRTIO_FEATURE::F_VERSION_1.bit() != 0 {
+            NET_HDR_LEN_MODERN
+        } else {
+            NET_HDR_LEN_LEGACY
+        };
+        let mut rx = VirtQueue::new();
+        let mut tx = VirtQueue::new();
+        mmio.setup_queue(RX_QUEUE, &mut rx);
+        mmio.setup_queue(TX_QUEUE, &mut tx);
+        let mut data = VirtIONetData {
+            rx_bufs: rx.slots(),
+            tx_bufs: tx.slots(),
+            rx,
+            tx,
+        };
+        for _ in 0..RX_BUFFERS.min(data.rx.num) {
+            data.post_rx(Box::new([0; RX_BUF_SIZE]));
+        }
+        mmio.driver_ok();
+        mmio.notify(RX_QUEUE);
+
+        let mut mac = [0; 6];
+        if features & VIRTIO_NET_F_MAC != 0 {
+            for (i, b) in mac.iter_mut().enumerate() {
+                *b = unsafe { mmio.config::<u8>(VIRTIO_NET_CONFIG_MAC + i).read_volatile() };
+            }
+        } else {
+            // locally administered address
+            mac = [0x02, 0, 0, 0, 0, 0x01];
+        }
+        Self {
+            mmio,
+            mac,
+            hdr_len,
+            data: Mutex::new(data, "virtio-net"),
+        }
+    }
+}
+
+impl NetDevice for VirtIONet {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) {
+        let mut data = self.data.lock();
+        data.reclaim_tx();
+        let idx = match data.tx.alloc_desc() {
+            Some(idx) => idx,
+            None => { return; }
+        };
+        // header of all zero means no offloading
+        let mut buf = alloc::vec![0; self.hdr_len + frame.len()];
+        buf[self.hdr_len..].copy_from_slice(frame);
+        let desc = &mut data.tx.desc[idx];
+        desc.addr = buf.as_ptr() as usize;
+        desc.len = buf.len() as u32;
+        desc.flags = 0;
+        desc.next = 0;
+        data.tx_bufs[idx] = Some(buf);
+        data.tx.submit(idx);
+        self.mmio.notify(TX_QUEUE);
+    }
+}
+
+impl VirtIODevice for VirtIONet {
+    fn intr(&self) {
+        let mut frames = Vec::new();
+        {
+            let mut reposted = false;
+            let mut data = self.data.lock();
+            data.reclaim_tx();
+            while let Some(elem) = data.rx.pop_used() {
+                let idx = elem.id as usize;
+                let buf = data.rx_bufs[idx].take().unwrap();
+                data.rx.free_desc(idx);
+                let len = (elem.len as usize).min(RX_BUF_SIZE);
+                if len > self.hdr_len {
+                    frames.push(Vec::from(&buf[self.hdr_len..len]));
+                }
+                data.post_rx(buf);
+                reposted = true;
+            }
+            if reposted {
+                self.mmio.notify(RX_QUEUE);
+            }
+        }
+        // network stack may send frames, so device lock is released
+        for frame in frames {
+            net::receive(&frame);
+        }
+    }
+}
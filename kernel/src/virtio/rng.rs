@@ -0,0 +1,131 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! virtio-entropy driver
+//!
+//! Each request is a single device-writable buffer, which device fills
+//! with random bytes, possibly fewer than requested. A request timed out
+//! at boot keeps its buffer until device completes it.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::arch;
+use crate::process::{my_cpu, wakeup};
+use crate::timer::{deadline_after, sleep_until};
+use crate::random::EntropySource;
+use crate::spinlock::Mutex;
+use crate::virtio::{Mmio, VirtIODevice, VirtQueue, IO_TIMEOUT, VRING_DESC_F_WRITE};
+
+const REQUEST_QUEUE: u32 = 0;
+
+/// Maximum bytes of one request
+const MAX_REQUEST: usize = 256;
+
+pub struct VirtIORngData {
+    vq: Box<VirtQueue>,
+    /// Bytes written by device of completed requests, indexed by descriptor
+    done: Vec<Option<usize>>,
+    /// Buffers of requests, indexed by descriptor
+    bufs: Vec<Option<Vec<u8>>>,
+    /// Whether request is given up, indexed by descriptor
+    abandoned: Vec<bool>,
+}
+
+impl VirtIORngData {
+    fn process_used(&mut self) {
+        while let Some(elem) = self.vq.pop_used() {
+            let idx = elem.id as usize;
+            if self.abandoned[idx] {
+                self.abandoned[idx] = false;
+                self.bufs[idx] = None;
+                self.vq.free_desc(idx);
+                continue;
+            }
+            self.done[idx] = Some(elem.len as usize);
+            wakeup(&self.done[idx] as *const Option<usize>);
+        }
+    }
+}
+
+/// virtio-rng device
+pub struct VirtIORng {
+    mmio: Mmio,
+    data: Mutex<VirtIORngData>,
+}
+
+impl VirtIORng {
+    /// Initialize virtio-rng device in `mmio`
+    pub fn new(mmio: Mmio) -> Self {
+        mmio.begin_init(0);
+        let mut vq = VirtQueue::new();
+        mmio.setup_queue(REQUEST_QUEUE, &mut vq);
+        mmio.driver_ok();
+        let data = VirtIORngData {
+            done: vq.slots(),
+            bufs: vq.slots(),
+            abandoned: alloc::vec![false; vq.num],
+            vq,
+        };
+        Self {
+            mmio,
+            data: Mutex::new(data, "virtio-rng"),
+        }
+    }
+}
+
+impl EntropySource for VirtIORng {
+    fn fill(&self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(MAX_REQUEST);
+        let mut bytes: Vec<u8> = alloc::vec![0; len];
+        let mut data = self.data.lock();
+        let idx = match data.vq.alloc_desc() {
+            Some(idx) => idx,
+            None => { return 0; }
+        };
+        let desc = &mut data.vq.desc[idx];
+        desc.addr = bytes.as_mut_ptr() as usize;
+        desc.len = len as u32;
+        desc.flags = VRING_DESC_F_WRITE;
+        desc.next = 0;
+        data.done[idx] = None;
+        data.bufs[idx] = Some(bytes);
+        data.vq.submit(idx);
+        self.mmio.notify(REQUEST_QUEUE);
+        let mut deadline = deadline_after(IO_TIMEOUT);
+        let written = loop {
+            if let Some(written) = data.done[idx].take() {
+                break written.min(len);
+            }
+            if my_cpu().process.is_none() {
+                // no process to sleep at boot time, poll used ring instead
+                data.process_used();
+                if data.done[idx].is_none() && arch::ticks() >= deadline {
+                    // device still owns buffer, freed when it completes
+                    data.abandoned[idx] = true;
+                    return 0;
+                }
+            } else {
+                let chan = &data.done[idx] as *const Option<usize>;
+                let timed_out;
+                (data, timed_out) = sleep_until(chan, data, deadline);
+                if timed_out {
+                    // interrupt may be lost
+                    data.process_used();
+                    deadline = deadline_after(IO_TIMEOUT);
+                }
+            }
+        };
+        data.vq.free_desc(idx);
+        let bytes = data.bufs[idx].take().unwrap();
+        buf[..written].copy_from_slice(&bytes[..written]);
+        written
+    }
+}
+
+impl VirtIODevice for VirtIORng {
+    fn intr(&self) {
+        self.data.lock().process_used();
+    }
+}
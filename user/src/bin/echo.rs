@@ -0,0 +1,48 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use user::println;
+use user::syscall::{exit, socket, bind, listen, accept, recv, send, close, SockAddrIn};
+use user::constant::{AF_INET, SOCK_STREAM};
+
+/// Port of echo service
+const ECHO_PORT: u16 = 7;
+
+/// Buffer aligned so that it never crosses page boundary, as required by syscalls
+#[repr(align(512))]
+struct Buffer([u8; 256]);
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    let fd = socket(AF_INET, SOCK_STREAM);
+    if fd < 0 {
+        println!("echo: no network");
+        exit(1);
+    }
+    if bind(fd, &SockAddrIn::new([0, 0, 0, 0], ECHO_PORT)) < 0 || listen(fd, 4) < 0 {
+        println!("echo: failed to listen on port {}", ECHO_PORT);
+        exit(1);
+    }
+    println!("echo: listening on port {}", ECHO_PORT);
+    let mut buf = Buffer([0; 256]);
+    loop {
+        let conn = accept(fd);
+        if conn < 0 {
+            continue;
+        }
+        loop {
+            let n = recv(conn, &mut buf.0);
+            if n <= 0 {
+                break;
+            }
+            send(conn, &buf.0[..n as usize]);
+        }
+        close(conn);
+    }
+}
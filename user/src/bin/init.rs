@@ -8,7 +8,7 @@
 #![feature(format_args_nl)]
 
 use user::println;
-use user::syscall::{fork, open, dup, exec};
+use user::syscall::{fork, open, dup, exec, exit};
 
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
@@ -21,7 +21,16 @@ pub unsafe extern "C" fn _start() -> ! {
     if p == 0 {
         println!("calling test1 in child...");
         exec("/test1", &["test1", "test2"]);
+        exit(-1);
     } else {
+        if fork() == 0 {
+            exec("/echo", &["echo"]);
+            exit(-1);
+        }
+        if fork() == 0 {
+            exec("/ipc", &["ipc"]);
+            exit(-1);
+        }
         loop {}
     }
 }
@@ -0,0 +1,69 @@
+// Copyright (c) 2020 Alex Chi
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+#![no_std]
+#![no_main]
+#![feature(format_args_nl)]
+
+use user::println;
+use user::syscall::{exit, fork, socket, bind, listen, accept, connect, recv, send, close, unlink, SockAddrUn, SockAddrIn};
+use user::constant::{AF_UNIX, AF_INET, SOCK_STREAM, SOCK_DGRAM};
+
+const PATH: &str = "/tmp/ipc.sock";
+
+/// Buffer aligned so that it never crosses page boundary, as required by syscalls
+#[repr(align(64))]
+struct Buffer([u8; 32]);
+
+/// Receive a message and print it
+fn print_recv(who: &str, fd: i32) {
+    let mut buf = Buffer([0; 32]);
+    let n = recv(fd, &mut buf.0);
+    if n < 0 {
+        println!("ipc: {} failed to receive", who);
+        exit(1);
+    }
+    let msg = core::str::from_utf8(&buf.0[..n as usize]).unwrap_or("?");
+    println!("ipc: {} received \"{}\"", who, msg);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    // Unix-domain stream between parent and child
+    let server = socket(AF_UNIX, SOCK_STREAM);
+    if bind(server, &SockAddrUn::new(PATH)) < 0 || listen(server, 1) < 0 {
+        println!("ipc: failed to listen on {}", PATH);
+        exit(1);
+    }
+    let udp = socket(AF_INET, SOCK_DGRAM);
+    bind(udp, &SockAddrIn::new([127, 0, 0, 1], 9000));
+    if fork() == 0 {
+        close(server);
+        close(udp);
+        let fd = socket(AF_UNIX, SOCK_STREAM);
+        if connect(fd, &SockAddrUn::new(PATH)) < 0 {
+            println!("ipc: failed to connect to {}", PATH);
+            exit(1);
+        }
+        send(fd, b"ping over unix socket");
+        print_recv("client", fd);
+        close(fd);
+
+        // UDP through loopback
+        let fd = socket(AF_INET, SOCK_DGRAM);
+        connect(fd, &SockAddrIn::new([127, 0, 0, 1], 9000));
+        send(fd, b"ping over loopback");
+        exit(0);
+    }
+    let conn = accept(server);
+    print_recv("server", conn);
+    send(conn, b"pong");
+    print_recv("server", udp);
+    close(conn);
+    close(server);
+    close(udp);
+    unlink(PATH);
+    exit(0);
+}
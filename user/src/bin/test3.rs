@@ -8,10 +8,21 @@
 #![feature(format_args_nl)]
 
 use user::println;
-use user::syscall::exit;
+use user::syscall::{exit, open, read, write, close, unlink};
+use user::constant::{STDOUT, O_CREATE};
 
 #[no_mangle]
 pub unsafe extern "C" fn _start() -> ! {
     println!("test3!");
+    let fd = open("/tmp/test3.txt", O_CREATE);
+    write(fd, b"hello from tmpfs");
+    close(fd);
+    let fd = open("/tmp/test3.txt", 0);
+    let mut data = [0; 16];
+    read(fd, &mut data);
+    write(STDOUT, &data);
+    write(STDOUT, b"\n");
+    close(fd);
+    unlink("/tmp/test3.txt");
     exit(0);
 }
@@ -8,3 +8,51 @@
 pub const STDIN: i32 = 0;
 pub const STDOUT: i32 = 1;
 pub const STDERR: i32 = 2;
+
+/// Error returned negated by syscalls: no such file
+pub const ENOENT: i32 = 2;
+/// Error returned negated by syscalls: bad executable
+pub const ENOEXEC: i32 = 8;
+/// Error returned negated by syscalls: out of memory
+pub const ENOMEM: i32 = 12;
+
+/// Open flag: create file if not exist
+pub const O_CREATE: i32 = 0x200;
+/// Open flag: truncate file to zero length
+pub const O_TRUNC: i32 = 0x400;
+
+/// Socket domain of Unix-domain sockets
+pub const AF_UNIX: i32 = 1;
+/// Socket domain of IPv4
+pub const AF_INET: i32 = 2;
+/// Socket type of reliable byte stream
+pub const SOCK_STREAM: i32 = 1;
+/// Socket type of datagram
+pub const SOCK_DGRAM: i32 = 2;
+
+/// TTY mode: Ctrl-C kills foreground process
+pub const ISIG: i32 = 0o1;
+/// TTY mode: canonical mode with line editing
+pub const ICANON: i32 = 0o2;
+/// TTY mode: echo input
+pub const ECHO: i32 = 0o10;
+
+/// ioctl request: get TTY mode
+pub const TCGETS: usize = 0x5401;
+/// ioctl request: set TTY mode
+pub const TCSETS: usize = 0x5402;
+/// ioctl request: set foreground process of TTY
+pub const TIOCSPGRP: usize = 0x5410;
+
+/// Clock of wall-clock time
+pub const CLOCK_REALTIME: i32 = 0;
+/// Clock of time since boot
+pub const CLOCK_MONOTONIC: i32 = 1;
+
+/// reboot command: power off
+pub const REBOOT_POWER_OFF: i32 = 0;
+/// reboot command: restart
+pub const REBOOT_RESTART: i32 = 1;
+
+/// setrlimit resource: maximum size of stack in bytes
+pub const RLIMIT_STACK: i32 = 3;
@@ -22,3 +22,15 @@
 #define SYS_sbrk 18
 #define SYS_sleep 19
 #define SYS_uptime 20
+#define SYS_socket 21
+#define SYS_bind 22
+#define SYS_listen 23
+#define SYS_accept 24
+#define SYS_connect 25
+#define SYS_send 26
+#define SYS_recv 27
+#define SYS_getrandom 28
+#define SYS_ioctl 29
+#define SYS_clock_gettime 30
+#define SYS_reboot 31
+#define SYS_setrlimit 32
@@ -14,6 +14,7 @@
 //! Usage of syscalls is listed in their corresponding sub-page.
 
 use crate::syscall_internal::*;
+use crate::constant::{AF_INET, AF_UNIX};
 use core::ptr::null;
 
 /// Exit current process with exit code `code`.
@@ -53,14 +54,17 @@ pub const EXEC_MAX_ARGS: usize = 10;
 /// Replace current process image with the new one
 /// in the filesystem.
 ///
-/// This function will not return.
+/// This function doesn't return on success. Pages of new image are
+/// loaded on first touch. Returns `-ENOENT` if `path` is not found,
+/// `-ENOEXEC` if it's not a valid ELF file, or `-ENOMEM` if memory is
+/// low, and current image is kept.
 ///
 /// # Examples
 /// ```
 /// use user::syscall::exec;
 /// exec("/init", &[]);
 /// ```
-pub fn exec(path: &str, args: &[&str]) -> ! {
+pub fn exec(path: &str, args: &[&str]) -> i32 {
     let arg_cnt = args.len();
     let mut args_sz = [0; EXEC_MAX_ARGS];
     let mut args_ptr = [null(); EXEC_MAX_ARGS];
@@ -81,7 +85,8 @@ pub fn exec(path: &str, args: &[&str]) -> ! {
 
 /// Write `content` to file descriptor `fd`.
 ///
-/// Returns number of characters written. A negative return value means error while writing.
+/// Returns number of characters written. A negative return value means error while writing,
+/// such as `-ENOMEM` when a file in `/tmp` can't grow.
 ///
 /// # Examples
 /// ```
@@ -99,8 +104,8 @@ pub fn write(fd: i32, content: &[u8]) -> i32 {
 
 /// Read `content` from file descriptor `fd`.
 ///
-/// You may read a maximum of `content.len()` characters from `fd`.
-/// Returns number of characters read.
+/// You may read a maximum of `content.len()` characters from `fd`, and at
+/// most 1024 characters at a time. Returns number of characters read.
 pub fn read(fd: i32, content: &mut [u8]) -> i32 {
     unsafe {
         __read(fd,
@@ -111,12 +116,15 @@ pub fn read(fd: i32, content: &mut [u8]) -> i32 {
 
 /// Open file of `path` with `mode`.
 ///
+/// `mode` may contain `O_CREATE` and `O_TRUNC` in `constant` module.
 /// This function returns file descriptor. Negative value means error.
 ///
 /// # Examples
 /// ```
 /// use user::syscall::open;
+/// use user::constant::O_CREATE;
 /// let fd = open("/console", 0);
+/// let fd = open("/tmp/log", O_CREATE);
 /// ```
 pub fn open(path: &str, mode: i32) -> i32 {
     unsafe {
@@ -152,3 +160,275 @@ pub fn dup(fd: i32) -> i32 {
 pub fn wait(pid: i32) -> i32 {
     unsafe { __wait(pid) }
 }
+
+/// Kill process `pid`, which exits when it returns to user space.
+///
+/// Returns -1 if there's no such process. Init can't be killed.
+pub fn kill(pid: i32) -> i32 {
+    unsafe { __kill(pid) }
+}
+
+/// Remove file or empty directory of `path`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::unlink;
+/// unlink("/tmp/log");
+/// ```
+pub fn unlink(path: &str) -> i32 {
+    unsafe { __unlink(path.as_ptr(), path.len() as i32) }
+}
+
+/// Create directory of `path`.
+///
+/// # Examples
+/// ```
+/// use user::syscall::mkdir;
+/// mkdir("/tmp/dir");
+/// ```
+pub fn mkdir(path: &str) -> i32 {
+    unsafe { __mkdir(path.as_ptr(), path.len() as i32) }
+}
+
+/// Socket address passed to `bind` and `connect`
+pub trait SockAddr {}
+
+/// IPv4 socket address
+#[repr(C)]
+pub struct SockAddrIn {
+    family: u16,
+    /// port in network byte order
+    port: [u8; 2],
+    addr: [u8; 4],
+    zero: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub const fn new(addr: [u8; 4], port: u16) -> Self {
+        Self {
+            family: AF_INET as u16,
+            port: port.to_be_bytes(),
+            addr,
+            zero: [0; 8],
+        }
+    }
+}
+
+impl SockAddr for SockAddrIn {}
+
+/// Maximum length of path in `SockAddrUn`
+pub const UNIX_PATH_MAX: usize = 108;
+
+/// Unix-domain socket address
+#[repr(C)]
+pub struct SockAddrUn {
+    family: u16,
+    /// NUL-terminated path
+    path: [u8; UNIX_PATH_MAX],
+}
+
+impl SockAddrUn {
+    /// Address of `path`, which is truncated to `UNIX_PATH_MAX - 1` bytes
+    pub fn new(path: &str) -> Self {
+        let mut addr = Self {
+            family: AF_UNIX as u16,
+            path: [0; UNIX_PATH_MAX],
+        };
+        let len = path.len().min(UNIX_PATH_MAX - 1);
+        addr.path[..len].copy_from_slice(&path.as_bytes()[..len]);
+        addr
+    }
+}
+
+impl SockAddr for SockAddrUn {}
+
+/// Create a socket of `domain` and `ty`.
+///
+/// Returns file descriptor of the socket. Negative value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::socket;
+/// use user::constant::{AF_INET, SOCK_STREAM};
+/// let fd = socket(AF_INET, SOCK_STREAM);
+/// ```
+pub fn socket(domain: i32, ty: i32) -> i32 {
+    unsafe { __socket(domain, ty) }
+}
+
+/// Bind socket `fd` to local address `addr`. Port 0 means any port.
+///
+/// Unix-domain sockets are bound to a new file at their path.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{bind, SockAddrIn, SockAddrUn};
+/// bind(fd, &SockAddrIn::new([0, 0, 0, 0], 7));
+/// bind(unix_fd, &SockAddrUn::new("/tmp/server.sock"));
+/// ```
+pub fn bind<A: SockAddr>(fd: i32, addr: &A) -> i32 {
+    unsafe { __bind(fd, addr as *const A as *const u8, core::mem::size_of::<A>() as i32) }
+}
+
+/// Listen for connections on socket `fd`, with at most `backlog`
+/// connections waiting for `accept`.
+pub fn listen(fd: i32, backlog: i32) -> i32 {
+    unsafe { __listen(fd, backlog) }
+}
+
+/// Wait for a connection on listening socket `fd`.
+///
+/// Returns file descriptor of the connection. Negative value means error.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{listen, accept};
+/// listen(fd, 4);
+/// let conn = accept(fd);
+/// ```
+pub fn accept(fd: i32) -> i32 {
+    unsafe { __accept(fd) }
+}
+
+/// Connect socket `fd` to remote address `addr`.
+///
+/// Stream sockets wait until connection is established. Returns -1 if
+/// peer refuses it, or doesn't answer after SYN is sent again several times
+/// with backoff, about a minute in total.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{connect, SockAddrIn};
+/// connect(fd, &SockAddrIn::new([10, 0, 2, 2], 8080));
+/// ```
+pub fn connect<A: SockAddr>(fd: i32, addr: &A) -> i32 {
+    unsafe { __connect(fd, addr as *const A as *const u8, core::mem::size_of::<A>() as i32) }
+}
+
+/// Send `content` through socket `fd`.
+///
+/// Returns number of bytes sent. Negative value means error.
+pub fn send(fd: i32, content: &[u8]) -> i32 {
+    unsafe { __send(fd, content.as_ptr(), content.len() as i32) }
+}
+
+/// Receive from socket `fd` into `content`.
+///
+/// Returns number of bytes received. 0 means peer has closed
+/// the connection, and negative value means error.
+pub fn recv(fd: i32, content: &mut [u8]) -> i32 {
+    unsafe { __recv(fd, content.as_mut_ptr(), content.len() as i32) }
+}
+
+/// Fill `content` with random bytes from kernel random number generator.
+///
+/// Never blocks, as generator is seeded at boot. Returns number of bytes
+/// filled, which is `content.len()` on success.
+pub fn getrandom(content: &mut [u8]) -> i32 {
+    unsafe { __getrandom(content.as_mut_ptr(), content.len() as i32) }
+}
+
+/// Control device `fd` by `request` with `arg`.
+///
+/// Console supports `TCGETS`, which returns current mode, `TCSETS`, which
+/// sets mode to `arg`, and `TIOCSPGRP`, which makes `arg` the process
+/// interrupted by Ctrl-C.
+///
+/// # Examples
+/// ```
+/// use user::constant::*;
+/// use user::syscall::ioctl;
+/// let mode = ioctl(STDIN, TCGETS, 0);
+/// // read keys one by one without echo
+/// ioctl(STDIN, TCSETS, (mode & !(ICANON | ECHO)) as usize);
+/// ```
+pub fn ioctl(fd: i32, request: usize, arg: usize) -> i32 {
+    unsafe { __ioctl(fd, request, arg) }
+}
+
+/// Time in seconds and nanoseconds, as `struct timespec`
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TimeSpec {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+/// Get time of `clock` into `ts`.
+///
+/// `CLOCK_REALTIME` is wall-clock time since Unix epoch, and
+/// `CLOCK_MONOTONIC` is time since boot. Returns 0 on success.
+///
+/// # Examples
+/// ```
+/// use user::constant::CLOCK_REALTIME;
+/// use user::syscall::{clock_gettime, TimeSpec};
+/// let mut ts = TimeSpec::default();
+/// clock_gettime(CLOCK_REALTIME, &mut ts);
+/// ```
+pub fn clock_gettime(clock: i32, ts: &mut TimeSpec) -> i32 {
+    unsafe { __clock_gettime(clock, ts) }
+}
+
+/// Power off machine with `REBOOT_POWER_OFF`, or restart it with
+/// `REBOOT_RESTART`.
+///
+/// Doesn't return on success, and returns -1 for unknown `cmd`.
+/// On QEMU, powering off terminates QEMU with exit status 0. To exit
+/// with another status, `exit` from init instead.
+pub fn reboot(cmd: i32) -> i32 {
+    unsafe { __reboot(cmd) }
+}
+
+/// Sleep for `ms` milliseconds.
+///
+/// Returns -1 if process is killed while sleeping.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sleep;
+/// sleep(100);
+/// ```
+pub fn sleep(ms: usize) -> i32 {
+    unsafe { __sleep(ms) }
+}
+
+/// Milliseconds since boot.
+pub fn uptime() -> i32 {
+    unsafe { __uptime() }
+}
+
+/// Grow heap by `increment` bytes, or shrink it if negative.
+///
+/// Returns previous end of heap, where new memory begins. Returns -1 if
+/// heap would shrink below its start or grow into stack, and `-ENOMEM`
+/// if there's not enough free memory. New memory is zero-filled when
+/// first touched.
+///
+/// # Examples
+/// ```
+/// use user::syscall::sbrk;
+/// let buf = sbrk(4096) as *mut u8;
+/// ```
+pub fn sbrk(increment: isize) -> isize {
+    unsafe { __sbrk(increment) }
+}
+
+/// Set `resource` of current process to `limit`, which is inherited by
+/// children.
+///
+/// With `RLIMIT_STACK`, `limit` is maximum size of stack in bytes, rounded
+/// up to pages, which takes effect at next `exec`. Stack grows down on
+/// demand up to it, and overflowing it kills the process. Returns -1 for
+/// unknown `resource` or if `limit` is zero or too large.
+///
+/// # Examples
+/// ```
+/// use user::syscall::{setrlimit, exec};
+/// use user::constant::RLIMIT_STACK;
+/// setrlimit(RLIMIT_STACK, 8 << 20);
+/// exec("/test1", &[]);
+/// ```
+pub fn setrlimit(resource: i32, limit: usize) -> i32 {
+    unsafe { __setrlimit(resource, limit) }
+}
@@ -11,6 +11,7 @@
 //! this module will finally trap into kernel.
 
 use core::arch::global_asm;
+use crate::syscall::TimeSpec;
 global_asm!(include_str!("usys.S"));
 
 extern "C" {
@@ -18,9 +19,27 @@ extern "C" {
     pub fn __read(fd: i32, content: *mut u8, sz: i32) -> i32;
     pub fn __exit(code: i32) -> !;
     pub fn __fork() -> i32;
-    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> !;
+    pub fn __exec(path: *const u8, path_sz: i32, arg_cnt: i32, args: *const *const u8, args_sz: *const i32) -> i32;
     pub fn __open(path: *const u8, sz: i32, mode: i32) -> i32;
     pub fn __close(fd: i32) -> i32;
     pub fn __dup(fd: i32) -> i32;
     pub fn __wait(pid: i32) -> i32;
+    pub fn __unlink(path: *const u8, sz: i32) -> i32;
+    pub fn __mkdir(path: *const u8, sz: i32) -> i32;
+    pub fn __socket(domain: i32, ty: i32) -> i32;
+    pub fn __bind(fd: i32, addr: *const u8, sz: i32) -> i32;
+    pub fn __listen(fd: i32, backlog: i32) -> i32;
+    pub fn __accept(fd: i32) -> i32;
+    pub fn __connect(fd: i32, addr: *const u8, sz: i32) -> i32;
+    pub fn __send(fd: i32, content: *const u8, sz: i32) -> i32;
+    pub fn __recv(fd: i32, content: *mut u8, sz: i32) -> i32;
+    pub fn __getrandom(content: *mut u8, sz: i32) -> i32;
+    pub fn __kill(pid: i32) -> i32;
+    pub fn __ioctl(fd: i32, request: usize, arg: usize) -> i32;
+    pub fn __clock_gettime(clock: i32, ts: *mut TimeSpec) -> i32;
+    pub fn __reboot(cmd: i32) -> i32;
+    pub fn __sleep(ms: usize) -> i32;
+    pub fn __uptime() -> i32;
+    pub fn __sbrk(increment: isize) -> isize;
+    pub fn __setrlimit(resource: i32, limit: usize) -> i32;
 }
@@ -0,0 +1,65 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+### 
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+# Boot core-os in QEMU and wait for kernel tests to pass, e.g.
+#   boot_test.py --expect "virtio-rng at slot" -- qemu-system-riscv64 ...
+# Fails if kernel panics, an expected line is missing, or it times out.
+
+import argparse
+import select
+import subprocess
+import sys
+import time
+
+parser = argparse.ArgumentParser()
+parser.add_argument("--expect", action="append", default=[],
+                    help="text that should appear in boot log")
+parser.add_argument("--timeout", type=float, default=120)
+parser.add_argument("qemu", nargs=argparse.REMAINDER)
+args = parser.parse_args()
+qemu = args.qemu[1:] if args.qemu[:1] == ["--"] else args.qemu
+
+PASSED = "all tests passed!"
+PANICKED = "Aborting: "
+
+proc = subprocess.Popen(qemu, stdin=subprocess.DEVNULL, stdout=subprocess.PIPE,
+                        stderr=subprocess.STDOUT)
+log = b""
+result = None
+deadline = time.monotonic() + args.timeout
+try:
+    while result is None:
+        remaining = deadline - time.monotonic()
+        if remaining <= 0:
+            result = "timed out"
+            break
+        ready, _, _ = select.select([proc.stdout], [], [], remaining)
+        if not ready:
+            continue
+        data = proc.stdout.read1(4096)
+        if not data:
+            result = f"qemu exited with {proc.wait()}"
+            break
+        sys.stdout.buffer.write(data)
+        sys.stdout.flush()
+        log += data
+        text = log.decode(errors="replace")
+        if PANICKED in text:
+            result = "kernel panicked"
+        elif PASSED in text:
+            result = "passed"
+finally:
+    proc.kill()
+    proc.wait()
+
+if result != "passed":
+    sys.exit(f"\nboot test failed: {result}")
+text = log.decode(errors="replace")
+missing = [e for e in args.expect if e not in text]
+if missing:
+    sys.exit(f"\nboot test failed: missing {missing}")
+print("\nboot test passed")
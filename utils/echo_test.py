@@ -0,0 +1,32 @@
+#!/usr/bin/env python3
+
+### Copyright (c) 2020 Alex Chi
+### 
+### This software is released under the MIT License.
+### https://opensource.org/licenses/MIT
+
+# Test TCP stack by talking to `echo` in core-os, which is forwarded
+# to host by `task qemu_net`.
+
+import socket
+import sys
+
+host = sys.argv[1] if len(sys.argv) > 1 else "127.0.0.1"
+port = int(sys.argv[2]) if len(sys.argv) > 2 else 5555
+
+messages = [b"hello, core-os!", bytes(range(256)) * 16]
+
+with socket.create_connection((host, port), timeout=10) as s:
+    for msg in messages:
+        s.sendall(msg)
+        received = b""
+        while len(received) < len(msg):
+            data = s.recv(4096)
+            if not data:
+                sys.exit("connection closed by core-os")
+            received += data
+        if received != msg:
+            sys.exit("echo mismatch")
+        print(f"echoed {len(msg)} bytes")
+
+print("echo test passed")
@@ -11,5 +11,7 @@ symbols = [
     "BSS_END",
     "KERNEL_STACK_START",
     "KERNEL_STACK_END",
-    "TRAMPOLINE_TEXT_START"
+    "TRAMPOLINE_TEXT_START",
+    "INITRAMFS_START",
+    "INITRAMFS_END"
 ]
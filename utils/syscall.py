@@ -19,5 +19,17 @@ syscalls = [
     "getpid",
     "sbrk",
     "sleep",
-    "uptime"
+    "uptime",
+    "socket",
+    "bind",
+    "listen",
+    "accept",
+    "connect",
+    "send",
+    "recv",
+    "getrandom",
+    "ioctl",
+    "clock_gettime",
+    "reboot",
+    "setrlimit"
 ]